@@ -0,0 +1,125 @@
+//! Rough per-call latencies of the hot paths, timed with a plain `Instant`
+//! loop. `Login` and `Validate` run over a local gRPC connection against the
+//! redis at `REDIS_URL` and are skipped when it is unreachable.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use auth::auth::auth_client::AuthClient;
+use auth::auth::{LoginRequest, ValidateRequest};
+use auth::config::Config;
+use auth::RedisManager;
+use r2d2_redis::r2d2;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tonic::transport::Channel;
+
+/// Timed calls of the in-process benchmarks, after a tenth as many untimed ones.
+const ITERATIONS: u32 = 100_000;
+/// The same for the calls over gRPC, which each wait on redis.
+const CALL_ITERATIONS: u32 = 1000;
+
+fn report(name: &str, iterations: u32, elapsed: Duration) {
+    println!(
+        "{:<28} {:>10.2} µs/call",
+        name,
+        elapsed.as_secs_f64() * 1e6 / f64::from(iterations)
+    );
+}
+
+fn bench(name: &str, mut call: impl FnMut()) {
+    for _ in 0..ITERATIONS / 10 {
+        call();
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        call();
+    }
+    report(name, ITERATIONS, start.elapsed());
+}
+
+async fn bench_calls<F: Future>(name: &str, mut call: impl FnMut() -> F) {
+    for _ in 0..CALL_ITERATIONS / 10 {
+        call().await;
+    }
+    let start = Instant::now();
+    for _ in 0..CALL_ITERATIONS {
+        call().await;
+    }
+    report(name, CALL_ITERATIONS, start.elapsed());
+}
+
+/// A pool on `REDIS_URL`, if a connection succeeds within a second.
+fn pool() -> Option<r2d2::Pool<RedisManager>> {
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_owned());
+    r2d2::Pool::builder()
+        .max_size(4)
+        .connection_timeout(Duration::from_secs(1))
+        .build(RedisManager::new(url).ok()?)
+        .ok()
+}
+
+fn login_request() -> LoginRequest {
+    LoginRequest {
+        user: "user".to_owned(),
+        password: "user".to_owned(),
+        ..Default::default()
+    }
+}
+
+async fn bench_service(mut client: AuthClient<Channel>, label: &str) {
+    bench_calls(&format!("login{}", label), || {
+        let mut client = client.clone();
+        async move { client.login(login_request()).await.unwrap() }
+    })
+    .await;
+
+    let token = client
+        .login(login_request())
+        .await
+        .unwrap()
+        .into_inner()
+        .token;
+    bench_calls(&format!("validate{}", label), || {
+        let mut client = client.clone();
+        let request = ValidateRequest {
+            token: token.clone(),
+            ..Default::default()
+        };
+        async move { client.validate(request).await.unwrap() }
+    })
+    .await;
+}
+
+fn main() {
+    // a token as `Login` generates it, and its redis key with HASH_TOKENS
+    bench("token generation", || {
+        std::hint::black_box(uuid::Uuid::new_v4().hyphenated().to_string());
+    });
+    bench("token generation, hashed", || {
+        let token = uuid::Uuid::new_v4().hyphenated().to_string();
+        std::hint::black_box(format!("{:x}", Sha256::digest(token.as_bytes())));
+    });
+
+    let pool = match pool() {
+        Some(pool) => pool,
+        None => {
+            println!("no redis at REDIS_URL, skipping login and validate");
+            return;
+        }
+    };
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let service = |hash_tokens: &str| {
+            let mut config = Config::default();
+            // the request log would time the terminal
+            config.set("REQUEST_LOG_EVERY", "0").unwrap();
+            config.set("HASH_TOKENS", hash_tokens).unwrap();
+            let service = common::builder(pool.clone()).config(config).build();
+            common::serve(service.into_service().unwrap())
+        };
+        bench_service(service("false").await, "").await;
+        bench_service(service("true").await, ", hashed").await;
+    });
+}
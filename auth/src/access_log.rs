@@ -0,0 +1,128 @@
+//! Access log of every call, written by a layer around all services so methods
+//! don't log on their own.
+//!
+//! Lines are logged at the info level of the `auth::access` target, which
+//! `RUST_LOG` enables as it would for a logging crate: `RUST_LOG=info` or
+//! `RUST_LOG=auth::access=info`.
+
+use crate::config::Config;
+use crate::metrics;
+use std::time::Instant;
+use tonic::codegen::{http, BoxFuture, Context, Poll, Service};
+use tonic::transport::server::TcpConnectInfo;
+use tower_layer::Layer;
+
+const TARGET: &str = "auth::access";
+
+/// Adds `AccessLog` to every service of a server.
+#[derive(Clone)]
+pub struct AccessLogLayer {
+    enabled: bool,
+}
+
+impl AccessLogLayer {
+    /// Logs if `RUST_LOG` enables info for the access log target.
+    pub fn from_config(config: &Config) -> Self {
+        AccessLogLayer {
+            enabled: config
+                .rust_log
+                .as_deref()
+                .is_some_and(|filter| info_enabled(filter, TARGET)),
+        }
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLog<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AccessLog {
+            inner,
+            enabled: self.enabled,
+        }
+    }
+}
+
+/// Logs method, peer, gRPC status and duration of each call to `inner`.
+#[derive(Clone)]
+pub struct AccessLog<S> {
+    inner: S,
+    enabled: bool,
+}
+
+impl<S, B, R> Service<http::Request<B>> for AccessLog<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<R>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        if !self.enabled {
+            return Box::pin(self.inner.call(request));
+        }
+        let method = request.uri().path().to_owned();
+        let peer = request
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(|info| info.remote_addr())
+            .map_or_else(|| "unknown".to_owned(), |addr| addr.to_string());
+        let start = Instant::now();
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await;
+            // errors are sent as trailers-only responses with the status in the
+            // headers, successful calls end with status 0 in the trailers
+            let status = match &response {
+                Ok(response) => metrics::code_label(
+                    response
+                        .headers()
+                        .get("grpc-status")
+                        .and_then(|status| status.to_str().ok())
+                        .and_then(|status| status.parse::<i32>().ok())
+                        .map_or(tonic::Code::Ok, tonic::Code::from),
+                ),
+                Err(_) => "transport_error",
+            };
+            println!(
+                "access method={} peer={} status={} duration_ms={}",
+                method,
+                peer,
+                status,
+                start.elapsed().as_millis()
+            );
+            response
+        })
+    }
+}
+
+/// Whether the `RUST_LOG` style `filter` logs info messages of `target`. The
+/// directive for the longest matching target prefix wins over a bare level.
+fn info_enabled(filter: &str, target: &str) -> bool {
+    let mut level = None;
+    let mut matched = 0;
+    for directive in filter.split(',').map(str::trim) {
+        let (prefix, directive_level) = match directive.split_once('=') {
+            Some((prefix, directive_level)) => (prefix, directive_level),
+            None if is_level(directive) => ("", directive),
+            None => (directive, "trace"),
+        };
+        let matches =
+            prefix.is_empty() || target == prefix || target.starts_with(&format!("{}::", prefix));
+        if matches && (level.is_none() || prefix.len() >= matched) {
+            level = Some(directive_level.to_lowercase());
+            matched = prefix.len();
+        }
+    }
+    matches!(level.as_deref(), Some("info" | "debug" | "trace"))
+}
+
+fn is_level(word: &str) -> bool {
+    ["off", "error", "warn", "info", "debug", "trace"].contains(&word.to_lowercase().as_str())
+}
@@ -0,0 +1,153 @@
+//! Operator facing HTTP endpoint describing the running instance.
+
+use crate::config::Secret;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Placeholder shown instead of secret configuration values.
+pub const REDACTED: &str = "<redacted>";
+
+/// Revokes all sessions, returning the new session epoch. It blocks on redis.
+pub type RevokeAll = Arc<dyn Fn() -> Result<u64, String> + Send + Sync>;
+
+/// Serves `/version`, `/config-summary` and `POST /revoke-all-sessions`. The
+/// summary must already have its secrets redacted. With an `api_key`, revoking
+/// requires it in the `x-api-key` header, as the gRPC services do.
+pub async fn serve(
+    addr: SocketAddr,
+    config_summary: serde_json::Value,
+    revoke_all: RevokeAll,
+    api_key: Option<Secret>,
+) -> Result<(), hyper::Error> {
+    let config_summary = Arc::new(config_summary);
+    let make_service = make_service_fn(move |_| {
+        let config_summary = config_summary.clone();
+        let revoke_all = revoke_all.clone();
+        let api_key = api_key.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(
+                    request,
+                    config_summary.clone(),
+                    revoke_all.clone(),
+                    api_key.clone(),
+                )
+            }))
+        }
+    });
+
+    Server::bind(&addr).serve(make_service).await
+}
+
+async fn handle(
+    request: Request<Body>,
+    config_summary: Arc<serde_json::Value>,
+    revoke_all: RevokeAll,
+    api_key: Option<Secret>,
+) -> Result<Response<Body>, Infallible> {
+    let body = match request.uri().path() {
+        "/version" => serde_json::json!({ "version": env!("CARGO_PKG_VERSION") }),
+        "/config-summary" => serde_json::Value::clone(&config_summary),
+        "/revoke-all-sessions" if request.method() != Method::POST => {
+            return Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::empty())
+                .unwrap())
+        }
+        "/revoke-all-sessions" if !has_api_key(&request, api_key.as_ref()) => {
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body(Body::empty())
+                .unwrap())
+        }
+        "/revoke-all-sessions" => {
+            let revoked = tokio::task::spawn_blocking(move || revoke_all())
+                .await
+                .unwrap_or_else(|err| Err(err.to_string()));
+            match revoked {
+                Ok(epoch) => serde_json::json!({ "revoked_before": epoch }),
+                Err(err) => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Body::from(serde_json::json!({ "error": err }).to_string()))
+                        .unwrap())
+                }
+            }
+        }
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap())
+        }
+    };
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap())
+}
+
+/// Whether `request` presents `api_key` in its `x-api-key` header, or there is
+/// no key to present.
+fn has_api_key(request: &Request<Body>, api_key: Option<&Secret>) -> bool {
+    let api_key = match api_key {
+        Some(api_key) => api_key,
+        None => return true,
+    };
+    let presented = request
+        .headers()
+        .get(crate::API_KEY_METADATA)
+        .map(|value| value.as_bytes())
+        .unwrap_or_default();
+    crate::constant_time_eq(presented, api_key.expose().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Posts to `/revoke-all-sessions` with `header` as the API key, returning
+    /// the status and whether sessions were revoked.
+    async fn revoke(header: Option<&str>) -> (StatusCode, bool) {
+        let revoked = Arc::new(AtomicBool::new(false));
+        let revoke_all: RevokeAll = {
+            let revoked = revoked.clone();
+            Arc::new(move || {
+                revoked.store(true, Ordering::SeqCst);
+                Ok(1)
+            })
+        };
+        let mut config = crate::config::Config::default();
+        config.set("API_KEY", "shared-secret").unwrap();
+        let mut request = Request::post("/revoke-all-sessions");
+        if let Some(header) = header {
+            request = request.header(crate::API_KEY_METADATA, header);
+        }
+        let response = handle(
+            request.body(Body::empty()).unwrap(),
+            Arc::new(serde_json::Value::Null),
+            revoke_all,
+            config.api_key,
+        )
+        .await
+        .unwrap();
+        (response.status(), revoked.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn revoking_all_sessions_requires_the_api_key() {
+        assert_eq!(revoke(None).await, (StatusCode::UNAUTHORIZED, false));
+        assert_eq!(
+            revoke(Some("wrong-secret")).await,
+            (StatusCode::UNAUTHORIZED, false)
+        );
+        assert_eq!(revoke(Some("shared-secret")).await, (StatusCode::OK, true));
+    }
+}
@@ -0,0 +1,276 @@
+//! The `AUDIT` lines logged for security relevant actions.
+//!
+//! With `AUDIT_HASH_CHAIN` every line ends in `prev=<hash> hash=<hash>`, where
+//! `hash` is the SHA-256 of the previous line's hash and this line's message.
+//! Editing, deleting or reordering a line breaks the chain from there on, which
+//! `verify` reports. Each process starts a new chain from the zero hash. With
+//! `AUDIT_CHAIN_KEY` the latest hash is also signed periodically, in a chained
+//! `checkpoint` line holding the HMAC-SHA256 of it, so a rewritten chain can't
+//! be passed off without the key.
+
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const PREFIX: &str = "AUDIT: ";
+const CHECKPOINT: &str = "checkpoint signature=";
+/// `prev` of the first line of a chain.
+const ZERO_HASH: [u8; 32] = [0; 32];
+
+/// Hash of the latest line, set once `start_chain` was called. Lines are
+/// printed while it's locked, so they appear in chain order.
+static CHAIN: OnceCell<Mutex<[u8; 32]>> = OnceCell::new();
+
+/// Chains every line logged from now on, as `AUDIT_HASH_CHAIN` asks. Calling it
+/// again keeps the chain going.
+pub(crate) fn start_chain() {
+    CHAIN.get_or_init(|| Mutex::new(ZERO_HASH));
+}
+
+/// Logs `message` as an `AUDIT` line, chained once `start_chain` was called.
+pub(crate) fn record(message: &str) {
+    match CHAIN.get() {
+        Some(chain) => append(&mut chain.lock().unwrap(), message),
+        None => println!("{}{}", PREFIX, message),
+    }
+}
+
+fn append(prev: &mut [u8; 32], message: &str) {
+    let hash = chain_hash(prev, message);
+    println!(
+        "{}{} prev={} hash={}",
+        PREFIX,
+        message,
+        hex(prev),
+        hex(&hash)
+    );
+    *prev = hash;
+}
+
+/// Every `interval`, signs the latest hash with `key` in a checkpoint line,
+/// unless nothing was logged since the last one. Must be called within a tokio
+/// runtime, and does nothing without `AUDIT_HASH_CHAIN`.
+pub(crate) fn spawn_checkpoints(key: Vec<u8>, interval: Duration) {
+    let chain = match CHAIN.get() {
+        Some(chain) => chain,
+        None => {
+            println!("AUDIT_CHAIN_KEY is ignored without AUDIT_HASH_CHAIN");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        let mut signed = ZERO_HASH;
+        loop {
+            ticker.tick().await;
+            let mut latest = chain.lock().unwrap();
+            if *latest == signed {
+                continue;
+            }
+            let signature = hmac(&key, &latest[..]);
+            append(&mut latest, &format!("{}{}", CHECKPOINT, hex(&signature)));
+            signed = *latest;
+        }
+    });
+}
+
+/// Where an audit log fails verification.
+#[derive(Debug, PartialEq)]
+pub struct ChainError {
+    /// 1-based line number in the verified input.
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "audit chain broken at line {}: {}",
+            self.line, self.reason
+        )
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+/// Checks the hash chain of the `AUDIT` lines among `lines`, ignoring all other
+/// output, and returns how many records it verified. With `key`, checkpoint
+/// signatures are checked too. A record with the zero `prev` starts a new
+/// chain, as after a restart. The hashes aren't keyed, so cutting the end off
+/// a chain or rewriting all of it from some record on is only noticed where a
+/// checkpoint verified with the key signed the original.
+pub fn verify<'a>(
+    lines: impl IntoIterator<Item = &'a str>,
+    key: Option<&[u8]>,
+) -> Result<usize, ChainError> {
+    let mut latest = None;
+    let mut records = 0;
+    for (index, line) in lines.into_iter().enumerate() {
+        let record = match line.trim_end().strip_prefix(PREFIX) {
+            Some(record) => record,
+            None => continue,
+        };
+        let error = |reason| ChainError {
+            line: index + 1,
+            reason,
+        };
+        let mut parts = record.rsplitn(3, ' ');
+        let (hash, prev, message) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(hash), Some(prev), Some(message)) => (hash, prev, message),
+            _ => return Err(error("record is not chained")),
+        };
+        let (hash, prev) = match (
+            hash.strip_prefix("hash=").and_then(parse_hex),
+            prev.strip_prefix("prev=").and_then(parse_hex),
+        ) {
+            (Some(hash), Some(prev)) => (hash, prev),
+            _ => return Err(error("record is not chained")),
+        };
+        if prev != ZERO_HASH && latest != Some(prev) {
+            return Err(error("previous record is missing or changed"));
+        }
+        if chain_hash(&prev, message) != hash {
+            return Err(error("record was changed"));
+        }
+        if let (Some(key), Some(signature)) = (key, message.strip_prefix(CHECKPOINT)) {
+            if parse_hex(signature) != Some(hmac(key, &prev)) {
+                return Err(error("checkpoint signature is wrong"));
+            }
+        }
+        latest = Some(hash);
+        records += 1;
+    }
+    Ok(records)
+}
+
+fn chain_hash(prev: &[u8; 32], message: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(prev);
+    hasher.update(message.as_bytes());
+    hasher.finalize().into()
+}
+
+/// HMAC-SHA256 as in RFC 2104, with SHA-256's 64 byte block.
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+    let mut inner = Sha256::new();
+    inner.update(pad(0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(pad(0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (byte, pair) in out.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The lines `append` logs for `messages`, starting a chain.
+    fn chain(messages: &[&str]) -> Vec<String> {
+        let mut prev = ZERO_HASH;
+        messages
+            .iter()
+            .map(|message| {
+                let hash = chain_hash(&prev, message);
+                let line = format!(
+                    "{}{} prev={} hash={}",
+                    PREFIX,
+                    message,
+                    hex(&prev),
+                    hex(&hash)
+                );
+                prev = hash;
+                line
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        let cases: [(&[u8], &[u8], &str); 3] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            // a key longer than the block is hashed first
+            (
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+        ];
+        for (key, message, expected) in cases {
+            assert_eq!(hex(&hmac(key, message)), expected);
+        }
+    }
+
+    #[test]
+    fn intact_chain_verifies_among_other_output() {
+        let mut lines = chain(&["login user=alice", "revoke user=alice"]);
+        lines.insert(1, "listening on 0.0.0.0:50051".to_owned());
+
+        assert_eq!(verify(lines.iter().map(String::as_str), None), Ok(2));
+    }
+
+    #[test]
+    fn changed_or_missing_record_breaks_the_chain() {
+        let lines = chain(&["login user=alice", "login user=bob", "revoke user=bob"]);
+
+        let mut changed = lines.clone();
+        changed[1] = changed[1].replace("bob", "eve");
+        let err = verify(changed.iter().map(String::as_str), None).unwrap_err();
+        assert_eq!((err.line, err.reason), (2, "record was changed"));
+
+        let missing = [&lines[0], &lines[2]];
+        let err = verify(missing.iter().map(|line| line.as_str()), None).unwrap_err();
+        assert_eq!(
+            (err.line, err.reason),
+            (2, "previous record is missing or changed")
+        );
+    }
+
+    #[test]
+    fn checkpoint_signature_is_checked_with_the_key() {
+        let first = chain_hash(&ZERO_HASH, "login user=alice");
+        let checkpoint = format!("{}{}", CHECKPOINT, hex(&hmac(b"audit-key", &first)));
+        let lines = chain(&["login user=alice", &checkpoint]);
+
+        assert_eq!(
+            verify(lines.iter().map(String::as_str), Some(b"audit-key")),
+            Ok(2)
+        );
+        let err = verify(lines.iter().map(String::as_str), Some(b"other-key")).unwrap_err();
+        assert_eq!((err.line, err.reason), (2, "checkpoint signature is wrong"));
+    }
+}
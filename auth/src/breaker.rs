@@ -0,0 +1,154 @@
+//! Circuit breaker for redis.
+//!
+//! While redis is down every checkout waits for the pool timeout before failing.
+//! After enough consecutive failures the breaker opens and requests fail right
+//! away; once the cooldown has passed a single probe is let through, whose
+//! outcome closes the breaker again or restarts the cooldown.
+
+use crate::metrics::{self, Metrics};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub struct Breaker {
+    pub failures: u32,
+    pub cooldown: Duration,
+    state: Mutex<State>,
+    sink: Arc<dyn Metrics>,
+}
+
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe is in flight, everything else is still refused.
+    HalfOpen,
+}
+
+impl Breaker {
+    /// Opens after `failures` consecutive failures and stays open for `cooldown`.
+    pub fn new(failures: u32, cooldown: Duration, sink: Arc<dyn Metrics>) -> Self {
+        Breaker {
+            failures,
+            cooldown,
+            state: Mutex::new(State::Closed { failures: 0 }),
+            sink,
+        }
+    }
+
+    /// Whether a call may go to redis. A caller that is allowed must report the
+    /// outcome with `record`.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if Instant::now() >= until => {
+                *state = State::HalfOpen;
+                self.transition("half_open");
+                true
+            }
+            State::Open { .. } | State::HalfOpen => false,
+        }
+    }
+
+    /// Records the outcome of a call let through by `allow`.
+    pub fn record(&self, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+        match (&mut *state, ok) {
+            (State::Closed { failures }, true) => *failures = 0,
+            (State::Closed { failures }, false) => {
+                *failures += 1;
+                if *failures >= self.failures {
+                    self.open(&mut state);
+                }
+            }
+            (State::HalfOpen, true) => {
+                *state = State::Closed { failures: 0 };
+                self.transition("closed");
+            }
+            (State::HalfOpen, false) => self.open(&mut state),
+            // calls allowed before the breaker opened
+            (State::Open { .. }, _) => {}
+        }
+    }
+
+    fn open(&self, state: &mut State) {
+        *state = State::Open {
+            until: Instant::now() + self.cooldown,
+        };
+        self.transition("open");
+    }
+
+    fn transition(&self, to: &'static str) {
+        println!("redis circuit breaker is {}", to.replace('_', "-"));
+        self.sink
+            .increment_counter(metrics::REDIS_BREAKER_TRANSITIONS_TOTAL, &[("state", to)]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{Labels, NoopMetrics};
+
+    /// Records the states the breaker reports moving to.
+    #[derive(Default)]
+    struct Transitions(Mutex<Vec<String>>);
+
+    impl Metrics for Transitions {
+        fn increment_counter(&self, name: &'static str, labels: Labels) {
+            assert_eq!(name, metrics::REDIS_BREAKER_TRANSITIONS_TOTAL);
+            self.0.lock().unwrap().push(labels[0].1.to_owned());
+        }
+        fn observe_histogram(&self, _name: &'static str, _labels: Labels, _value: f64) {}
+        fn set_gauge(&self, _name: &'static str, _labels: Labels, _value: i64) {}
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = Breaker::new(3, Duration::from_secs(60), Arc::new(NoopMetrics));
+        breaker.record(false);
+        breaker.record(false);
+        // a success in between starts the count over
+        breaker.record(true);
+        breaker.record(false);
+        breaker.record(false);
+        assert!(breaker.allow());
+
+        breaker.record(false);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn probe_after_the_cooldown_closes_or_reopens() {
+        let transitions = Arc::new(Transitions::default());
+        let breaker = Breaker::new(1, Duration::ZERO, transitions.clone());
+        breaker.record(false);
+
+        // the first call after the cooldown probes, the others wait for it
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        breaker.record(false);
+        assert!(breaker.allow());
+        breaker.record(true);
+        assert!(breaker.allow());
+        assert!(breaker.allow());
+
+        assert_eq!(
+            *transitions.0.lock().unwrap(),
+            ["open", "half_open", "open", "half_open", "closed"]
+        );
+    }
+
+    #[test]
+    fn outcomes_of_calls_from_before_opening_are_ignored() {
+        let breaker = Breaker::new(1, Duration::from_secs(60), Arc::new(NoopMetrics));
+        assert!(breaker.allow());
+        assert!(breaker.allow());
+        breaker.record(false);
+        breaker.record(true);
+        assert!(!breaker.allow());
+    }
+}
@@ -0,0 +1,498 @@
+//! Sampled captures of incoming requests, to reproduce a problem by issuing
+//! them again against another instance.
+//!
+//! With `CAPTURE_FILE`, one in `CAPTURE_EVERY` calls is appended to that file
+//! as a JSON line holding the method, the time, the request metadata and the
+//! request message. Passwords, tokens and credentials in the metadata never
+//! reach the file: they are replaced by `sha256:` and the hex SHA-256 of the
+//! value, so captures of the same token can be matched up but not used.
+//! Binary metadata is left out. `auth-replay <file> <url>` issues the captured
+//! requests again, which validates hashed tokens as unknown.
+//!
+//! ```
+//! use auth::auth::LoginRequest;
+//! use auth::capture::Captured;
+//!
+//! let mut request = tonic::Request::new(LoginRequest {
+//!     user: "alice".to_owned(),
+//!     password: "secret".to_owned(),
+//!     scopes: vec!["read".to_owned()],
+//!     ..Default::default()
+//! });
+//! request.metadata_mut().insert("x-request-id", "42".parse().unwrap());
+//! request.metadata_mut().insert("x-api-key", "key".parse().unwrap());
+//!
+//! let line = Captured::new("login", &request, 0).to_line();
+//! assert!(!line.contains("secret") && !line.contains("\"key\""));
+//!
+//! let replayed: tonic::Request<LoginRequest> = Captured::parse(&line).unwrap().request().unwrap();
+//! assert_eq!(replayed.get_ref().user, "alice");
+//! assert_eq!(replayed.get_ref().scopes, ["read"]);
+//! assert!(replayed.get_ref().password.starts_with("sha256:"));
+//! assert_eq!(replayed.metadata().get("x-request-id").unwrap(), "42");
+//! ```
+
+use crate::auth::auth_client::AuthClient;
+use crate::auth::{
+    IntrospectRequest, LoginAnonymousRequest, LoginRequest, SessionCountRequest,
+    TokenExchangeRequest, ValidateBatchRequest, ValidateRequest,
+};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tonic::metadata::{KeyAndValueRef, MetadataKey, MetadataValue};
+use tonic::transport::Channel;
+use tonic::Request;
+
+/// Metadata holding credentials, hashed like passwords and tokens. The request
+/// log redacts them too.
+pub(crate) const SECRET_METADATA: &[&str] = &["authorization", "cookie", crate::API_KEY_METADATA];
+
+/// A request message as captured, with passwords and tokens hashed.
+pub trait Capturable: Sized {
+    fn to_capture(&self) -> Value;
+    /// The message of a capture, `None` if a field is missing or mistyped.
+    fn from_capture(fields: &Value) -> Option<Self>;
+}
+
+/// One captured call.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Captured {
+    /// The method as named in metrics, such as `login`.
+    pub method: String,
+    /// When the call was received, unix seconds.
+    pub time: u64,
+    /// ASCII metadata in order, with credentials hashed.
+    pub metadata: Vec<(String, String)>,
+    pub message: Value,
+}
+
+/// Why a capture can't be read or replayed.
+#[derive(Debug, PartialEq)]
+pub struct CaptureError(pub &'static str);
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bad capture: {}", self.0)
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+impl Captured {
+    pub fn new<T: Capturable>(method: &str, request: &Request<T>, time: u64) -> Self {
+        let metadata = request
+            .metadata()
+            .iter()
+            .filter_map(|entry| match entry {
+                KeyAndValueRef::Ascii(key, value) => {
+                    let value = value.to_str().ok()?;
+                    let value = if SECRET_METADATA.contains(&key.as_str()) {
+                        redact(value)
+                    } else {
+                        value.to_owned()
+                    };
+                    Some((key.as_str().to_owned(), value))
+                }
+                KeyAndValueRef::Binary(..) => None,
+            })
+            .collect();
+        Captured {
+            method: method.to_owned(),
+            time,
+            metadata,
+            message: request.get_ref().to_capture(),
+        }
+    }
+
+    /// The capture as one line of JSON.
+    pub fn to_line(&self) -> String {
+        serde_json::json!({
+            "method": self.method,
+            "time": self.time,
+            "metadata": self
+                .metadata
+                .iter()
+                .map(|(key, value)| serde_json::json!([key, value]))
+                .collect::<Vec<_>>(),
+            "message": self.message,
+        })
+        .to_string()
+    }
+
+    pub fn parse(line: &str) -> Result<Self, CaptureError> {
+        let value: Value = serde_json::from_str(line).map_err(|_| CaptureError("not JSON"))?;
+        let parsed = || {
+            Some(Captured {
+                method: value.get("method")?.as_str()?.to_owned(),
+                time: value.get("time")?.as_u64()?,
+                metadata: value
+                    .get("metadata")?
+                    .as_array()?
+                    .iter()
+                    .map(|entry| match entry.as_array()?.as_slice() {
+                        [key, value] => {
+                            Some((key.as_str()?.to_owned(), value.as_str()?.to_owned()))
+                        }
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?,
+                message: value.get("message")?.clone(),
+            })
+        };
+        parsed().ok_or(CaptureError("missing or mistyped field"))
+    }
+
+    /// The captured call as a request, with its metadata.
+    pub fn request<T: Capturable>(&self) -> Result<Request<T>, CaptureError> {
+        let message = T::from_capture(&self.message)
+            .ok_or(CaptureError("message doesn't match the method"))?;
+        let mut request = Request::new(message);
+        for (key, value) in &self.metadata {
+            let key = MetadataKey::from_bytes(key.as_bytes())
+                .map_err(|_| CaptureError("invalid metadata key"))?;
+            let value = MetadataValue::try_from(value.as_str())
+                .map_err(|_| CaptureError("invalid metadata value"))?;
+            request.metadata_mut().append(key, value);
+        }
+        Ok(request)
+    }
+}
+
+/// Issues a captured call again through `client`, returning the status it
+/// ended with.
+pub async fn replay(
+    client: &mut AuthClient<Channel>,
+    captured: &Captured,
+) -> Result<tonic::Code, CaptureError> {
+    let result = match captured.method.as_str() {
+        "login" => client.login(captured.request()?).await.map(drop),
+        "login_anonymous" => client.login_anonymous(captured.request()?).await.map(drop),
+        "validate" => client.validate(captured.request()?).await.map(drop),
+        "introspect" => client.introspect(captured.request()?).await.map(drop),
+        "validate_batch" => client.validate_batch(captured.request()?).await.map(drop),
+        "session_count" => client.session_count(captured.request()?).await.map(drop),
+        "token_exchange" => client.token_exchange(captured.request()?).await.map(drop),
+        _ => return Err(CaptureError("unknown method")),
+    };
+    Ok(result.map_or_else(|status| status.code(), |()| tonic::Code::Ok))
+}
+
+/// Appends sampled calls to `CAPTURE_FILE`.
+pub(crate) struct Recorder {
+    file: Mutex<File>,
+    every: u64,
+    calls: AtomicU64,
+}
+
+impl Recorder {
+    /// A recorder for `CAPTURE_FILE`, if set and `CAPTURE_EVERY` isn't 0. A file
+    /// that can't be opened is logged and disables capturing.
+    pub(crate) fn from_config(config: &crate::config::Config) -> Option<Self> {
+        let path = config.capture_file.as_deref()?;
+        let every = config.capture_every.unwrap_or(100);
+        if every == 0 {
+            return None;
+        }
+        match std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+        {
+            Ok(file) => {
+                println!("capturing one in {} requests to {}", every, path);
+                Some(Recorder {
+                    file: Mutex::new(file),
+                    every,
+                    calls: AtomicU64::new(0),
+                })
+            }
+            Err(err) => {
+                println!(
+                    "WARNING: not capturing requests, can't open {}: {}",
+                    path, err
+                );
+                None
+            }
+        }
+    }
+
+    pub(crate) fn every(&self) -> u64 {
+        self.every
+    }
+
+    /// Appends the call if it's sampled. The line is written while the file is
+    /// locked, so concurrent captures don't interleave.
+    pub(crate) fn record<T: Capturable>(&self, method: &str, request: &Request<T>) {
+        if !self
+            .calls
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.every)
+        {
+            return;
+        }
+        let line = Captured::new(method, request, crate::unix_now()).to_line();
+        if let Err(err) = writeln!(self.file.lock().unwrap(), "{}", line) {
+            println!("failed to capture a {} request: {}", method, err);
+        }
+    }
+}
+
+/// `sha256:` and the hex SHA-256 of `value`; empty values stay empty, so a
+/// missing credential replays as missing.
+fn redact(value: &str) -> String {
+    if value.is_empty() {
+        return String::new();
+    }
+    let hash = Sha256::digest(value.as_bytes());
+    format!(
+        "sha256:{}",
+        hash.iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>()
+    )
+}
+
+fn string(fields: &Value, field: &str) -> Option<String> {
+    fields.get(field)?.as_str().map(str::to_owned)
+}
+
+fn strings(fields: &Value, field: &str) -> Option<Vec<String>> {
+    fields
+        .get(field)?
+        .as_array()?
+        .iter()
+        .map(|value| value.as_str().map(str::to_owned))
+        .collect()
+}
+
+fn number<T: TryFrom<u64>>(fields: &Value, field: &str) -> Option<T> {
+    T::try_from(fields.get(field)?.as_u64()?).ok()
+}
+
+fn flag(fields: &Value, field: &str) -> Option<bool> {
+    fields.get(field)?.as_bool()
+}
+
+impl Capturable for LoginRequest {
+    fn to_capture(&self) -> Value {
+        serde_json::json!({
+            "user": self.user,
+            "password": redact(&self.password),
+            "client_version": self.client_version,
+            "one_time": self.one_time,
+            "scopes": self.scopes,
+            "requested_ttl_seconds": self.requested_ttl_seconds,
+            "class": self.class,
+            "remember_me": self.remember_me,
+            "not_before": self.not_before.as_ref().map(|time| serde_json::json!([time.seconds, time.nanos])),
+        })
+    }
+
+    fn from_capture(fields: &Value) -> Option<Self> {
+        Some(LoginRequest {
+            user: string(fields, "user")?,
+            password: string(fields, "password")?,
+            client_version: number(fields, "client_version")?,
+            // captures of earlier releases hold the enum the field replaced
+            one_time: match fields.get("one_time") {
+                Some(one_time) => one_time.as_bool()?,
+                None => number::<u64>(fields, "token_class")? == 1,
+            },
+            scopes: strings(fields, "scopes")?,
+            requested_ttl_seconds: match fields.get("requested_ttl_seconds")? {
+                ttl if ttl.is_null() => None,
+                ttl => Some(ttl.as_u64()?),
+            },
+            class: string(fields, "class")?,
+            remember_me: flag(fields, "remember_me")?,
+            not_before: match fields.get("not_before")? {
+                time if time.is_null() => None,
+                time => match time.as_array()?.as_slice() {
+                    [seconds, nanos] => Some(prost_types::Timestamp {
+                        seconds: seconds.as_i64()?,
+                        nanos: i32::try_from(nanos.as_i64()?).ok()?,
+                    }),
+                    _ => return None,
+                },
+            },
+        })
+    }
+}
+
+impl Capturable for LoginAnonymousRequest {
+    fn to_capture(&self) -> Value {
+        serde_json::json!({ "client_version": self.client_version })
+    }
+
+    fn from_capture(fields: &Value) -> Option<Self> {
+        Some(LoginAnonymousRequest {
+            client_version: number(fields, "client_version")?,
+        })
+    }
+}
+
+impl Capturable for ValidateRequest {
+    fn to_capture(&self) -> Value {
+        serde_json::json!({
+            "token": redact(&self.token),
+            "client_version": self.client_version,
+            "result_in_response": self.result_in_response,
+            "required_scope": self.required_scope,
+            "extend": self.extend,
+        })
+    }
+
+    fn from_capture(fields: &Value) -> Option<Self> {
+        Some(ValidateRequest {
+            token: string(fields, "token")?,
+            client_version: number(fields, "client_version")?,
+            result_in_response: flag(fields, "result_in_response")?,
+            required_scope: string(fields, "required_scope")?,
+            extend: flag(fields, "extend")?,
+        })
+    }
+}
+
+impl Capturable for IntrospectRequest {
+    fn to_capture(&self) -> Value {
+        serde_json::json!({ "token": redact(&self.token) })
+    }
+
+    fn from_capture(fields: &Value) -> Option<Self> {
+        Some(IntrospectRequest {
+            token: string(fields, "token")?,
+        })
+    }
+}
+
+impl Capturable for ValidateBatchRequest {
+    fn to_capture(&self) -> Value {
+        serde_json::json!({
+            "tokens": self.tokens.iter().map(|token| redact(token)).collect::<Vec<_>>(),
+        })
+    }
+
+    fn from_capture(fields: &Value) -> Option<Self> {
+        Some(ValidateBatchRequest {
+            tokens: strings(fields, "tokens")?,
+        })
+    }
+}
+
+impl Capturable for SessionCountRequest {
+    fn to_capture(&self) -> Value {
+        serde_json::json!({ "token": redact(&self.token) })
+    }
+
+    fn from_capture(fields: &Value) -> Option<Self> {
+        Some(SessionCountRequest {
+            token: string(fields, "token")?,
+        })
+    }
+}
+
+impl Capturable for TokenExchangeRequest {
+    fn to_capture(&self) -> Value {
+        serde_json::json!({
+            "token": redact(&self.token),
+            "scopes": self.scopes,
+            "ttl_seconds": self.ttl_seconds,
+            "client_version": self.client_version,
+        })
+    }
+
+    fn from_capture(fields: &Value) -> Option<Self> {
+        Some(TokenExchangeRequest {
+            token: string(fields, "token")?,
+            scopes: strings(fields, "scopes")?,
+            ttl_seconds: number(fields, "ttl_seconds")?,
+            client_version: number(fields, "client_version")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "3f2a9c41";
+    const TOKEN_SHA256: &str =
+        "sha256:7f5b61b4ffbfb92770c328862873e07e74e94459cbf922937638a73981ffe5a4";
+
+    #[test]
+    fn credentials_in_metadata_are_hashed() {
+        let mut request = Request::new(IntrospectRequest::default());
+        let metadata = request.metadata_mut();
+        metadata.insert("authorization", TOKEN.parse().unwrap());
+        metadata.insert("cookie", TOKEN.parse().unwrap());
+        metadata.insert("x-api-key", TOKEN.parse().unwrap());
+        metadata.insert("x-request-id", TOKEN.parse().unwrap());
+        metadata.insert_bin("trace-bin", MetadataValue::from_bytes(b"\x00\x01"));
+
+        let captured = Captured::new("introspect", &request, 0);
+
+        let hashed: Vec<_> = captured
+            .metadata
+            .iter()
+            .map(|(key, value)| (key.as_str(), value == TOKEN_SHA256))
+            .collect();
+        assert_eq!(
+            hashed,
+            [
+                ("authorization", true),
+                ("cookie", true),
+                ("x-api-key", true),
+                ("x-request-id", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokens_and_passwords_are_hashed_in_every_message() {
+        let token = || TOKEN.to_owned();
+        let messages = [
+            LoginRequest {
+                password: token(),
+                ..Default::default()
+            }
+            .to_capture(),
+            ValidateRequest {
+                token: token(),
+                ..Default::default()
+            }
+            .to_capture(),
+            IntrospectRequest { token: token() }.to_capture(),
+            ValidateBatchRequest {
+                tokens: vec![token(), String::new()],
+            }
+            .to_capture(),
+            SessionCountRequest { token: token() }.to_capture(),
+            TokenExchangeRequest {
+                token: token(),
+                ..Default::default()
+            }
+            .to_capture(),
+        ];
+
+        for message in messages {
+            let line = message.to_string();
+            assert!(!line.contains(&format!("\"{}\"", TOKEN)), "{}", line);
+            assert!(line.contains(TOKEN_SHA256), "{}", line);
+        }
+    }
+
+    #[test]
+    fn missing_credential_stays_empty() {
+        assert_eq!(redact(""), "");
+        let message = ValidateBatchRequest {
+            tokens: vec![String::new()],
+        }
+        .to_capture();
+        assert_eq!(strings(&message, "tokens").unwrap(), [""]);
+    }
+}
@@ -0,0 +1,602 @@
+//! Layered configuration: defaults, then a settings file, then the environment,
+//! then command line flags, each overriding the one before.
+//!
+//! Settings have their environment variable names in every layer: `KEY=value`
+//! lines in the file named by `--config` or `CONFIG_FILE`, and `--key-name=value`
+//! or `--key-name value` flags for `KEY_NAME`, with a bare `--key-name` turning a
+//! flag on. Secrets may also be read from the file named by `<KEY>_FILE`, as
+//! mounted by Docker and Kubernetes secrets. Every setting is listed in
+//! `SETTINGS` with the kind of value it takes, so a misspelt name in the file or
+//! on the command line and a malformed value anywhere fail the load instead of
+//! being ignored.
+
+use crate::session_format::SessionFormat;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// What a setting's value must look like.
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    /// `1`, `true`, `0` or `false`.
+    Flag,
+    /// A non-negative integer.
+    Number,
+    /// `host:port` with a literal IP address.
+    Address,
+    /// One of the listed names.
+    Choice(&'static [&'static str]),
+    /// Text that is never logged, see `Secret`.
+    Secret,
+}
+
+impl Kind {
+    fn expected(self) -> String {
+        match self {
+            Kind::Text | Kind::Secret => "text".to_owned(),
+            Kind::Flag => "1, true, 0 or false".to_owned(),
+            Kind::Number => "a non-negative integer".to_owned(),
+            Kind::Address => "an ip:port address".to_owned(),
+            Kind::Choice(names) => format!("one of {}", names.join(", ")),
+        }
+    }
+}
+
+/// The type of a `Config` field, parsed from values of its `KIND`.
+trait Value: Sized {
+    const KIND: Kind;
+    fn parse(value: &str) -> Option<Self>;
+}
+
+/// Flags are off unless set otherwise.
+impl Value for bool {
+    const KIND: Kind = Kind::Flag;
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "1" | "true" => Some(true),
+            "0" | "false" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// Settings other than flags are `None` while unset.
+impl<T: Value> Value for Option<T> {
+    const KIND: Kind = T::KIND;
+    fn parse(value: &str) -> Option<Self> {
+        T::parse(value).map(Some)
+    }
+}
+
+impl Value for String {
+    const KIND: Kind = Kind::Text;
+    fn parse(value: &str) -> Option<Self> {
+        Some(value.to_owned())
+    }
+}
+
+impl Value for Secret {
+    const KIND: Kind = Kind::Secret;
+    fn parse(value: &str) -> Option<Self> {
+        Some(Secret(value.to_owned()))
+    }
+}
+
+impl Value for SocketAddr {
+    const KIND: Kind = Kind::Address;
+    fn parse(value: &str) -> Option<Self> {
+        value.parse().ok()
+    }
+}
+
+macro_rules! numbers {
+    ($($ty:ty),*) => {$(
+        impl Value for $ty {
+            const KIND: Kind = Kind::Number;
+            fn parse(value: &str) -> Option<Self> {
+                value.parse().ok()
+            }
+        }
+    )*};
+}
+
+numbers!(u16, u32, u64, usize);
+
+/// Declares an enum of the names a `Kind::Choice` setting takes.
+macro_rules! choice {
+    ($(#[$doc:meta])* $name:ident { $($variant:ident = $value:literal,)* }) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub enum $name {
+            $($variant,)*
+        }
+
+        impl Value for $name {
+            const KIND: Kind = Kind::Choice(&[$($value),*]);
+            fn parse(value: &str) -> Option<Self> {
+                match value {
+                    $($value => Some($name::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+choice! {
+    /// `ERROR_DETAIL_LEVEL`: whether error messages explain the failure or
+    /// only name its kind.
+    ErrorDetailLevel {
+        Verbose = "verbose",
+        Opaque = "opaque",
+    }
+}
+
+choice! {
+    /// `METRICS_BACKEND`: how metrics are exported.
+    MetricsBackend {
+        Prometheus = "prometheus",
+        Otel = "otel",
+    }
+}
+
+impl Value for SessionFormat {
+    const KIND: Kind = Kind::Choice(&["json", "binary"]);
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "json" => Some(SessionFormat::Json),
+            "binary" => Some(SessionFormat::Binary),
+            _ => None,
+        }
+    }
+}
+
+/// Declares each setting once, as its key, the `Config` field holding it (the
+/// key in lower case) and the field's type, which decides the `Kind` of value
+/// the setting takes. Fields without a default are unset or off.
+macro_rules! settings {
+    (@default) => {
+        Default::default()
+    };
+    (@default $default:expr) => {
+        $default
+    };
+    ($($key:literal $field:ident: $ty:ty $(= $default:expr)?;)*) => {
+        /// Every setting the service reads, see the Configuration table of the README.
+        const SETTINGS: &[(&str, Kind)] = &[$(($key, <$ty as Value>::KIND)),*];
+
+        /// Values of the settings, see the Configuration table of the README.
+        /// `Debug` output redacts secrets.
+        #[derive(Clone, Debug)]
+        pub struct Config {
+            $(pub $field: $ty,)*
+        }
+
+        impl Default for Config {
+            fn default() -> Self {
+                Config {
+                    $($field: settings!(@default $($default)?),)*
+                }
+            }
+        }
+
+        impl Config {
+            /// Parses `value` into the field of `key`, `None` if it's malformed.
+            fn assign(&mut self, key: &str, value: &str) -> Option<()> {
+                match key {
+                    $($key => self.$field = Value::parse(value)?,)*
+                    _ => unreachable!("{} is not in SETTINGS", key),
+                }
+                Some(())
+            }
+        }
+    };
+}
+
+settings! {
+    "ADMIN_ADDR" admin_addr: Option<SocketAddr>;
+    "ADMIN_ALLOW_REMOTE" admin_allow_remote: bool;
+    "ADMIN_GRPC_ADDR" admin_grpc_addr: Option<SocketAddr>;
+    "ALLOW_WEAK_HASHING" allow_weak_hashing: bool;
+    "API_KEY" api_key: Option<Secret>;
+    "APP_ENV" app_env: Option<String>;
+    "AUDIT_CHAIN_KEY" audit_chain_key: Option<Secret>;
+    "AUDIT_CHECKPOINT_INTERVAL_SECONDS" audit_checkpoint_interval_seconds: Option<u64>;
+    "AUDIT_HASH_CHAIN" audit_hash_chain: bool;
+    "BAGGAGE_SPAN_ATTRIBUTES" baggage_span_attributes: Option<String>;
+    "BANNED_USERNAMES" banned_usernames: Option<String>;
+    "BANNED_USERNAMES_EXEMPT" banned_usernames_exempt: Option<String>;
+    "BIND_SESSIONS_TO_IP" bind_sessions_to_ip: bool;
+    "CAPTURE_EVERY" capture_every: Option<u64>;
+    "CAPTURE_FILE" capture_file: Option<String>;
+    "DEBUG_EXPOSE_SESSION_ID" debug_expose_session_id: bool;
+    "DISABLED_METHODS" disabled_methods: Option<String>;
+    "DISABLED_USERS" disabled_users: Option<String>;
+    "DISABLE_BUILTIN_USERS" disable_builtin_users: bool;
+    "EMERGENCY_ADMIN_PASSWORD" emergency_admin_password: Option<Secret>;
+    "EMERGENCY_ADMIN_SESSION_TTL_SECONDS" emergency_admin_session_ttl_seconds: Option<u64>;
+    "EMERGENCY_ADMIN_UNTIL" emergency_admin_until: Option<u64>;
+    "EMERGENCY_ADMIN_USER" emergency_admin_user: Option<String>;
+    "ERROR_DETAIL_LEVEL" error_detail_level: Option<ErrorDetailLevel>;
+    "EVENTS_NATS_ADDR" events_nats_addr: Option<String>;
+    "EVENTS_SUBJECT_PREFIX" events_subject_prefix: Option<String>;
+    "EXCHANGE_TTL_SECONDS" exchange_ttl_seconds: Option<u64>;
+    "EXTEND_THRESHOLD_PERCENT" extend_threshold_percent: Option<u64>;
+    "GRPC_COMPRESSION" grpc_compression: Option<String>;
+    "GUEST_TTL_SECONDS" guest_ttl_seconds: Option<u64>;
+    "HASHING_CONCURRENCY" hashing_concurrency: Option<usize>;
+    "HASHING_QUEUE" hashing_queue: Option<usize>;
+    "HASHING_QUEUE_TIMEOUT_MS" hashing_queue_timeout_ms: Option<u64>;
+    "HASH_TOKENS" hash_tokens: bool;
+    "HTPASSWD_FILE" htpasswd_file: Option<String>;
+    "INDEX_PRUNE_BATCH_SIZE" index_prune_batch_size: Option<usize>;
+    "INDEX_PRUNE_INTERVAL_SECONDS" index_prune_interval_seconds: Option<u64>;
+    "LEGACY_TOKEN_KEYS_UNTIL" legacy_token_keys_until: Option<u64>;
+    "LOGIN_CONCURRENCY_LIMIT" login_concurrency_limit: Option<usize>;
+    "LOGIN_FAILURE_DELAY_MS" login_failure_delay_ms: Option<u64>;
+    "LOGIN_IP_LOCKOUT_SECONDS" login_ip_lockout_seconds: Option<u64>;
+    "LOGIN_LOCKOUT_SECONDS" login_lockout_seconds: Option<u64>;
+    "LOGIN_MAX_FAILURES" login_max_failures: Option<u64>;
+    "LOGIN_MAX_FAILURES_PER_IP" login_max_failures_per_ip: Option<u64>;
+    "MAX_CONNECTIONS" max_connections: Option<usize>;
+    "MAX_NOT_BEFORE_SECONDS" max_not_before_seconds: Option<u64>;
+    "MAX_SESSIONS_PER_USER" max_sessions_per_user: Option<u64>;
+    "METRICS_ADDR" metrics_addr: Option<SocketAddr>;
+    "METRICS_BACKEND" metrics_backend: Option<MetricsBackend>;
+    "MIN_BCRYPT_COST" min_bcrypt_cost: Option<u32>;
+    "RECENTLY_EXPIRED_GRACE_SECONDS" recently_expired_grace_seconds: Option<u64>;
+    "REDIS_AUX_DB" redis_aux_db: Option<u32>;
+    "REDIS_BREAKER_COOLDOWN_SECONDS" redis_breaker_cooldown_seconds: Option<u64>;
+    "REDIS_BREAKER_FAILURES" redis_breaker_failures: Option<u32>;
+    "REDIS_EVICTION_CHECK_INTERVAL_SECONDS" redis_eviction_check_interval_seconds: Option<u64>;
+    "REDIS_HOST" redis_host: Option<String>;
+    "REDIS_IDLE_TIMEOUT_SECONDS" redis_idle_timeout_seconds: Option<u64>;
+    "REDIS_MAX_LIFETIME_SECONDS" redis_max_lifetime_seconds: Option<u64>;
+    "REDIS_NAMESPACE" redis_namespace: Option<String>;
+    "REDIS_PASSWORD" redis_password: Option<Secret>;
+    "REDIS_POOL_MIN_IDLE" redis_pool_min_idle: Option<u32>;
+    "REDIS_PORT" redis_port: Option<u16>;
+    "REDIS_SESSION_DB" redis_session_db: Option<u32>;
+    "REDIS_TIMEOUT_MS" redis_timeout_ms: Option<u64>;
+    "REDIS_TLS" redis_tls: bool;
+    "REDIS_TLS_INSECURE" redis_tls_insecure: bool;
+    "REDIS_USERNAME" redis_username: Option<Secret>;
+    "REMEMBER_ME_TTL_SECONDS" remember_me_ttl_seconds: Option<u64>;
+    "REQUEST_LOG_EVERY" request_log_every: Option<u64>;
+    "REQUIRE_TLS_FOR_LOGIN" require_tls_for_login: bool;
+    "REQUIRE_TRACING" require_tracing: bool;
+    "REVOKE_SESSIONS_ON_SHUTDOWN" revoke_sessions_on_shutdown: bool;
+    "RUNTIME_MAX_BLOCKING_THREADS" runtime_max_blocking_threads: Option<usize>;
+    "RUNTIME_WORKER_THREADS" runtime_worker_threads: Option<usize>;
+    "RUST_LOG" rust_log: Option<String>;
+    "SESSION_COUNT_INTERVAL_SECONDS" session_count_interval_seconds: Option<u64>;
+    "SESSION_FORMAT" session_format: SessionFormat = SessionFormat::Json;
+    "SESSION_MAX_LIFETIME_SECONDS" session_max_lifetime_seconds: Option<u64>;
+    "SESSION_TTL_MAX_SECONDS" session_ttl_max_seconds: Option<u64>;
+    "SESSION_TTL_MIN_SECONDS" session_ttl_min_seconds: Option<u64>;
+    "SESSION_TTL_SECONDS" session_ttl_seconds: Option<u64>;
+    "SLOW_REDIS_LOG_KEYS" slow_redis_log_keys: bool;
+    "SLOW_REDIS_MS" slow_redis_ms: Option<u64>;
+    "SLOW_REQUEST_MS" slow_request_ms: Option<u64>;
+    "TLS_CERT_FILE" tls_cert_file: Option<String>;
+    "TLS_CIPHER_SUITES" tls_cipher_suites: Option<String>;
+    "TLS_CURVES" tls_curves: Option<String>;
+    "TLS_KEY_FILE" tls_key_file: Option<String>;
+    "TLS_MIN_VERSION" tls_min_version: Option<String>;
+    "TOKEN_CLASSES" token_classes: Option<String>;
+    "TRACE_CONTEXT_EXTRACTION" trace_context_extraction: bool = true;
+    "TRACE_CONTEXT_KEYS" trace_context_keys: Option<String>;
+    "TRACE_ERRORS_ONLY" trace_errors_only: bool;
+    "TRACE_ID_IN_RESPONSE" trace_id_in_response: bool;
+    "TRUSTED_PROXIES" trusted_proxies: Option<String>;
+    "USER_SCOPES" user_scopes: Option<String>;
+    "VALIDATE_CACHE_MARGIN_SECONDS" validate_cache_margin_seconds: Option<u64>;
+    "VALIDATE_CONCURRENCY_LIMIT" validate_concurrency_limit: Option<usize>;
+    "VALIDATE_RESULT_IN_RESPONSE" validate_result_in_response: bool;
+}
+
+/// Settings of one layer, in the order they were given.
+type Layer = Vec<(String, String)>;
+
+/// Environment variable naming the settings file.
+const CONFIG_FILE: &str = "CONFIG_FILE";
+
+fn kind(key: &str) -> Option<Kind> {
+    SETTINGS
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, kind)| *kind)
+}
+
+/// The secret setting `key` reads its value from the file named by, if it is
+/// one's `<KEY>_FILE` variant.
+fn secret_file_of(key: &str) -> Option<&str> {
+    let secret = key.strip_suffix("_FILE")?;
+    matches!(kind(secret), Some(Kind::Secret)).then_some(secret)
+}
+
+/// Why the configuration can't be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The settings file or a secret file can't be read.
+    Read {
+        path: String,
+        err: std::io::Error,
+    },
+    /// A line of the settings file isn't `KEY=value`.
+    Syntax {
+        path: String,
+        line: usize,
+    },
+    /// A command line argument isn't a setting flag or lacks its value.
+    Argument(String),
+    /// The settings file or command line names a setting that doesn't exist.
+    UnknownSetting(String),
+    InvalidValue {
+        key: String,
+        expected: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, err } => write!(f, "failed to read {}: {}", path, err),
+            ConfigError::Syntax { path, line } => {
+                write!(f, "{} line {}: expected KEY=value", path, line)
+            }
+            ConfigError::Argument(arg) => write!(f, "unexpected argument {}", arg),
+            ConfigError::UnknownSetting(key) => write!(f, "unknown setting {}", key),
+            ConfigError::InvalidValue { key, expected } => {
+                write!(f, "invalid value of {}, expected {}", key, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A secret setting. Its `Debug` and `Display` output is redacted, so it can't
+/// end up in a log by accident; `expose` hands out the value where it's used.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(crate::admin::REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(crate::admin::REDACTED)
+    }
+}
+
+impl Config {
+    /// The configuration of this process: the settings file, the environment
+    /// and the command line over no defaults.
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::default().layered(std::env::vars(), std::env::args().skip(1))
+    }
+
+    /// Applies the settings file, `env` and the command line `args` over the
+    /// settings already set, which act as the defaults. The file is named by
+    /// `--config` in `args` or by `CONFIG_FILE` in `env`.
+    pub fn layered(
+        mut self,
+        env: impl IntoIterator<Item = (String, String)>,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Config, ConfigError> {
+        let env: Layer = env.into_iter().collect();
+        let (path, flags) = parse_args(args)?;
+        let path = path.or_else(|| {
+            env.iter()
+                .find(|(key, _)| key == CONFIG_FILE)
+                .map(|(_, path)| path.clone())
+        });
+        if let Some(path) = path {
+            let content = std::fs::read_to_string(&path).map_err(|err| ConfigError::Read {
+                path: path.clone(),
+                err,
+            })?;
+            self.apply(parse_file(&path, &content)?)?;
+        }
+        // variables of other programs share the environment, so only known
+        // ones are taken
+        self.apply(
+            env.into_iter()
+                .filter(|(key, _)| kind(key).is_some() || secret_file_of(key).is_some())
+                .collect(),
+        )?;
+        self.apply(flags)?;
+        Ok(self)
+    }
+
+    /// Applies one layer's settings. A secret's `<KEY>_FILE` wins over the
+    /// `<KEY>` of the same layer.
+    fn apply(&mut self, mut layer: Layer) -> Result<(), ConfigError> {
+        layer.sort_by_key(|(key, _)| secret_file_of(key).is_some());
+        for (key, value) in layer {
+            match secret_file_of(&key) {
+                Some(secret) => {
+                    let content =
+                        std::fs::read_to_string(&value).map_err(|err| ConfigError::Read {
+                            path: value.clone(),
+                            err,
+                        })?;
+                    self.set(secret, content.trim_end_matches(&['\r', '\n'][..]))?;
+                }
+                None => self.set(&key, value)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets `key` to `value`, failing for unknown settings and malformed values.
+    pub fn set(&mut self, key: &str, value: impl Into<String>) -> Result<(), ConfigError> {
+        let kind = kind(key).ok_or_else(|| ConfigError::UnknownSetting(key.to_owned()))?;
+        self.assign(key, &value.into())
+            .ok_or_else(|| ConfigError::InvalidValue {
+                key: key.to_owned(),
+                expected: kind.expected(),
+            })
+    }
+}
+
+/// Splits `args` into the settings file named by `--config` and the settings
+/// set by the other flags.
+fn parse_args(
+    args: impl IntoIterator<Item = String>,
+) -> Result<(Option<String>, Layer), ConfigError> {
+    let mut path = None;
+    let mut flags = Vec::new();
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let flag = arg
+            .strip_prefix("--")
+            .filter(|flag| !flag.is_empty())
+            .ok_or_else(|| ConfigError::Argument(arg.clone()))?;
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_owned())),
+            None => (flag, None),
+        };
+        let key = name.replace('-', "_").to_uppercase();
+        if name != "config" && kind(&key).is_none() && secret_file_of(&key).is_none() {
+            return Err(ConfigError::UnknownSetting(key));
+        }
+        let value = match value {
+            Some(value) => value,
+            None if matches!(kind(&key), Some(Kind::Flag))
+                && args.peek().is_none_or(|next| next.starts_with("--")) =>
+            {
+                "true".to_owned()
+            }
+            None => args
+                .next()
+                .ok_or_else(|| ConfigError::Argument(arg.clone()))?,
+        };
+        if key == CONFIG_FILE || name == "config" {
+            path = Some(value);
+        } else {
+            flags.push((key, value));
+        }
+    }
+    Ok((path, flags))
+}
+
+/// Parses `KEY=value` lines, skipping blank lines and `#` comments.
+fn parse_file(path: &str, content: &str) -> Result<Layer, ConfigError> {
+    let mut settings = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| ConfigError::Syntax {
+            path: path.to_owned(),
+            line: index + 1,
+        })?;
+        settings.push((key.trim().to_owned(), value.trim().to_owned()));
+    }
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_parse_into_typed_fields() {
+        let mut config = Config::default();
+        assert!(config.trace_context_extraction);
+        config.set("TRACE_CONTEXT_EXTRACTION", "0").unwrap();
+        config.set("SESSION_FORMAT", "binary").unwrap();
+        config.set("REDIS_PORT", "6380").unwrap();
+        assert!(!config.trace_context_extraction);
+        assert_eq!(config.session_format, SessionFormat::Binary);
+        assert_eq!(config.redis_port, Some(6380));
+
+        let err = config.set("TRACE_CONTEXT_EXTRACTION", "off").unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidValue { .. }));
+        assert!(config.set("REDIS_PORT", "70000").is_err());
+        assert!(config.set("SESSION_FORMAT", "xml").is_err());
+    }
+
+    /// Writes `content` to a file of its own in the temporary directory.
+    fn temp_file(content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("auth-config-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let file = temp_file("# defaults\nREDIS_PORT=6380\nREDIS_SESSION_DB=1\nAPP_ENV=staging\n");
+        let env = vars(&[
+            ("CONFIG_FILE", &file),
+            ("REDIS_SESSION_DB", "2"),
+            ("APP_ENV", "production"),
+            // variables of other programs are ignored
+            ("PATH", "/usr/bin"),
+        ]);
+        let args = ["--app-env", "test", "--hash-tokens"].map(str::to_owned);
+
+        let config = Config::default().layered(env, args).unwrap();
+
+        assert_eq!(config.redis_port, Some(6380));
+        assert_eq!(config.redis_session_db, Some(2));
+        assert_eq!(config.app_env.as_deref(), Some("test"));
+        assert!(config.hash_tokens);
+    }
+
+    #[test]
+    fn unknown_settings_of_the_file_or_command_line_fail() {
+        let file = temp_file("REDIS_PROT=6380\n");
+        let err = Config::default()
+            .layered(vars(&[("CONFIG_FILE", &file)]), [])
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::UnknownSetting(key) if key == "REDIS_PROT"));
+
+        let err = Config::default()
+            .layered([], ["--redis-prot=6380".to_owned()])
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::UnknownSetting(key) if key == "REDIS_PROT"));
+    }
+
+    #[test]
+    fn secret_file_wins_over_the_value_of_its_layer() {
+        let file = temp_file("from-file\n");
+        let env = vars(&[("API_KEY", "from-env"), ("API_KEY_FILE", &file)]);
+
+        let config = Config::default().layered(env, []).unwrap();
+
+        assert_eq!(
+            config.api_key.as_ref().map(Secret::expose),
+            Some("from-file")
+        );
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let mut config = Config::default();
+        config.set("API_KEY", "shared-secret").unwrap();
+        let secret = config.api_key.clone().unwrap();
+
+        assert_eq!(secret.expose(), "shared-secret");
+        assert_eq!(secret.to_string(), crate::admin::REDACTED);
+        assert!(!format!("{:?}", config).contains("shared-secret"));
+    }
+}
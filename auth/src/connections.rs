@@ -0,0 +1,87 @@
+//! Global cap on open client connections.
+//!
+//! Tower layers on the server only see requests, so the limit is enforced on the
+//! stream of accepted connections instead: every open connection holds a slot
+//! until it is closed.
+
+use crate::metrics::{self, Metrics};
+use hyper::server::conn::AddrStream;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::server::{Connected, TcpConnectInfo, TcpIncoming};
+
+/// Passes on accepted connections while fewer than `max` are open. Connections
+/// over the limit are closed right away and counted in
+/// `CONNECTIONS_REJECTED_TOTAL`.
+pub fn limit(
+    incoming: TcpIncoming,
+    max: usize,
+    sink: Arc<dyn Metrics>,
+) -> impl Stream<Item = Result<LimitedConn, io::Error>> {
+    let slots = Arc::new(Semaphore::new(max));
+    incoming.filter_map(move |conn| match conn {
+        Ok(conn) => match slots.clone().try_acquire_owned() {
+            Ok(permit) => Some(Ok(LimitedConn {
+                inner: conn,
+                _permit: permit,
+            })),
+            Err(_) => {
+                println!(
+                    "rejecting connection from {}: {} connections open",
+                    conn.remote_addr(),
+                    max
+                );
+                sink.increment_counter(metrics::CONNECTIONS_REJECTED_TOTAL, &[]);
+                None
+            }
+        },
+        Err(err) => Some(Err(err)),
+    })
+}
+
+/// An accepted connection holding one slot of the limit.
+pub struct LimitedConn {
+    inner: AddrStream,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Connected for LimitedConn {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.inner.connect_info()
+    }
+}
+
+impl AsyncRead for LimitedConn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LimitedConn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
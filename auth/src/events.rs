@@ -0,0 +1,178 @@
+//! Auth events for downstream systems, such as logins and revocations.
+//!
+//! Handlers hand events to an `EventSink`, which must not block them: the NATS
+//! sink queues events for a background task and drops them when the queue is
+//! full or the server is unreachable, so publishing never fails a request.
+
+/// Something that happened to sessions.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A session was issued to `user` (empty for guests), whose credentials
+    /// `backend` verified.
+    Login {
+        user: String,
+        backend: &'static str,
+        class: String,
+    },
+    /// Sessions were revoked: those of `user`, or every session when unset.
+    SessionsRevoked {
+        user: Option<String>,
+        reason: &'static str,
+    },
+}
+
+impl Event {
+    /// Name of the kind of event, the last part of its subject.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Login { .. } => "login",
+            Event::SessionsRevoked { .. } => "sessions_revoked",
+        }
+    }
+
+    /// The event as published, with the unix `time` it happened at.
+    pub fn to_json(&self, time: u64) -> serde_json::Value {
+        match self {
+            Event::Login {
+                user,
+                backend,
+                class,
+            } => serde_json::json!({
+                "type": self.name(),
+                "time": time,
+                "user": user,
+                "backend": backend,
+                "class": class,
+            }),
+            Event::SessionsRevoked { user, reason } => serde_json::json!({
+                "type": self.name(),
+                "time": time,
+                "user": user,
+                "reason": reason,
+            }),
+        }
+    }
+}
+
+/// Receives the service's events. `emit` is called from request handlers and
+/// must return without waiting on I/O.
+pub trait EventSink: Send + Sync {
+    fn emit(&self, event: Event);
+}
+
+/// Discards everything; used when no event publisher is configured.
+pub struct NoopEvents;
+
+impl EventSink for NoopEvents {
+    fn emit(&self, _event: Event) {}
+}
+
+#[cfg(feature = "nats")]
+pub use nats::NatsEvents;
+
+#[cfg(feature = "nats")]
+mod nats {
+    //! A minimal publisher of the NATS text protocol: `CONNECT` once, then one
+    //! `PUB` per event, answering the server's `PING`s.
+
+    use super::{Event, EventSink};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc;
+
+    /// Events queued while the connection is slow or down.
+    const QUEUE: usize = 1024;
+    const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+    /// Events dropped since the last report, so a full queue is logged once
+    /// rather than once per event.
+    static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+    /// Publishes each event as JSON to `<prefix>.<event name>`.
+    pub struct NatsEvents {
+        prefix: String,
+        queue: mpsc::Sender<(String, Vec<u8>)>,
+    }
+
+    impl NatsEvents {
+        /// Starts publishing to the NATS server at `addr` (`host:port`). Must
+        /// be called within a tokio runtime.
+        pub fn spawn(addr: String, prefix: String) -> Self {
+            let (queue, events) = mpsc::channel(QUEUE);
+            tokio::spawn(publish(addr, events));
+            NatsEvents { prefix, queue }
+        }
+    }
+
+    impl EventSink for NatsEvents {
+        fn emit(&self, event: Event) {
+            let subject = format!("{}.{}", self.prefix, event.name());
+            let payload = event.to_json(crate::unix_now()).to_string().into_bytes();
+            if self.queue.try_send((subject, payload)).is_err()
+                && DROPPED.fetch_add(1, Ordering::Relaxed) == 0
+            {
+                println!("auth event queue is full, dropping events");
+            }
+        }
+    }
+
+    /// Publishes queued events, reconnecting until the queue is closed. Events
+    /// taken from the queue when the connection fails are lost.
+    async fn publish(addr: String, mut events: mpsc::Receiver<(String, Vec<u8>)>) {
+        loop {
+            match connection(&addr, &mut events).await {
+                Ok(()) => return,
+                Err(err) => println!("nats connection to {} failed: {}", addr, err),
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn connection(
+        addr: &str,
+        events: &mut mpsc::Receiver<(String, Vec<u8>)>,
+    ) -> std::io::Result<()> {
+        let (read, mut write) = TcpStream::connect(addr).await?.into_split();
+        let mut lines = BufReader::new(read).lines();
+        match lines.next_line().await? {
+            Some(info) if info.starts_with("INFO") => {}
+            _ => return Err(protocol_error("expected INFO from the server")),
+        }
+        write
+            .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"auth\"}\r\n")
+            .await?;
+        println!("publishing auth events to nats at {}", addr);
+        loop {
+            tokio::select! {
+                event = events.recv() => {
+                    let (subject, payload) = match event {
+                        Some(event) => event,
+                        None => return Ok(()),
+                    };
+                    let mut message = format!("PUB {} {}\r\n", subject, payload.len()).into_bytes();
+                    message.extend_from_slice(&payload);
+                    message.extend_from_slice(b"\r\n");
+                    write.write_all(&message).await?;
+                    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+                    if dropped > 0 {
+                        println!("dropped {} auth events", dropped);
+                    }
+                }
+                line = lines.next_line() => match line? {
+                    Some(line) if line.starts_with("PING") => write.write_all(b"PONG\r\n").await?,
+                    Some(line) if line.starts_with("-ERR") => {
+                        return Err(protocol_error(&line));
+                    }
+                    Some(_) => {}
+                    None => return Err(protocol_error("closed by the server")),
+                },
+            }
+        }
+    }
+
+    fn protocol_error(message: &str) -> std::io::Error {
+        std::io::Error::other(message.to_owned())
+    }
+}
@@ -0,0 +1,46 @@
+//! Entry points of the fuzz targets in `fuzz/`, for the parsers of data that
+//! comes from outside: stored sessions and request metadata. Each takes the
+//! raw fuzzer input and panics only on a bug.
+
+use crate::config::Config;
+use crate::{session_format, MetadataMap, SessionFormat, TraceContext};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::noop::NoopSpan;
+use tonic::metadata::{MetadataKey, MetadataValue};
+
+/// Decodes `value` as a stored session. A session that decodes must read back
+/// the same once written again as JSON.
+pub fn decode_session(value: &[u8]) {
+    if let Ok(session) = session_format::decode(value) {
+        let encoded = SessionFormat::Json.encode(&session);
+        assert_eq!(session_format::decode(&encoded).unwrap(), session);
+    }
+}
+
+/// Extracts trace context and baggage from metadata read out of `input`: a
+/// `TRACE_CONTEXT_KEYS` value on the first line, then a `key: value` entry per
+/// line. Entries tonic wouldn't accept as metadata are skipped.
+pub fn extract_trace_context(input: &[u8]) {
+    let input = String::from_utf8_lossy(input);
+    let mut lines = input.lines();
+    let mut config = Config::default();
+    if let Some(keys) = lines.next().filter(|keys| !keys.is_empty()) {
+        config.set("TRACE_CONTEXT_KEYS", keys).unwrap();
+    }
+    config
+        .set("BAGGAGE_SPAN_ATTRIBUTES", "tenant,user")
+        .unwrap();
+    let trace = TraceContext::from_config(&config);
+
+    let mut metadata = tonic::metadata::MetadataMap::new();
+    for (key, value) in lines.filter_map(|line| line.split_once(": ")) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value),
+        ) {
+            metadata.append(key, value);
+        }
+    }
+    let cx = crate::propagator().extract(&MetadataMap(&metadata, &trace));
+    trace.record_baggage(&cx, &mut NoopSpan::new());
+}
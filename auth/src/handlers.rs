@@ -0,0 +1,1461 @@
+//! The gRPC methods of `Auth` and `AuthAdmin` and the `handle_*` behind them.
+//! The redis and session helpers they share stay on `AuthService` in the crate
+//! root.
+
+use crate::auth::auth_admin_server::AuthAdmin;
+use crate::auth::auth_server::Auth;
+use crate::auth::{
+    BatchItemResult, InspectTokenRequest, InspectTokenResponse, IntrospectRequest,
+    IntrospectResponse, ListSessionsRequest, ListSessionsResponse, LoginAnonymousRequest,
+    LoginRequest, LoginResponse, RevokeAllSessionsRequest, RevokeAllSessionsResponse,
+    SessionCountRequest, SessionCountResponse, SessionInfo, TokenExchangeRequest,
+    ValidateBatchRequest, ValidateBatchResponse, ValidateRequest, ValidateResponse,
+    ValidationResult,
+};
+use crate::events::Event;
+use crate::pool::RedisConnection;
+use crate::scripts::{COUNT_SESSIONS, CREATE_SESSION};
+use crate::session_format::DecodeError;
+use crate::{
+    acquire_slot, audit, bearer_token, capture, constant_time_eq, distinct_scopes, expiry, fail,
+    handler_panicked, ipv6, metrics, over_tls, request_id, revoke_all_sessions, session_format,
+    status_with_reason, subsec_micros, unix_time, AuthService, Deadline, Session, ACCOUNT_DISABLED,
+    CACHE_HINT_SINCE_VERSION, DEFAULT_TOKEN_CLASS, EMERGENCY_BACKEND, GUEST_BACKEND, GUEST_SCOPE,
+    HTPASSWD_BACKEND, LOCKOUT_KEYS, MAX_BATCH_TOKENS, RECENTLY_EXPIRED, RETRY_AFTER_KEY,
+    STATIC_BACKEND, TOKEN_ATTEMPTS, TOKEN_KEYS, TRACE_ID_KEY,
+};
+use opentelemetry::trace::{Span, TraceId};
+use opentelemetry::{global, KeyValue};
+use prost_types::Timestamp;
+use r2d2_redis::{redis, redis::Commands};
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+#[tonic::async_trait]
+impl Auth for AuthService {
+    async fn login(
+        &self,
+        request: Request<LoginRequest>,
+    ) -> Result<Response<LoginResponse>, Status> {
+        self.capture("login", &request);
+        let span = self.trace.start_span("login", &request);
+        let trace_id = span.span_context().trace_id();
+        let request_id = request_id(request.metadata());
+        let result = self
+            .observed("login", request_id, self.handle_login(request, span))
+            .await;
+        self.with_trace_id(trace_id, result)
+    }
+    async fn login_anonymous(
+        &self,
+        request: Request<LoginAnonymousRequest>,
+    ) -> Result<Response<LoginResponse>, Status> {
+        self.capture("login_anonymous", &request);
+        let span = self.trace.start_span("login_anonymous", &request);
+        let trace_id = span.span_context().trace_id();
+        let request_id = request_id(request.metadata());
+        let result = self
+            .observed(
+                "login_anonymous",
+                request_id,
+                self.handle_login_anonymous(request, span),
+            )
+            .await;
+        self.with_trace_id(trace_id, result)
+    }
+    async fn validate(
+        &self,
+        request: Request<ValidateRequest>,
+    ) -> Result<Response<ValidateResponse>, Status> {
+        self.capture("validate", &request);
+        let span = self.trace.start_span("validate", &request);
+        let trace_id = span.span_context().trace_id();
+        let request_id = request_id(request.metadata());
+        let result = self
+            .observed("validate", request_id, self.handle_validate(request, span))
+            .await;
+        self.with_trace_id(trace_id, result)
+    }
+    async fn introspect(
+        &self,
+        request: Request<IntrospectRequest>,
+    ) -> Result<Response<IntrospectResponse>, Status> {
+        self.capture("introspect", &request);
+        let span = self.trace.start_span("introspect", &request);
+        let trace_id = span.span_context().trace_id();
+        let request_id = request_id(request.metadata());
+        let result = self
+            .observed(
+                "introspect",
+                request_id,
+                self.handle_introspect(request, span),
+            )
+            .await;
+        self.with_trace_id(trace_id, result)
+    }
+
+    async fn validate_batch(
+        &self,
+        request: Request<ValidateBatchRequest>,
+    ) -> Result<Response<ValidateBatchResponse>, Status> {
+        self.capture("validate_batch", &request);
+        let span = self.trace.start_span("validate_batch", &request);
+        let trace_id = span.span_context().trace_id();
+        let request_id = request_id(request.metadata());
+        let result = self
+            .observed(
+                "validate_batch",
+                request_id,
+                self.handle_validate_batch(request, span),
+            )
+            .await;
+        self.with_trace_id(trace_id, result)
+    }
+
+    async fn session_count(
+        &self,
+        request: Request<SessionCountRequest>,
+    ) -> Result<Response<SessionCountResponse>, Status> {
+        self.capture("session_count", &request);
+        let span = self.trace.start_span("session_count", &request);
+        let trace_id = span.span_context().trace_id();
+        let request_id = request_id(request.metadata());
+        let result = self
+            .observed(
+                "session_count",
+                request_id,
+                self.handle_session_count(request, span),
+            )
+            .await;
+        self.with_trace_id(trace_id, result)
+    }
+
+    async fn token_exchange(
+        &self,
+        request: Request<TokenExchangeRequest>,
+    ) -> Result<Response<LoginResponse>, Status> {
+        self.capture("token_exchange", &request);
+        let span = self.trace.start_span("token_exchange", &request);
+        let trace_id = span.span_context().trace_id();
+        let request_id = request_id(request.metadata());
+        let result = self
+            .observed(
+                "token_exchange",
+                request_id,
+                self.handle_token_exchange(request, span),
+            )
+            .await;
+        self.with_trace_id(trace_id, result)
+    }
+}
+
+/// The `AuthAdmin` service, sharing the state of the `Auth` service it was split
+/// off by `AuthService::into_services`.
+pub struct AdminService {
+    pub(crate) auth: Arc<AuthService>,
+}
+
+#[tonic::async_trait]
+impl AuthAdmin for AdminService {
+    async fn list_sessions(
+        &self,
+        request: Request<ListSessionsRequest>,
+    ) -> Result<Response<ListSessionsResponse>, Status> {
+        let request_id = request_id(request.metadata());
+        self.auth
+            .observed(
+                "list_sessions",
+                request_id,
+                self.auth.handle_list_sessions(request),
+            )
+            .await
+    }
+
+    async fn revoke_all_sessions(
+        &self,
+        request: Request<RevokeAllSessionsRequest>,
+    ) -> Result<Response<RevokeAllSessionsResponse>, Status> {
+        let request_id = request_id(request.metadata());
+        self.auth
+            .observed(
+                "revoke_all_sessions",
+                request_id,
+                self.auth.handle_revoke_all_sessions(request),
+            )
+            .await
+    }
+
+    async fn inspect_token(
+        &self,
+        request: Request<InspectTokenRequest>,
+    ) -> Result<Response<InspectTokenResponse>, Status> {
+        let request_id = request_id(request.metadata());
+        self.auth
+            .observed(
+                "inspect_token",
+                request_id,
+                self.auth.handle_inspect_token(request),
+            )
+            .await
+    }
+}
+
+impl AuthService {
+    /// Appends the call to the capture file if it's sampled.
+    fn capture<T: capture::Capturable>(&self, method: &str, request: &Request<T>) {
+        if let Some(recorder) = &self.capture {
+            recorder.record(method, request);
+        }
+    }
+
+    /// Puts the call's trace id into the response or error metadata, if enabled.
+    fn with_trace_id<T>(
+        &self,
+        trace_id: TraceId,
+        mut result: Result<Response<T>, Status>,
+    ) -> Result<Response<T>, Status> {
+        if !self.trace_id_in_response || trace_id == TraceId::INVALID {
+            return result;
+        }
+        if let Ok(value) = trace_id.to_string().parse() {
+            let metadata = match &mut result {
+                Ok(response) => response.metadata_mut(),
+                Err(status) => status.metadata_mut(),
+            };
+            metadata.insert(TRACE_ID_KEY, value);
+        }
+        result
+    }
+
+    /// Awaits a handler, recording its outcome and latency, and logging it if it
+    /// took longer than the slow request threshold. A panicking handler is
+    /// answered with `INTERNAL` instead of dropping the connection.
+    async fn observed<T>(
+        &self,
+        method: &'static str,
+        request_id: Option<String>,
+        handler: impl Future<Output = Result<T, Status>>,
+    ) -> Result<T, Status> {
+        let start = Instant::now();
+        let mut handler = Box::pin(handler);
+        let result = std::future::poll_fn(|cx| {
+            match std::panic::catch_unwind(AssertUnwindSafe(|| handler.as_mut().poll(cx))) {
+                Ok(poll) => poll,
+                Err(payload) => Poll::Ready(Err(handler_panicked(
+                    method,
+                    request_id.as_deref(),
+                    payload,
+                ))),
+            }
+        })
+        .await;
+        let elapsed = start.elapsed();
+
+        if self
+            .slow_request
+            .is_some_and(|threshold| elapsed > threshold)
+        {
+            println!(
+                "slow request: method {} took {:?} (request id {})",
+                method,
+                elapsed,
+                request_id.as_deref().unwrap_or("none")
+            );
+            self.metrics
+                .increment_counter(metrics::SLOW_REQUESTS_TOTAL, &[("method", method)]);
+        }
+
+        let outcome = match &result {
+            Ok(_) => "ok".to_owned(),
+            Err(status) => {
+                self.metrics.increment_counter(
+                    metrics::ERRORS_TOTAL,
+                    &[
+                        ("method", method),
+                        ("code", metrics::code_label(status.code())),
+                    ],
+                );
+                format!("{:?}", status.code())
+            }
+        };
+        self.metrics.increment_counter(
+            metrics::REQUESTS_TOTAL,
+            &[("method", method), ("result", &outcome)],
+        );
+        self.metrics.observe_histogram(
+            metrics::REQUEST_DURATION_SECONDS,
+            &[("method", method)],
+            elapsed.as_secs_f64(),
+        );
+
+        result.map_err(|status| self.client_status(method, request_id.as_deref(), status))
+    }
+
+    /// The error to send the client. With `ERROR_DETAIL_LEVEL=opaque` the
+    /// message is replaced by the generic description of its code and logged
+    /// instead; the code and metadata, such as `x-auth-error`, are kept for
+    /// clients to act on.
+    fn client_status(&self, method: &str, request_id: Option<&str>, status: Status) -> Status {
+        if !self.opaque_errors {
+            return status;
+        }
+        println!(
+            "{} failed with {:?}: {} (request id {})",
+            method,
+            status.code(),
+            status.message(),
+            request_id.unwrap_or("none")
+        );
+        Status::with_metadata(
+            status.code(),
+            status.code().description(),
+            status.metadata().clone(),
+        )
+    }
+
+    async fn handle_login(
+        &self,
+        request: Request<LoginRequest>,
+        mut span: global::BoxedSpan,
+    ) -> Result<Response<LoginResponse>, Status> {
+        let deadline = self.redis_deadline(request.metadata());
+        if self.require_tls_for_login && !over_tls(&request) {
+            return Err(fail(
+                &mut span,
+                Status::failed_precondition("login requires TLS"),
+            ));
+        }
+
+        let _permit = acquire_slot(&self.login_limit, &mut span)?;
+
+        let peer = request.remote_addr();
+        let client_ip = self.client_ip(&request);
+        let mut req = request.into_inner();
+
+        let missing = if req.user.is_empty() {
+            Some("user required")
+        } else if req.password.is_empty() {
+            Some("password required")
+        } else {
+            None
+        };
+        if let Some(message) = missing {
+            return Err(fail(&mut span, Status::invalid_argument(message)));
+        }
+        req.scopes = match distinct_scopes(std::mem::take(&mut req.scopes)) {
+            Ok(scopes) => scopes,
+            Err(err) => {
+                return Err(fail(&mut span, err));
+            }
+        };
+
+        let class_name = if req.class.is_empty() {
+            DEFAULT_TOKEN_CLASS
+        } else {
+            req.class.as_str()
+        };
+        let class = match self.token_classes.get(class_name) {
+            Some(class) => class,
+            None => {
+                return Err(fail(
+                    &mut span,
+                    Status::invalid_argument(format!("unknown token class {}", class_name)),
+                ));
+            }
+        };
+        span.set_attribute(KeyValue::new("auth.token_class", class_name.to_owned()));
+
+        let mut aux = self.redis_conn(&self.aux_pool, deadline, &mut span)?;
+
+        match self.timed_redis("check_lockout", LOCKOUT_KEYS, || {
+            self.locked_out(&mut aux, &req.user, client_ip)
+        }) {
+            Ok(None) => {}
+            Ok(Some(retry_after)) => {
+                drop(aux);
+                self.failure_delay().await;
+                let mut metadata = tonic::metadata::MetadataMap::new();
+                metadata.insert(RETRY_AFTER_KEY, retry_after.as_secs().into());
+                return Err(fail(
+                    &mut span,
+                    Status::with_metadata(
+                        tonic::Code::ResourceExhausted,
+                        format!(
+                            "too many failed login attempts, retry in {} seconds",
+                            retry_after.as_secs()
+                        ),
+                        metadata,
+                    ),
+                ));
+            }
+            Err(err) => {
+                fail(&mut span, &err);
+                return Err(deadline.status_or(Status::internal(err.to_string())));
+            }
+        }
+
+        let (backend, ttl) = match self
+            .emergency_admin
+            .as_ref()
+            .filter(|admin| admin.user == req.user)
+        {
+            Some(admin) => {
+                span.set_attribute(KeyValue::new("auth.emergency", true));
+                let granted = admin.active(self.unix_now())
+                    && constant_time_eq(req.password.as_bytes(), admin.password.as_bytes());
+                audit::record(&format!(
+                    "emergency admin login {} for {} from {}",
+                    if granted { "GRANTED" } else { "REJECTED" },
+                    admin.user,
+                    peer.map_or_else(|| "unknown peer".to_owned(), |peer| peer.to_string())
+                ));
+                if !granted {
+                    self.record_failure(&mut aux, &req.user, client_ip);
+                    drop(aux);
+                    self.failure_delay().await;
+                    return Err(fail(&mut span, Status::unauthenticated("wrong password")));
+                }
+                (EMERGENCY_BACKEND, admin.ttl)
+            }
+            None => {
+                if !self.users.known(&req.user) || self.users.banned(&req.user) {
+                    self.record_failure(&mut aux, &req.user, client_ip);
+                    drop(aux);
+                    self.failure_delay().await;
+                    return Err(fail(&mut span, Status::unauthenticated("user not found")));
+                }
+
+                span.add_event("user well known", vec![]);
+
+                let (backend, valid) = match self.users.passwords.get(&req.user) {
+                    Some(password) => (STATIC_BACKEND, *password == req.password),
+                    None => {
+                        // no pooled connection is held while queueing for a slot
+                        drop(aux);
+                        let slot = self.hashing_limit.acquire(&mut span).await?;
+                        let hash = self.users.htpasswd[&req.user].hash.clone();
+                        let password = req.password.clone();
+                        let valid = tokio::task::spawn_blocking(move || hash.verify(&password))
+                            .await
+                            .unwrap_or(false);
+                        drop(slot);
+                        aux = self.redis_conn(&self.aux_pool, deadline, &mut span)?;
+                        (HTPASSWD_BACKEND, valid)
+                    }
+                };
+                if !valid {
+                    self.record_failure(&mut aux, &req.user, client_ip);
+                    drop(aux);
+                    self.failure_delay().await;
+                    return Err(fail(&mut span, Status::unauthenticated("wrong password")));
+                }
+                if self.users.account_disabled(&req.user) {
+                    println!("refusing login of {}: {}", req.user, ACCOUNT_DISABLED);
+                    self.record_failure(&mut aux, &req.user, client_ip);
+                    drop(aux);
+                    self.failure_delay().await;
+                    span.set_attribute(KeyValue::new("auth.failure_reason", ACCOUNT_DISABLED));
+                    return Err(fail(&mut span, Status::unauthenticated("wrong password")));
+                }
+
+                (
+                    backend,
+                    self.granted_ttl(req.requested_ttl_seconds, req.remember_me, class),
+                )
+            }
+        };
+
+        let allowed = self.users.scopes.get(&req.user);
+        if let Some(scope) = req
+            .scopes
+            .iter()
+            .find(|scope| !allowed.is_some_and(|allowed| allowed.contains(scope)))
+        {
+            return Err(fail(
+                &mut span,
+                Status::permission_denied(format!("scope {} not allowed", scope)),
+            ));
+        }
+
+        let now = self.clock.now();
+        let not_before = match self.not_before(now, req.not_before.as_ref(), ttl) {
+            Ok(not_before) => not_before,
+            Err(err) => {
+                return Err(fail(&mut span, err));
+            }
+        };
+
+        span.set_attribute(KeyValue::new("auth.backend", backend));
+        self.metrics
+            .increment_counter(metrics::LOGINS_TOTAL, &[("backend", backend)]);
+
+        self.reset_failures(&mut aux, &req.user);
+        drop(aux);
+        let mut conn = self.redis_conn(&self.pool, deadline, &mut span)?;
+
+        let session = Session {
+            session_id: self.session_id.clone(),
+            login_at: unix_time(now),
+            login_micros: subsec_micros(now),
+            not_before,
+            ttl: ttl.as_secs(),
+            client_ip: if self.bind_sessions_to_ip {
+                client_ip.map(ipv6)
+            } else {
+                None
+            },
+            one_time: req.one_time || class.single_use,
+            scopes: req.scopes.clone(),
+            class: class_name.to_owned(),
+            user: req.user.clone(),
+            parent: String::new(),
+        };
+
+        let indexes = [
+            self.user_sessions_key(&req.user),
+            self.class_sessions_key(class_name, &req.user),
+        ];
+        let token = self.store_session(
+            &mut conn,
+            &session,
+            ttl,
+            indexes,
+            class.max_sessions,
+            deadline,
+            &mut span,
+        )?;
+        self.events.emit(Event::Login {
+            user: req.user.clone(),
+            backend,
+            class: class_name.to_owned(),
+        });
+
+        self.metrics.observe_histogram(
+            metrics::GRANTED_TTL_SECONDS,
+            &[("class", class_name)],
+            ttl.as_secs_f64(),
+        );
+
+        let expire_at = std::option::Option::Some(expiry(now, ttl));
+
+        Ok(Response::new(LoginResponse {
+            token,
+            expire_at,
+            session_id: self.debug_session_id(req.client_version),
+            issued_at: Some(Timestamp::from(now)),
+            not_before: (not_before != 0).then_some(Timestamp {
+                seconds: not_before as i64,
+                nanos: 0,
+            }),
+        }))
+    }
+
+    /// Issues a guest session. It is stored like any other, with an empty user.
+    async fn handle_login_anonymous(
+        &self,
+        request: Request<LoginAnonymousRequest>,
+        mut span: global::BoxedSpan,
+    ) -> Result<Response<LoginResponse>, Status> {
+        let ttl = match self.guest_ttl {
+            Some(ttl) => ttl,
+            None => {
+                return Err(fail(
+                    &mut span,
+                    Status::unimplemented("guest sessions are disabled"),
+                ));
+            }
+        };
+        let deadline = self.redis_deadline(request.metadata());
+        let _permit = acquire_slot(&self.login_limit, &mut span)?;
+
+        let client_ip = self.client_ip(&request);
+        let req = request.into_inner();
+
+        let mut conn = self.redis_conn(&self.pool, deadline, &mut span)?;
+
+        let now = self.clock.now();
+        let session = Session {
+            session_id: self.session_id.clone(),
+            login_at: unix_time(now),
+            login_micros: subsec_micros(now),
+            not_before: 0,
+            // guest sessions keep the lifetime they started with
+            ttl: 0,
+            client_ip: if self.bind_sessions_to_ip {
+                client_ip.map(ipv6)
+            } else {
+                None
+            },
+            one_time: false,
+            scopes: vec![GUEST_SCOPE.to_owned()],
+            class: DEFAULT_TOKEN_CLASS.to_owned(),
+            user: String::new(),
+            parent: String::new(),
+        };
+
+        // no user bounds the index, so drop the entries every guest token outlived
+        let guests = self.guest_sessions_key();
+        let outlived = session.login_at.saturating_sub(ttl.as_secs());
+        if let Err(err) = conn.zrembyscore::<_, _, _, ()>(&guests, "-inf", outlived) {
+            println!("failed to prune guest sessions: {}", err);
+        }
+        let token = self.store_session(
+            &mut conn,
+            &session,
+            ttl,
+            [guests.clone(), guests],
+            0,
+            deadline,
+            &mut span,
+        )?;
+
+        span.set_attribute(KeyValue::new("auth.backend", GUEST_BACKEND));
+        self.metrics
+            .increment_counter(metrics::LOGINS_TOTAL, &[("backend", GUEST_BACKEND)]);
+        self.events.emit(Event::Login {
+            user: String::new(),
+            backend: GUEST_BACKEND,
+            class: session.class,
+        });
+
+        Ok(Response::new(LoginResponse {
+            token,
+            expire_at: Some(expiry(now, ttl)),
+            session_id: self.debug_session_id(req.client_version),
+            issued_at: Some(Timestamp::from(now)),
+            not_before: None,
+        }))
+    }
+
+    /// Stores `session` under a newly generated token for `ttl`, indexed in the
+    /// per-user index and the per-user class index `indexes` (or twice in the
+    /// guest index), and returns the
+    /// token. See `CREATE_SESSION` for `max_sessions`.
+    #[allow(clippy::too_many_arguments)]
+    fn store_session(
+        &self,
+        conn: &mut RedisConnection,
+        session: &Session,
+        ttl: Duration,
+        indexes: [String; 2],
+        max_sessions: u64,
+        deadline: Deadline,
+        span: &mut global::BoxedSpan,
+    ) -> Result<String, Status> {
+        // redis rejects an expiry of zero
+        let ttl_arg = usize::try_from(ttl.as_secs().max(1)).unwrap_or(usize::MAX);
+        let encoded = self.session_format.encode(session);
+        let mut token = None;
+        for _ in 0..TOKEN_ATTEMPTS {
+            let candidate = Uuid::new_v4().hyphenated().to_string();
+            let mut create_session = CREATE_SESSION.key(self.token_key(&candidate));
+            create_session.key(&indexes[0]);
+            create_session.key(&indexes[1]);
+            if self.revoke_sessions_on_shutdown {
+                create_session.key(self.instance_sessions_key());
+            }
+            let created = self.timed_redis("create_session", TOKEN_KEYS, || {
+                create_session
+                    .arg(&encoded[..])
+                    .arg(ttl_arg)
+                    .arg(session.login_at)
+                    .arg(max_sessions)
+                    .invoke::<bool>(conn)
+            });
+            let created = match created {
+                Ok(created) => created,
+                Err(err) => {
+                    fail(span, &err);
+                    return Err(deadline.status_or(Status::internal(err.to_string())));
+                }
+            };
+            if created {
+                token = Some(candidate);
+                break;
+            }
+            println!("generated token is already in use, generating another one");
+        }
+        let token = match token {
+            Some(token) => token,
+            None => {
+                return Err(fail(
+                    span,
+                    Status::internal("failed to generate a unique token"),
+                ));
+            }
+        };
+
+        if let Some(grace) = self.recently_expired_grace {
+            // outlives the token by the grace period, see `handle_validate`
+            let marker_ttl = ttl_arg.saturating_add(grace.as_secs() as usize);
+            if let Err(err) = conn.set_ex::<_, _, ()>(
+                self.expired_marker_key(&self.token_key(&token)),
+                1,
+                marker_ttl,
+            ) {
+                println!("failed to write expiry marker: {}", err);
+            }
+        }
+
+        Ok(token)
+    }
+
+    async fn handle_validate(
+        &self,
+        request: Request<ValidateRequest>,
+        mut span: global::BoxedSpan,
+    ) -> Result<Response<ValidateResponse>, Status> {
+        let deadline = self.redis_deadline(request.metadata());
+        let _permit = acquire_slot(&self.validate_limit, &mut span)?;
+
+        let header_token = bearer_token(request.metadata()).map(str::to_owned);
+        let client_ip = self.client_ip(&request);
+        let req = request.into_inner();
+        let token = match header_token {
+            Some(header_token) if req.token.is_empty() => header_token,
+            Some(header_token) if header_token != req.token => {
+                return Err(fail(
+                    &mut span,
+                    Status::invalid_argument("token in body and authorization header differ"),
+                ));
+            }
+            _ => req.token,
+        };
+        if token.is_empty() {
+            return Err(fail(&mut span, Status::invalid_argument("token required")));
+        }
+        let in_response = req.result_in_response || self.validate_result_in_response;
+
+        let mut conn = self.redis_conn(&self.pool, deadline, &mut span)?;
+
+        let key = self.token_key(&token);
+        let mut reply = self.timed_redis("get_session", TOKEN_KEYS, || {
+            redis::pipe()
+                .get(&key)
+                .ttl(&key)
+                .get(self.session_epoch_key())
+                .query::<(r2d2_redis::redis::Value, i64, Option<u64>)>(&mut *conn)
+        });
+        if let Ok((value @ r2d2_redis::redis::Value::Nil, remaining, _)) = &mut reply {
+            if let Some((migrated, ttl)) = self.migrate_token_key(&mut conn, &token, &key) {
+                span.add_event("migrated legacy token key", vec![]);
+                *value = r2d2_redis::redis::Value::Data(migrated);
+                *remaining = ttl;
+            }
+        }
+        match reply {
+            Ok((value, remaining, revoked_before)) => match value {
+                r2d2_redis::redis::Value::Data(value) => {
+                    span.set_attribute(KeyValue::new("redis.result", "hit"));
+                    let session = match session_format::decode(&value) {
+                        Ok(session) => session,
+                        Err(DecodeError::Malformed) => {
+                            return Err(fail(
+                                &mut span,
+                                Status::internal("malformed session data"),
+                            ));
+                        }
+                        Err(unreadable) => {
+                            let err = self.drop_unreadable(&mut conn, &key, &unreadable);
+                            fail(&mut span, &err);
+                            return self.validation_failed(
+                                in_response,
+                                ValidationResult::Unknown,
+                                err,
+                            );
+                        }
+                    };
+                    if let Some((result, err)) = self.session_failure(
+                        &mut conn,
+                        &key,
+                        &session,
+                        client_ip,
+                        &req.required_scope,
+                        revoked_before.unwrap_or_default(),
+                    ) {
+                        fail(&mut span, &err);
+                        self.validation_failed(in_response, result, err)
+                    } else {
+                        span.add_event("token exists in redis", vec![]);
+                        let extended_to = if req.extend {
+                            self.extend_session(&mut conn, &key, &session, remaining)
+                        } else {
+                            None
+                        };
+                        span.set_attribute(KeyValue::new("auth.extended", extended_to.is_some()));
+                        let remaining = extended_to.unwrap_or(remaining);
+                        Ok(Response::new(ValidateResponse {
+                            session_id: self.debug_session_id(req.client_version),
+                            result: if in_response {
+                                ValidationResult::Valid as i32
+                            } else {
+                                ValidationResult::Unspecified as i32
+                            },
+                            cacheable_for_seconds: if req.client_version >= CACHE_HINT_SINCE_VERSION
+                            {
+                                self.cacheable_for(&session, remaining)
+                            } else {
+                                0
+                            },
+                            extended: extended_to.is_some(),
+                        }))
+                    }
+                }
+                r2d2_redis::redis::Value::Nil => {
+                    span.set_attribute(KeyValue::new("redis.result", "miss"));
+                    let recently_expired = self.recently_expired_grace.is_some()
+                        && conn.exists(self.expired_marker_key(&key)).unwrap_or(false);
+                    let (err, result) = if recently_expired {
+                        let err = status_with_reason(
+                            tonic::Code::Unauthenticated,
+                            "token recently expired",
+                            RECENTLY_EXPIRED,
+                        );
+                        (err, ValidationResult::RecentlyExpired)
+                    } else {
+                        let err =
+                            Status::unauthenticated(format!("wrong redis response: {:?}", value));
+                        (err, ValidationResult::Unknown)
+                    };
+                    fail(&mut span, &err);
+                    self.validation_failed(in_response, result, err)
+                }
+                _ => {
+                    span.set_attribute(KeyValue::new("redis.result", "error"));
+                    Err(fail(
+                        &mut span,
+                        Status::unauthenticated(format!("wrong redis response: {:?}", value)),
+                    ))
+                }
+            },
+            Err(err) if err.code() == Some("WRONGTYPE") => {
+                span.set_attribute(KeyValue::new("redis.result", "error"));
+                Err(fail(&mut span, self.drop_wrong_type(&mut conn, &key)))
+            }
+            Err(err) => {
+                span.set_attribute(KeyValue::new("redis.result", "error"));
+                Err(fail(
+                    &mut span,
+                    deadline.status_or(Status::unauthenticated(err.to_string())),
+                ))
+            }
+        }
+    }
+    async fn handle_validate_batch(
+        &self,
+        request: Request<ValidateBatchRequest>,
+        mut span: global::BoxedSpan,
+    ) -> Result<Response<ValidateBatchResponse>, Status> {
+        let deadline = self.redis_deadline(request.metadata());
+        let _permit = acquire_slot(&self.validate_limit, &mut span)?;
+
+        let client_ip = self.client_ip(&request);
+        let tokens = request.into_inner().tokens;
+        if tokens.len() > MAX_BATCH_TOKENS {
+            return Err(fail(
+                &mut span,
+                Status::invalid_argument(format!(
+                    "at most {} tokens can be validated at once",
+                    MAX_BATCH_TOKENS
+                )),
+            ));
+        }
+        span.set_attribute(KeyValue::new("batch.size", tokens.len() as i64));
+        if tokens.is_empty() {
+            return Ok(Response::new(ValidateBatchResponse::default()));
+        }
+
+        let mut conn = self.redis_conn(&self.pool, deadline, &mut span)?;
+
+        let keys: Vec<String> = tokens.iter().map(|token| self.token_key(token)).collect();
+        let mut lookup = redis::pipe();
+        lookup.get(self.session_epoch_key());
+        for key in &keys {
+            lookup.get(key);
+        }
+        let values = self.timed_redis("get_sessions", TOKEN_KEYS, || {
+            lookup.query::<Vec<Option<Vec<u8>>>>(&mut *conn)
+        });
+        let values: Vec<redis::RedisResult<Option<Vec<u8>>>> = match values {
+            Ok(values) => values.into_iter().map(Ok).collect(),
+            // a key of another type fails the whole pipeline, so the keys are
+            // read one by one to tell which
+            Err(err) if err.code() == Some("WRONGTYPE") => {
+                std::iter::once(self.session_epoch_key())
+                    .chain(keys.iter().cloned())
+                    .map(|key| {
+                        self.timed_redis("get_session", TOKEN_KEYS, || {
+                            conn.get::<_, Option<Vec<u8>>>(key)
+                        })
+                    })
+                    .collect()
+            }
+            Err(err) => {
+                fail(&mut span, &err);
+                return Err(deadline.status_or(Status::internal(err.to_string())));
+            }
+        };
+        let mut values = values.into_iter();
+        let revoked_before = match values.next() {
+            Some(Ok(epoch)) => epoch
+                .and_then(|epoch| String::from_utf8(epoch).ok()?.parse().ok())
+                .unwrap_or_default(),
+            Some(Err(err)) => {
+                fail(&mut span, &err);
+                return Err(deadline.status_or(Status::internal(err.to_string())));
+            }
+            None => unreachable!("the epoch is read first"),
+        };
+
+        let mut items = Vec::with_capacity(tokens.len());
+        for (index, ((token, key), value)) in tokens.iter().zip(&keys).zip(values).enumerate() {
+            let value = match value {
+                Ok(None) if !token.is_empty() => {
+                    match self.migrate_token_key(&mut conn, token, key) {
+                        Some((migrated, _)) => {
+                            span.add_event("migrated legacy token key", vec![]);
+                            Ok(Some(migrated))
+                        }
+                        None => Ok(None),
+                    }
+                }
+                value => value,
+            };
+            let (result, reason) = match value {
+                _ if token.is_empty() => (ValidationResult::Unknown, "token required".to_owned()),
+                Err(err) if err.code() == Some("WRONGTYPE") => {
+                    let err = self.drop_wrong_type(&mut conn, key);
+                    fail(&mut span, &err);
+                    (ValidationResult::Unknown, err.message().to_owned())
+                }
+                Err(err) => {
+                    return Err(fail(
+                        &mut span,
+                        deadline.status_or(Status::internal(err.to_string())),
+                    ));
+                }
+                Ok(Some(value)) => match session_format::decode(&value) {
+                    Ok(session) => {
+                        match self.session_failure(
+                            &mut conn,
+                            key,
+                            &session,
+                            client_ip,
+                            "",
+                            revoked_before,
+                        ) {
+                            Some((result, err)) => (result, err.message().to_owned()),
+                            None => (ValidationResult::Valid, String::new()),
+                        }
+                    }
+                    Err(DecodeError::Malformed) => {
+                        // a bug, but one the other tokens shouldn't fail for
+                        let err = Status::internal("malformed session data");
+                        fail(&mut span, &err);
+                        (ValidationResult::Unknown, err.message().to_owned())
+                    }
+                    Err(unreadable) => {
+                        let err = self.drop_unreadable(&mut conn, key, &unreadable);
+                        (ValidationResult::Unknown, err.message().to_owned())
+                    }
+                },
+                Ok(None)
+                    if self.recently_expired_grace.is_some()
+                        && conn.exists(self.expired_marker_key(key)).unwrap_or(false) =>
+                {
+                    (
+                        ValidationResult::RecentlyExpired,
+                        "token recently expired".to_owned(),
+                    )
+                }
+                Ok(None) => (ValidationResult::Unknown, "unknown token".to_owned()),
+            };
+            items.push(BatchItemResult {
+                index: index as u32,
+                result: result as i32,
+                // the result already tells clients what went wrong
+                reason: if self.opaque_errors {
+                    String::new()
+                } else {
+                    reason
+                },
+            });
+        }
+        let results: Vec<i32> = items.iter().map(|item| item.result).collect();
+
+        let valid = results
+            .iter()
+            .filter(|&&result| result == ValidationResult::Valid as i32)
+            .count();
+        span.set_attribute(KeyValue::new("batch.valid", valid as i64));
+
+        Ok(Response::new(ValidateBatchResponse { results, items }))
+    }
+
+    /// Counts the sessions of the token's user. The token must pass the checks of
+    /// `Validate`, so only the user's own count is revealed.
+    async fn handle_session_count(
+        &self,
+        request: Request<SessionCountRequest>,
+        mut span: global::BoxedSpan,
+    ) -> Result<Response<SessionCountResponse>, Status> {
+        let deadline = self.redis_deadline(request.metadata());
+        let _permit = acquire_slot(&self.validate_limit, &mut span)?;
+
+        let client_ip = self.client_ip(&request);
+        let token = request.into_inner().token;
+        if token.is_empty() {
+            return Err(fail(&mut span, Status::invalid_argument("token required")));
+        }
+
+        let mut conn = self.redis_conn(&self.pool, deadline, &mut span)?;
+
+        let key = self.token_key(&token);
+        let (value, revoked_before) = match self.timed_redis("get_session", TOKEN_KEYS, || {
+            redis::pipe()
+                .get(&key)
+                .get(self.session_epoch_key())
+                .query::<(Option<Vec<u8>>, Option<u64>)>(&mut *conn)
+        }) {
+            Ok(reply) => reply,
+            Err(err) => {
+                fail(&mut span, &err);
+                return Err(deadline.status_or(Status::internal(err.to_string())));
+            }
+        };
+        let session = match value.as_deref().map(session_format::decode) {
+            Some(Ok(session)) => session,
+            Some(Err(DecodeError::Malformed)) => {
+                return Err(fail(&mut span, Status::internal("malformed session data")));
+            }
+            Some(Err(unreadable)) => {
+                return Err(fail(
+                    &mut span,
+                    self.drop_unreadable(&mut conn, &key, &unreadable),
+                ));
+            }
+            None => {
+                return Err(fail(&mut span, Status::unauthenticated("unknown token")));
+            }
+        };
+        if let Some((_, err)) = self.session_failure(
+            &mut conn,
+            &key,
+            &session,
+            client_ip,
+            "",
+            revoked_before.unwrap_or_default(),
+        ) {
+            return Err(fail(&mut span, err));
+        }
+
+        if session.user.is_empty() {
+            return Err(fail(
+                &mut span,
+                Status::failed_precondition("guest sessions belong to no user"),
+            ));
+        }
+
+        let index = self.user_sessions_key(&session.user);
+        let count = match self.timed_redis("count_sessions", TOKEN_KEYS, || {
+            COUNT_SESSIONS.key(&index).invoke::<u64>(&mut *conn)
+        }) {
+            Ok(count) => count,
+            Err(err) => {
+                fail(&mut span, &err);
+                return Err(deadline.status_or(Status::internal(err.to_string())));
+            }
+        };
+        span.set_attribute(KeyValue::new("auth.session_count", count as i64));
+
+        Ok(Response::new(SessionCountResponse { count }))
+    }
+
+    /// Issues a token for a subset of the scopes of a valid parent token. The
+    /// new token stores the parent's key and is refused once that is gone, so
+    /// revoking, expiring or consuming the parent revokes it too.
+    async fn handle_token_exchange(
+        &self,
+        request: Request<TokenExchangeRequest>,
+        mut span: global::BoxedSpan,
+    ) -> Result<Response<LoginResponse>, Status> {
+        let deadline = self.redis_deadline(request.metadata());
+        let _permit = acquire_slot(&self.login_limit, &mut span)?;
+
+        let client_ip = self.client_ip(&request);
+        let mut req = request.into_inner();
+        if req.token.is_empty() {
+            return Err(fail(&mut span, Status::invalid_argument("token required")));
+        }
+        req.scopes = match distinct_scopes(std::mem::take(&mut req.scopes)) {
+            Ok(scopes) => scopes,
+            Err(err) => {
+                return Err(fail(&mut span, err));
+            }
+        };
+
+        let mut conn = self.redis_conn(&self.pool, deadline, &mut span)?;
+
+        let key = self.token_key(&req.token);
+        let (value, remaining, revoked_before) =
+            match self.timed_redis("get_session", TOKEN_KEYS, || {
+                redis::pipe()
+                    .get(&key)
+                    .ttl(&key)
+                    .get(self.session_epoch_key())
+                    .query::<(Option<Vec<u8>>, i64, Option<u64>)>(&mut *conn)
+            }) {
+                Ok(reply) => reply,
+                Err(err) => {
+                    fail(&mut span, &err);
+                    return Err(deadline.status_or(Status::internal(err.to_string())));
+                }
+            };
+        let parent = match value.as_deref().map(session_format::decode) {
+            Some(Ok(session)) => session,
+            Some(Err(DecodeError::Malformed)) => {
+                return Err(fail(&mut span, Status::internal("malformed session data")));
+            }
+            Some(Err(unreadable)) => {
+                return Err(fail(
+                    &mut span,
+                    self.drop_unreadable(&mut conn, &key, &unreadable),
+                ));
+            }
+            None => {
+                return Err(fail(&mut span, Status::unauthenticated("unknown token")));
+            }
+        };
+        // checked before validating, which would consume a one-time token
+        let refused = if parent.one_time {
+            Some("one-time tokens can't be exchanged")
+        } else if !parent.parent.is_empty() {
+            // its own parent's revocation would only reach it on validation
+            Some("exchanged tokens can't be exchanged again")
+        } else {
+            None
+        };
+        if let Some(refused) = refused {
+            return Err(fail(&mut span, Status::failed_precondition(refused)));
+        }
+        if let Some((_, err)) = self.session_failure(
+            &mut conn,
+            &key,
+            &parent,
+            client_ip,
+            "",
+            revoked_before.unwrap_or_default(),
+        ) {
+            return Err(fail(&mut span, err));
+        }
+        if let Some(scope) = req
+            .scopes
+            .iter()
+            .find(|scope| !parent.scopes.contains(scope))
+        {
+            return Err(fail(
+                &mut span,
+                Status::permission_denied(format!("token lacks scope {}", scope)),
+            ));
+        }
+
+        let now = self.clock.now();
+        let mut ttl = match req.ttl_seconds {
+            0 => self.exchange_ttl,
+            requested => Duration::from_secs(requested).min(self.exchange_ttl),
+        };
+        ttl = ttl.min(Duration::from_secs(
+            u64::try_from(remaining).unwrap_or_default(),
+        ));
+        if let Some(max) = self.max_session_lifetime {
+            let lifetime_left = (parent.login_at + max.as_secs()).saturating_sub(unix_time(now));
+            ttl = ttl.min(Duration::from_secs(lifetime_left));
+        }
+        if ttl.is_zero() {
+            return Err(fail(
+                &mut span,
+                Status::failed_precondition("token is about to expire"),
+            ));
+        }
+
+        let session = Session {
+            session_id: self.session_id.clone(),
+            login_at: unix_time(now),
+            login_micros: subsec_micros(now),
+            not_before: 0,
+            // lives no longer than the parent, which may be extended instead
+            ttl: 0,
+            client_ip: if self.bind_sessions_to_ip {
+                client_ip.map(ipv6)
+            } else {
+                None
+            },
+            one_time: false,
+            scopes: req.scopes,
+            class: parent.class,
+            user: parent.user,
+            parent: key,
+        };
+        // kept out of the class index, so the class's session limit neither
+        // counts nor evicts them
+        let index = if session.user.is_empty() {
+            self.guest_sessions_key()
+        } else {
+            self.user_sessions_key(&session.user)
+        };
+        let token = self.store_session(
+            &mut conn,
+            &session,
+            ttl,
+            [index.clone(), index],
+            0,
+            deadline,
+            &mut span,
+        )?;
+        span.set_attribute(KeyValue::new("auth.exchanged_ttl", ttl.as_secs() as i64));
+
+        Ok(Response::new(LoginResponse {
+            token,
+            expire_at: Some(expiry(now, ttl)),
+            session_id: self.debug_session_id(req.client_version),
+            issued_at: Some(Timestamp::from(now)),
+            not_before: None,
+        }))
+    }
+
+    async fn handle_introspect(
+        &self,
+        request: Request<IntrospectRequest>,
+        mut span: global::BoxedSpan,
+    ) -> Result<Response<IntrospectResponse>, Status> {
+        let deadline = self.redis_deadline(request.metadata());
+        let _permit = acquire_slot(&self.validate_limit, &mut span)?;
+
+        let client_ip = self.client_ip(&request);
+        let key = self.token_key(&request.into_inner().token);
+
+        let mut conn = self.redis_conn(&self.pool, deadline, &mut span)?;
+
+        let (value, ttl, revoked_before): (Option<Vec<u8>>, i64, Option<u64>) = match self
+            .timed_redis("introspect_session", TOKEN_KEYS, || {
+                redis::pipe()
+                    .get(&key)
+                    .ttl(&key)
+                    .get(self.session_epoch_key())
+                    .query(&mut *conn)
+            }) {
+            Ok(reply) => reply,
+            Err(err) => {
+                fail(&mut span, &err);
+                return Err(deadline.status_or(Status::internal(err.to_string())));
+            }
+        };
+
+        // a token is active if it would pass `Validate`, which introspecting a
+        // one-time token must not consume
+        let session = value
+            .as_deref()
+            .and_then(|value| session_format::decode(value).ok())
+            .filter(|session| {
+                self.session_check(
+                    &mut conn,
+                    &key,
+                    session,
+                    client_ip,
+                    "",
+                    revoked_before.unwrap_or_default(),
+                )
+                .is_none()
+            });
+
+        let response = match session {
+            Some(session) => IntrospectResponse {
+                active: true,
+                username: session.user,
+                // a negative TTL means the key has no expiry
+                exp: if ttl >= 0 {
+                    self.unix_now() as i64 + ttl
+                } else {
+                    0
+                },
+                iat: session.login_at as i64,
+            },
+            None => IntrospectResponse::default(),
+        };
+
+        span.set_attribute(KeyValue::new("active", response.active));
+
+        Ok(Response::new(response))
+    }
+
+    /// Lists the sessions in the index of a user whose token still exists.
+    async fn handle_list_sessions(
+        &self,
+        request: Request<ListSessionsRequest>,
+    ) -> Result<Response<ListSessionsResponse>, Status> {
+        let mut span = self.trace.start_span("list_sessions", &request);
+        let deadline = self.redis_deadline(request.metadata());
+
+        let user = request.into_inner().user;
+        if user.is_empty() {
+            return Err(fail(&mut span, Status::invalid_argument("user required")));
+        }
+
+        let mut conn = self.redis_conn(&self.pool, deadline, &mut span)?;
+
+        let reply = self.timed_redis("list_sessions", TOKEN_KEYS, || {
+            let keys: Vec<String> = conn.zrange(self.user_sessions_key(&user), 0, -1)?;
+            let mut lookup = redis::pipe();
+            lookup.get(self.session_epoch_key());
+            for key in &keys {
+                lookup.get(key).ttl(key);
+            }
+            // the epoch, then the value and TTL of each token
+            let reply: Vec<redis::Value> = lookup.query(&mut *conn)?;
+            let revoked_before: Option<u64> = redis::from_redis_value(&reply[0])?;
+            let sessions: Vec<(Option<Vec<u8>>, i64)> =
+                redis::FromRedisValue::from_redis_values(&reply[1..])?;
+            Ok::<_, redis::RedisError>((keys, revoked_before, sessions))
+        });
+        let (keys, revoked_before, sessions) = match reply {
+            Ok(reply) => reply,
+            Err(err) => {
+                fail(&mut span, &err);
+                return Err(deadline.status_or(Status::internal(err.to_string())));
+            }
+        };
+
+        let sessions = keys
+            .iter()
+            .zip(sessions)
+            .filter_map(|(key, (value, ttl))| {
+                // gone since the index was read, or written by another release
+                let session = session_format::decode(&value?).ok()?;
+                Some(self.session_info(key, &session, ttl, revoked_before.unwrap_or_default()))
+            })
+            .collect::<Vec<_>>();
+        span.set_attribute(KeyValue::new("sessions", sessions.len() as i64));
+
+        Ok(Response::new(ListSessionsResponse { sessions }))
+    }
+
+    /// Revokes every session issued so far, see `revoke_all_sessions`.
+    async fn handle_revoke_all_sessions(
+        &self,
+        request: Request<RevokeAllSessionsRequest>,
+    ) -> Result<Response<RevokeAllSessionsResponse>, Status> {
+        let mut span = self.trace.start_span("revoke_all_sessions", &request);
+        let (pool, key, now) = (
+            self.pool.clone(),
+            self.session_epoch_key(),
+            self.clock.now(),
+        );
+        let revoked = tokio::task::spawn_blocking(move || revoke_all_sessions(&pool, &key, now))
+            .await
+            .map_err(|err| err.to_string())
+            .and_then(|revoked| revoked.map_err(|err| err.to_string()));
+        let revoked_before = match revoked {
+            Ok(epoch) => epoch,
+            Err(err) => {
+                return Err(fail(&mut span, Status::internal(err)));
+            }
+        };
+        self.events.emit(Event::SessionsRevoked {
+            user: None,
+            reason: "revoke_all",
+        });
+
+        Ok(Response::new(RevokeAllSessionsResponse { revoked_before }))
+    }
+
+    /// Describes the session of a token. Unlike `Introspect` it reports
+    /// sessions that aren't valid too, and changes nothing.
+    async fn handle_inspect_token(
+        &self,
+        request: Request<InspectTokenRequest>,
+    ) -> Result<Response<InspectTokenResponse>, Status> {
+        let mut span = self.trace.start_span("inspect_token", &request);
+        let deadline = self.redis_deadline(request.metadata());
+
+        let token = request.into_inner().token;
+        if token.is_empty() {
+            return Err(fail(&mut span, Status::invalid_argument("token required")));
+        }
+        let key = self.token_key(&token);
+
+        let mut conn = self.redis_conn(&self.pool, deadline, &mut span)?;
+
+        let (value, ttl, revoked_before): (Option<Vec<u8>>, i64, Option<u64>) = match self
+            .timed_redis("inspect_session", TOKEN_KEYS, || {
+                redis::pipe()
+                    .get(&key)
+                    .ttl(&key)
+                    .get(self.session_epoch_key())
+                    .query(&mut *conn)
+            }) {
+            Ok(reply) => reply,
+            Err(err) => {
+                fail(&mut span, &err);
+                return Err(deadline.status_or(Status::internal(err.to_string())));
+            }
+        };
+        let session = match value.as_deref().map(session_format::decode) {
+            Some(Ok(session)) => Some(session),
+            Some(Err(err)) => {
+                return Err(fail(&mut span, Status::internal(err.to_string())));
+            }
+            None => None,
+        };
+
+        Ok(Response::new(InspectTokenResponse {
+            session: session.map(|session| {
+                self.session_info(&key, &session, ttl, revoked_before.unwrap_or_default())
+            }),
+        }))
+    }
+
+    /// The operator view of the session stored under `key` with `ttl` seconds
+    /// left, negative if it has no expiry.
+    fn session_info(
+        &self,
+        key: &str,
+        session: &Session,
+        ttl: i64,
+        revoked_before: u64,
+    ) -> SessionInfo {
+        let timestamp = |seconds: u64| Timestamp {
+            seconds: seconds as i64,
+            nanos: 0,
+        };
+        let now = self.unix_now();
+        SessionInfo {
+            token_sha256: match key.strip_prefix(&self.token_key_prefix()) {
+                Some(hashed) if self.hash_tokens => hashed.to_owned(),
+                Some(token) => format!("{:x}", Sha256::digest(token.as_bytes())),
+                None => format!("{:x}", Sha256::digest(key.as_bytes())),
+            },
+            user: session.user.clone(),
+            class: session.class.clone(),
+            scopes: session.scopes.clone(),
+            issued_at: Some(timestamp(session.login_at)),
+            expire_at: (ttl >= 0).then(|| timestamp(now + ttl as u64)),
+            not_before: (session.not_before != 0).then(|| timestamp(session.not_before)),
+            one_time: session.one_time,
+            client_ip: session.client_ip.map_or_else(String::new, |ip| {
+                ip.to_ipv4_mapped()
+                    .map_or_else(|| ip.to_string(), |ip| ip.to_string())
+            }),
+            exchanged: !session.parent.is_empty(),
+            active: session.session_id == self.session_id
+                && session.login_time_micros() > revoked_before
+                && session.not_before <= now
+                && !self.session_expired(session),
+        }
+    }
+}
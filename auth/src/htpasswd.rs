@@ -0,0 +1,255 @@
+//! Users imported from an Apache htpasswd file.
+//!
+//! Supported schemes are bcrypt (`$2y$`, `$2a$`, `$2b$`), Apache MD5 (`$apr1$`)
+//! and SHA-1 (`{SHA}`). Any other entry fails the load, so a file that can't be
+//! fully served is noticed at startup rather than at login. A `!` in front of
+//! the hash disables the account while keeping its password.
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// Password hash of one htpasswd entry.
+#[derive(Clone)]
+pub enum Hash {
+    Bcrypt(String),
+    Apr1 { salt: String, checksum: String },
+    Sha1([u8; 20]),
+}
+
+/// One user of the file.
+#[derive(Clone)]
+pub struct Entry {
+    pub hash: Hash,
+    pub disabled: bool,
+}
+
+/// A line of the file that can't be used.
+#[derive(Debug)]
+pub struct Error {
+    line: usize,
+    reason: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parses htpasswd `content` into entries by username. Blank lines and `#`
+/// comments are skipped.
+pub fn parse(content: &str) -> Result<HashMap<String, Entry>, Error> {
+    let mut users = HashMap::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |reason: &str| Error {
+            line: index + 1,
+            reason: reason.to_owned(),
+        };
+        let (user, hash) = line
+            .split_once(':')
+            .ok_or_else(|| error("expected <user>:<hash>"))?;
+        let (hash, disabled) = match hash.strip_prefix('!') {
+            Some(hash) => (hash, true),
+            None => (hash, false),
+        };
+        let hash = Hash::parse(hash).ok_or_else(|| error("unsupported hash scheme"))?;
+        users.insert(user.to_owned(), Entry { hash, disabled });
+    }
+    Ok(users)
+}
+
+impl Hash {
+    fn parse(hash: &str) -> Option<Self> {
+        if ["$2y$", "$2a$", "$2b$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            return Some(Hash::Bcrypt(hash.to_owned()));
+        }
+        if let Some(rest) = hash.strip_prefix("$apr1$") {
+            let (salt, checksum) = rest.split_once('$')?;
+            if salt.len() > 8 || checksum.len() != 22 {
+                return None;
+            }
+            return Some(Hash::Apr1 {
+                salt: salt.to_owned(),
+                checksum: checksum.to_owned(),
+            });
+        }
+        if let Some(digest) = hash.strip_prefix("{SHA}") {
+            return base64::decode(digest).ok()?.try_into().ok().map(Hash::Sha1);
+        }
+        None
+    }
+
+    /// Why the hash is too cheap to withstand offline cracking, if it is: bcrypt
+    /// below `min_bcrypt_cost`, or one of the fast `$apr1$` and `{SHA}` schemes.
+    pub fn weakness(&self, min_bcrypt_cost: u32) -> Option<String> {
+        match self {
+            Hash::Bcrypt(hash) => {
+                let cost: u32 = hash.get(4..6)?.parse().ok()?;
+                (cost < min_bcrypt_cost)
+                    .then(|| format!("bcrypt cost {} is below {}", cost, min_bcrypt_cost))
+            }
+            Hash::Apr1 { .. } => Some("$apr1$ is a fast hash".to_owned()),
+            Hash::Sha1(_) => Some("{SHA} is an unsalted fast hash".to_owned()),
+        }
+    }
+
+    /// Checks `password` against the hash. Bcrypt is slow by design, so callers
+    /// should not run this on an async worker thread.
+    pub fn verify(&self, password: &str) -> bool {
+        match self {
+            Hash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Hash::Apr1 { salt, checksum } => crate::constant_time_eq(
+                apr1(password.as_bytes(), salt.as_bytes()).as_bytes(),
+                checksum.as_bytes(),
+            ),
+            Hash::Sha1(digest) => {
+                crate::constant_time_eq(&sha1_smol::Sha1::from(password).digest().bytes(), digest)
+            }
+        }
+    }
+}
+
+const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Apache's variant of the md5-crypt algorithm, returning the encoded checksum.
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+    let mut alternate = md5::Context::new();
+    alternate.consume(password);
+    alternate.consume(salt);
+    alternate.consume(password);
+    let alternate = alternate.compute();
+
+    let mut context = md5::Context::new();
+    context.consume(password);
+    context.consume(b"$apr1$");
+    context.consume(salt);
+    for chunk in password.chunks(16) {
+        context.consume(&alternate[..chunk.len()]);
+    }
+    let mut length = password.len();
+    while length > 0 {
+        if length & 1 == 1 {
+            context.consume([0u8]);
+        } else {
+            context.consume(&password[..1]);
+        }
+        length >>= 1;
+    }
+    let mut digest = context.compute();
+
+    for round in 0..1000 {
+        let mut context = md5::Context::new();
+        if round & 1 == 1 {
+            context.consume(password);
+        } else {
+            context.consume(&digest[..]);
+        }
+        if round % 3 != 0 {
+            context.consume(salt);
+        }
+        if round % 7 != 0 {
+            context.consume(password);
+        }
+        if round & 1 == 1 {
+            context.consume(&digest[..]);
+        } else {
+            context.consume(password);
+        }
+        digest = context.compute();
+    }
+
+    let mut encoded = String::with_capacity(22);
+    let mut push = |mut value: u32, chars: usize| {
+        for _ in 0..chars {
+            encoded.push(ITOA64[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        push(
+            (digest[a] as u32) << 16 | (digest[b] as u32) << 8 | digest[c] as u32,
+            4,
+        );
+    }
+    push(digest[11] as u32, 2);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `openssl passwd -apr1 -salt 3f2a9c41 hunter2` and the base64 SHA-1 of `hunter2`
+    const APR1: &str = "$apr1$3f2a9c41$9cA1p75GLoJECoWnK1nWM.";
+    const SHA: &str = "{SHA}87u9ZqY9S/F0eUBXjsPQEDUw4h0=";
+    const BCRYPT: &str = "$2y$05$LIeb5VB5ZLXRDzy3wKX8.uQXbIVOex2BekqPax4hU6U/yS3SbcIKm";
+
+    #[test]
+    fn entries_of_every_scheme_parse() {
+        let content = format!(
+            "# users\nalice:{}\n\nbob:!{}\ncarol:{}\n",
+            APR1, SHA, BCRYPT
+        );
+
+        let users = parse(&content).unwrap();
+
+        assert_eq!(users.len(), 3);
+        assert!(matches!(&users["alice"].hash, Hash::Apr1 { salt, .. } if salt == "3f2a9c41"));
+        assert!(!users["alice"].disabled);
+        assert!(matches!(users["bob"].hash, Hash::Sha1(_)));
+        assert!(users["bob"].disabled);
+        assert!(matches!(&users["carol"].hash, Hash::Bcrypt(hash) if hash == BCRYPT));
+    }
+
+    #[test]
+    fn unusable_line_fails_the_parse() {
+        for (content, line, reason) in [
+            ("alice:{SHA}87u9\nbob", 1, "unsupported hash scheme"),
+            (
+                &format!("alice:{}\nbob", APR1) as &str,
+                2,
+                "expected <user>:<hash>",
+            ),
+            ("\nalice:$6$salt$crypt", 2, "unsupported hash scheme"),
+            ("alice:$apr1$3f2a9c41$short", 1, "unsupported hash scheme"),
+        ] {
+            let err = parse(content).err().unwrap();
+            assert_eq!(
+                (err.line, err.reason.as_str()),
+                (line, reason),
+                "{}",
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn fast_hashes_verify() {
+        let users = parse(&format!("alice:{}\nbob:{}", APR1, SHA)).unwrap();
+        for user in ["alice", "bob"] {
+            assert!(users[user].hash.verify("hunter2"), "{}", user);
+            assert!(!users[user].hash.verify("hunter3"), "{}", user);
+        }
+    }
+
+    #[test]
+    fn cheap_hashes_are_weak() {
+        let bcrypt = Hash::parse(BCRYPT).unwrap();
+        assert_eq!(
+            bcrypt.weakness(10).as_deref(),
+            Some("bcrypt cost 5 is below 10")
+        );
+        assert_eq!(bcrypt.weakness(5), None);
+        assert!(Hash::parse(APR1).unwrap().weakness(0).is_some());
+        assert!(Hash::parse(SHA).unwrap().weakness(0).is_some());
+    }
+}
@@ -0,0 +1,2821 @@
+//! Session service issuing and validating tokens stored in redis.
+//!
+//! The `auth` binary runs it as configured by the environment, see `run`. To
+//! mount it into another tonic server, build an `AuthService` and add its
+//! `into_service`; settings the builder doesn't take come from its `config`,
+//! or without one from the environment variables described in the README.
+//!
+//! ```no_run
+//! # async fn embed() -> Result<(), Box<dyn std::error::Error>> {
+//! let manager = auth::RedisManager::new("redis://127.0.0.1/")?;
+//! let pool = r2d2::Pool::builder().build(manager)?;
+//! let auth = auth::AuthService::builder(pool)
+//!     .ttl(std::time::Duration::from_secs(3600))
+//!     .namespace("my-app")
+//!     .build();
+//! tonic::transport::Server::builder()
+//!     .add_service(auth.into_service()?)
+//!     .serve("127.0.0.1:50051".parse()?)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+// Handlers and helpers return `tonic::Status` as their error type by design.
+#![allow(clippy::result_large_err)]
+
+use auth::auth_admin_server::AuthAdminServer;
+use auth::auth_server::AuthServer;
+use auth::{
+    InspectTokenRequest, IntrospectRequest, ListSessionsRequest, LoginAnonymousRequest,
+    LoginRequest, RevokeAllSessionsRequest, SessionCountRequest, TokenExchangeRequest,
+    ValidateBatchRequest, ValidateRequest, ValidateResponse, ValidationResult,
+};
+use breaker::Breaker;
+pub use clock::{Clock, ManualClock, SystemClock};
+use config::{Config, Secret};
+pub use events::{Event, EventSink, NoopEvents};
+pub use handlers::AdminService;
+pub use metrics::{Metrics, NoopMetrics, PrometheusMetrics};
+use opentelemetry::global;
+use opentelemetry::sdk::propagation::{BaggagePropagator, TextMapCompositePropagator};
+use opentelemetry::{
+    baggage::BaggageExt,
+    propagation::{Extractor, Injector},
+    trace::{Span, Tracer},
+    Context, KeyValue,
+};
+pub use pool::{RedisConnection, RedisManager};
+use prost_types::Timestamp;
+use r2d2_redis::{r2d2, redis, redis::Commands, redis::IntoConnectionInfo};
+use rand::Rng;
+pub use run::run;
+use scripts::{COUNT_SESSIONS, EXTEND_SESSION, MIGRATE_TOKEN_KEY, RAISE_SESSION_EPOCH};
+use session_format::DecodeError;
+pub use session_format::SessionFormat;
+use sha2::{Digest, Sha256};
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tonic::codec::CompressionEncoding;
+use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+mod access_log;
+mod admin;
+pub mod audit;
+mod breaker;
+pub mod capture;
+mod clock;
+pub mod config;
+mod connections;
+pub mod events;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+mod handlers;
+mod htpasswd;
+mod metrics;
+mod pool;
+mod run;
+mod scripts;
+mod session_format;
+#[cfg(feature = "tls")]
+mod tls;
+mod toggles;
+
+const APPLICATION_ID: &str = "auth";
+
+pub mod auth {
+    tonic::include_proto!("auth");
+}
+
+struct User<'a> {
+    name: &'a str,
+    password: &'a str,
+}
+
+const USERS: &[User] = &[
+    User {
+        name: "root",
+        password: "admin",
+    },
+    User {
+        name: "user",
+        password: "user",
+    },
+];
+
+/// Name of the user directory backed by `PASSWORDS`, reported as `auth.backend`.
+const STATIC_BACKEND: &str = "static";
+
+/// Backend name of the break-glass login, see `EmergencyAdmin`.
+const EMERGENCY_BACKEND: &str = "emergency";
+
+/// Backend name of the users loaded from `HTPASSWD_FILE`.
+const HTPASSWD_BACKEND: &str = "htpasswd";
+
+/// Backend name of guest sessions from `LoginAnonymous`.
+const GUEST_BACKEND: &str = "guest";
+
+/// The only scope of guest sessions.
+const GUEST_SCOPE: &str = "guest";
+
+/// The user directory and the account policies of `Config`: built-in users
+/// unless `DISABLE_BUILTIN_USERS`, users of `HTPASSWD_FILE`, banned and disabled
+/// accounts and the scopes each user may request.
+struct Users {
+    /// Built-in users, which take precedence over htpasswd entries of the same
+    /// name.
+    passwords: HashMap<String, String>,
+    htpasswd: HashMap<String, htpasswd::Entry>,
+    /// Usernames nobody may log in as, compared after normalization.
+    banned: Vec<String>,
+    /// Usernames exempt from the ban list, so e.g. `root` keeps working if `root` is banned.
+    banned_exempt: Vec<String>,
+    /// Accounts suspended by `DISABLED_USERS`, compared after normalization.
+    disabled: Vec<String>,
+    /// Scopes each user may request at login, from `USER_SCOPES` written as
+    /// `user=scope,scope;user=scope`. Users without an entry can't be granted any.
+    /// Scopes containing `:` would break the session encoding and are ignored.
+    scopes: HashMap<String, Vec<String>>,
+}
+
+impl Users {
+    /// Panics if `HTPASSWD_FILE` can't be read or has weak hashes, so a broken
+    /// user directory fails the start.
+    fn from_config(config: &Config) -> Self {
+        let mut passwords = HashMap::new();
+        // built-in users are then indistinguishable from unknown ones
+        if config.disable_builtin_users {
+            println!("built-in users are disabled");
+        } else {
+            for user in USERS {
+                passwords.insert(user.name.to_owned(), user.password.to_owned());
+            }
+        }
+        Users {
+            passwords,
+            htpasswd: load_htpasswd(config),
+            banned: username_list(config.banned_usernames.as_deref().unwrap_or_default()),
+            banned_exempt: username_list(
+                config.banned_usernames_exempt.as_deref().unwrap_or("root"),
+            ),
+            disabled: username_list(config.disabled_users.as_deref().unwrap_or_default()),
+            scopes: config
+                .user_scopes
+                .as_deref()
+                .unwrap_or_default()
+                .split(';')
+                .filter_map(|entry| entry.split_once('='))
+                .map(|(user, scopes)| {
+                    let scopes = scopes
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|scope| !scope.is_empty() && !scope.contains(':'))
+                        .map(str::to_owned)
+                        .collect();
+                    (user.trim().to_owned(), scopes)
+                })
+                .collect(),
+        }
+    }
+
+    fn known(&self, name: &str) -> bool {
+        self.passwords.contains_key(name) || self.htpasswd.contains_key(name)
+    }
+
+    fn banned(&self, name: &str) -> bool {
+        let name = normalize_username(name);
+        self.banned.contains(&name) && !self.banned_exempt.contains(&name)
+    }
+
+    /// Whether the account is suspended, by `DISABLED_USERS` or a `!` in front of
+    /// its htpasswd hash. Built-in users shadow htpasswd entries of the same name.
+    fn account_disabled(&self, name: &str) -> bool {
+        self.disabled.contains(&normalize_username(name))
+            || (!self.passwords.contains_key(name)
+                && self.htpasswd.get(name).is_some_and(|e| e.disabled))
+    }
+
+    /// Known users whose accounts are disabled.
+    fn disabled_users(&self) -> Vec<String> {
+        self.passwords
+            .keys()
+            .chain(self.htpasswd.keys())
+            .filter(|name| self.account_disabled(name))
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Users of the htpasswd file named by `HTPASSWD_FILE`, none when unset.
+fn load_htpasswd(config: &Config) -> HashMap<String, htpasswd::Entry> {
+    let path = match config.htpasswd_file.as_deref() {
+        Some(path) => path,
+        None => return HashMap::new(),
+    };
+    let content = std::fs::read_to_string(path)
+        .unwrap_or_else(|err| panic!("failed to read HTPASSWD_FILE {}: {}", path, err));
+    let users = htpasswd::parse(&content)
+        .unwrap_or_else(|err| panic!("failed to load HTPASSWD_FILE {}: {}", path, err));
+    check_hash_strength(&users, config);
+    println!("loaded {} users from {}", users.len(), path);
+    users
+}
+
+/// Refuses htpasswd hashes cheaper than `MIN_BCRYPT_COST` (10 by default)
+/// allows, unless `ALLOW_WEAK_HASHING` accepts them with a warning.
+fn check_hash_strength(users: &HashMap<String, htpasswd::Entry>, config: &Config) {
+    let min_cost = config.min_bcrypt_cost.unwrap_or(10);
+    let mut weak: Vec<String> = users
+        .iter()
+        .filter_map(|(user, entry)| {
+            entry
+                .hash
+                .weakness(min_cost)
+                .map(|reason| format!("{} ({})", user, reason))
+        })
+        .collect();
+    if weak.is_empty() {
+        return;
+    }
+    weak.sort();
+    if !config.allow_weak_hashing {
+        panic!(
+            "HTPASSWD_FILE has weak password hashes, rehash them or set ALLOW_WEAK_HASHING: {}",
+            weak.join(", ")
+        );
+    }
+    println!(
+        "WARNING: accepting weak password hashes (ALLOW_WEAK_HASHING): {}",
+        weak.join(", ")
+    );
+}
+
+/// How handler spans join the caller's trace.
+struct TraceContext {
+    /// Whether inbound trace context is honored at all. Untrusted edges can turn
+    /// it off so every request starts a fresh trace.
+    extraction: bool,
+    /// Metadata keys honored for trace context extraction, all of them when unset.
+    /// A trailing `*` matches a prefix, e.g. `uberctx-*`.
+    keys: Option<Vec<String>>,
+    /// Baggage entries copied onto handler spans as `baggage.<key>` attributes.
+    baggage_attributes: Vec<String>,
+}
+
+impl TraceContext {
+    fn from_config(config: &Config) -> Self {
+        TraceContext {
+            extraction: config.trace_context_extraction,
+            keys: config.trace_context_keys.as_deref().map(|keys| {
+                keys.split(',')
+                    .map(|key| key.trim().to_ascii_lowercase())
+                    .filter(|key| !key.is_empty())
+                    .collect()
+            }),
+            baggage_attributes: config
+                .baggage_span_attributes
+                .as_deref()
+                .unwrap_or_default()
+                .split(',')
+                .map(|key| key.trim().to_owned())
+                .filter(|key| !key.is_empty())
+                .collect(),
+        }
+    }
+
+    fn key_allowed(&self, key: &str) -> bool {
+        match &self.keys {
+            Some(allowed) => allowed
+                .iter()
+                .any(|allowed| match allowed.strip_suffix('*') {
+                    Some(prefix) => key.starts_with(prefix),
+                    None => key == allowed,
+                }),
+            None => true,
+        }
+    }
+
+    /// Extracts the caller's trace context from request metadata.
+    fn parent_context(&self, metadata: &tonic::metadata::MetadataMap) -> Context {
+        if !self.extraction {
+            return Context::new();
+        }
+        global::get_text_map_propagator(|prop| prop.extract(&MetadataMap(metadata, self)))
+    }
+
+    /// Starts the span of a call as a child of the caller's trace context.
+    fn start_span<T: RequestSummary>(
+        &self,
+        name: &'static str,
+        request: &Request<T>,
+    ) -> global::BoxedSpan {
+        let parent_cx = self.parent_context(request.metadata());
+        let mut span = global::tracer(APPLICATION_ID).start_with_context(name, &parent_cx);
+        span.set_attribute(KeyValue::new("request", request_summary(name, request)));
+        self.record_baggage(&parent_cx, &mut span);
+        span
+    }
+
+    fn record_baggage(&self, cx: &Context, span: &mut impl Span) {
+        let baggage = cx.baggage();
+        for key in &self.baggage_attributes {
+            if let Some(value) = baggage.get(key.clone()) {
+                span.set_attribute(KeyValue::new(
+                    format!("baggage.{}", key),
+                    value.as_str().into_owned(),
+                ));
+            }
+        }
+    }
+}
+
+/// Token class of logins that don't name one.
+const DEFAULT_TOKEN_CLASS: &str = "web";
+
+/// Lifetime and usage rules shared by the tokens of one class.
+#[derive(Clone)]
+struct ClassPolicy {
+    ttl: Duration,
+    /// Consumed by the first successful validation, like `LoginRequest.one_time`.
+    single_use: bool,
+    /// Sessions of the class a user may hold at once (0 is unlimited); logging in
+    /// beyond that evicts the oldest.
+    max_sessions: u64,
+}
+
+/// Token classes configured by `TOKEN_CLASSES` in `spec`, written as
+/// `name=option,option;name=option` with the options `ttl:<seconds>`,
+/// `max_sessions:<count>` and `single_use`. Unset options keep the values of
+/// `default`, which is also the `web` class unless that is listed. A malformed
+/// entry panics, so a typo is noticed at startup rather than at login.
+fn token_classes(spec: &str, default: ClassPolicy) -> HashMap<String, ClassPolicy> {
+    let mut classes = HashMap::new();
+    classes.insert(DEFAULT_TOKEN_CLASS.to_owned(), default.clone());
+
+    for entry in spec.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let invalid =
+            |reason: &str| -> ! { panic!("invalid TOKEN_CLASSES entry {:?}: {}", entry, reason) };
+        let (name, options) = entry.split_once('=').unwrap_or((entry, ""));
+        let name = name.trim();
+        // the name is stored in the session encoding
+        if name.is_empty() || name.contains(':') {
+            invalid("class names must be non-empty and not contain ':'");
+        }
+        let mut class = default.clone();
+        for option in options.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            match option.split_once(':') {
+                None if option == "single_use" => class.single_use = true,
+                Some(("ttl", secs)) => match secs.parse() {
+                    Ok(secs) => {
+                        class.ttl = bounded_ttl("TOKEN_CLASSES ttl", Duration::from_secs(secs))
+                    }
+                    Err(_) => invalid("ttl must be a number of seconds"),
+                },
+                Some(("max_sessions", max)) => match max.parse() {
+                    Ok(max) => class.max_sessions = max,
+                    Err(_) => invalid("max_sessions must be a number"),
+                },
+                _ => invalid(&format!("unknown option {}", option)),
+            }
+        }
+        classes.insert(name.to_owned(), class);
+    }
+    classes
+}
+
+/// Canonical form usernames are compared in: surrounding whitespace and case are ignored.
+fn normalize_username(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+/// The comma separated usernames in `names`, normalized.
+fn username_list(names: &str) -> Vec<String> {
+    names
+        .split(',')
+        .map(normalize_username)
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Request metadata as the propagator reads it, limited to the keys allowed for
+/// trace context extraction.
+struct MetadataMap<'a>(&'a tonic::metadata::MetadataMap, &'a TraceContext);
+
+impl<'a> Extractor for MetadataMap<'a> {
+    /// Get a value for a key from the MetadataMap.  If the value can't be converted to &str
+    /// or the key isn't allowed for trace context extraction, returns None
+    fn get(&self, key: &str) -> Option<&str> {
+        if !self.1.key_allowed(key) {
+            return None;
+        }
+        self.0.get(key).and_then(|metadata| metadata.to_str().ok())
+    }
+
+    /// Collect the keys allowed for trace context extraction from the MetadataMap.
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .map(|key| match key {
+                tonic::metadata::KeyRef::Ascii(v) => v.as_str(),
+                tonic::metadata::KeyRef::Binary(v) => v.as_str(),
+            })
+            .filter(|key| self.1.key_allowed(key))
+            .collect::<Vec<_>>()
+    }
+}
+
+struct MetadataMapMut<'a>(&'a mut tonic::metadata::MetadataMap);
+
+impl<'a> Injector for MetadataMapMut<'a> {
+    /// Set a key and value in the MetadataMap.  Does nothing if the key or value are not valid inputs
+    fn set(&mut self, key: &str, value: String) {
+        if let Ok(key) = tonic::metadata::MetadataKey::from_bytes(key.as_bytes()) {
+            if let Ok(value) = tonic::metadata::MetadataValue::try_from(&value) {
+                self.0.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Injects the given context, including its baggage, into the metadata of an outbound
+/// request to a downstream service.
+#[allow(dead_code)]
+fn inject_context<T>(cx: &Context, request: &mut Request<T>) {
+    global::get_text_map_propagator(|prop| {
+        prop.inject_context(cx, &mut MetadataMapMut(request.metadata_mut()))
+    });
+}
+
+/// Client protocol version that introduced the `session_id` response fields.
+const SESSION_ID_SINCE_VERSION: u32 = 1;
+
+/// Client protocol version that introduced `ValidateResponse.cacheable_for_seconds`.
+const CACHE_HINT_SINCE_VERSION: u32 = 2;
+
+/// Metadata key carrying a machine-readable reason for an error status.
+const ERROR_REASON_KEY: &str = "x-auth-error";
+
+/// The session outlived the configured maximum lifetime; the user must log in again.
+const SESSION_EXPIRED: &str = "SESSION_EXPIRED";
+const IP_MISMATCH: &str = "IP_MISMATCH";
+/// Login with the right password to a disabled account. Only recorded on the
+/// span and in the log; the client gets the same error as for a wrong password,
+/// so suspended accounts can't be told apart from failed guesses.
+const ACCOUNT_DISABLED: &str = "ACCOUNT_DISABLED";
+/// The token is gone but existed within the grace period, as opposed to a token
+/// that was never issued.
+const RECENTLY_EXPIRED: &str = "RECENTLY_EXPIRED";
+/// The token was issued with a not-before time that is still ahead; retrying
+/// later may succeed.
+const TOKEN_NOT_YET_VALID: &str = "TOKEN_NOT_YET_VALID";
+
+/// Response metadata key carrying the trace id of the call, see `TRACE_ID_IN_RESPONSE`.
+const TRACE_ID_KEY: &str = "x-trace-id";
+
+/// Kinds of keys redis operations touch, as logged by `timed_redis`.
+const TOKEN_KEYS: &str = "token";
+const LOCKOUT_KEYS: &str = "lockout";
+
+/// Most tokens a single `ValidateBatch` call may carry.
+const MAX_BATCH_TOKENS: usize = 100;
+
+/// Metadata key of the seconds until a refused login may be retried.
+const RETRY_AFTER_KEY: &str = "retry-after";
+
+/// Builds an error status that carries `reason` in its metadata, so clients can tell
+/// failures with the same gRPC code apart.
+fn status_with_reason(code: tonic::Code, message: &str, reason: &'static str) -> Status {
+    let mut metadata = tonic::metadata::MetadataMap::new();
+    metadata.insert(
+        ERROR_REASON_KEY,
+        tonic::metadata::MetadataValue::from_static(reason),
+    );
+    Status::with_metadata(code, message, metadata)
+}
+
+/// Latest instant a `google.protobuf.Timestamp` may hold: 9999-12-31T23:59:59Z.
+const MAX_TIMESTAMP_SECS: u64 = 253_402_300_799;
+
+/// Longest TTL a session is granted, 100 years. Longer settings are clamped
+/// once when the service is built, see `bounded_ttl`.
+const MAX_TTL: Duration = Duration::from_secs(100 * 365 * 24 * 3600);
+
+/// `ttl` from `setting`, clamped to `MAX_TTL` with a warning, so the expiry
+/// of a session always fits a timestamp.
+fn bounded_ttl(setting: &str, ttl: Duration) -> Duration {
+    if ttl <= MAX_TTL {
+        return ttl;
+    }
+    println!(
+        "WARNING: {} of {}s exceeds the longest TTL of {}s, clamping it",
+        setting,
+        ttl.as_secs(),
+        MAX_TTL.as_secs()
+    );
+    MAX_TTL
+}
+
+/// Computes the expiry timestamp of a session issued at `now`. TTLs are bounded
+/// by `bounded_ttl`, so only a clock past the year 9899 is clamped.
+fn expiry(now: SystemTime, ttl: Duration) -> Timestamp {
+    let max = UNIX_EPOCH + Duration::from_secs(MAX_TIMESTAMP_SECS);
+    Timestamp::from(now.checked_add(ttl).filter(|&at| at <= max).unwrap_or(max))
+}
+
+fn unix_now() -> u64 {
+    unix_time(SystemTime::now())
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn subsec_micros(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_micros())
+        .unwrap_or_default()
+}
+
+/// Unix time in microseconds, the unit of the session epoch.
+fn unix_micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| u64::try_from(d.as_micros()).unwrap_or(u64::MAX))
+        .unwrap_or_default()
+}
+
+/// Session data stored in redis under the token key.
+#[cfg_attr(any(test, feature = "fuzzing"), derive(Debug, PartialEq))]
+struct Session {
+    session_id: String,
+    /// Unix time of the login that started the session.
+    login_at: u64,
+    /// Microseconds into the second of `login_at`, so a revocation in the same
+    /// second tells sessions from before and after it apart.
+    login_micros: u32,
+    /// Unix time before which the token is refused, 0 if it was valid at once.
+    not_before: u64,
+    /// Seconds the token was granted at login, which extending it restores; 0
+    /// if it can't be extended.
+    ttl: u64,
+    /// Client address the session is bound to, see `BIND_SESSIONS_TO_IP`.
+    client_ip: Option<Ipv6Addr>,
+    /// Consumed by the first successful validation.
+    one_time: bool,
+    /// Scopes granted at login, see `USER_SCOPES`.
+    scopes: Vec<String>,
+    /// Name of the token class the session was issued as, see `TOKEN_CLASSES`.
+    class: String,
+    user: String,
+    /// Key of the token this one was exchanged from, empty for a login. The
+    /// token is only valid while that key exists.
+    parent: String,
+}
+
+impl Session {
+    /// Login time in microseconds, as compared with the session epoch.
+    fn login_time_micros(&self) -> u64 {
+        self.login_at
+            .saturating_mul(1_000_000)
+            .saturating_add(self.login_micros.into())
+    }
+}
+
+/// Tokens generated per login before giving up on finding an unused one.
+const TOKEN_ATTEMPTS: usize = 3;
+
+/// The `Auth` gRPC service. Build it with `AuthService::builder`.
+pub struct AuthService {
+    /// The settings the service was built from, for what is only read later.
+    config: Config,
+    session_id: String,
+    clock: Arc<dyn Clock>,
+    /// Tokens and everything indexing them.
+    pool: r2d2::Pool<RedisManager>,
+    /// Auxiliary data such as failed login counters; may be the same pool.
+    aux_pool: r2d2::Pool<RedisManager>,
+    metrics: Arc<dyn Metrics>,
+    events: Arc<dyn EventSink>,
+    ttl: Duration,
+    /// Bounds for TTLs requested at login.
+    min_ttl: Duration,
+    max_ttl: Duration,
+    /// Default TTL of remember me logins.
+    remember_me_ttl: Duration,
+    /// Share of its granted TTL below which a validation asking for it extends
+    /// a token, in percent.
+    extend_threshold_percent: u64,
+    /// Errors reach clients with generic messages, see `client_status`.
+    opaque_errors: bool,
+    /// Longest TTL of a token issued by `TokenExchange`.
+    exchange_ttl: Duration,
+    /// Sampled requests are appended to `CAPTURE_FILE`, see `capture`.
+    capture: Option<capture::Recorder>,
+    /// How far ahead a login's not-before time may be; unset refuses them.
+    max_not_before: Option<Duration>,
+    login_limit: Option<ConcurrencyLimit>,
+    validate_limit: Option<ConcurrencyLimit>,
+    hashing_limit: HashingLimit,
+    max_session_lifetime: Option<Duration>,
+    /// Prefix for every auxiliary key the service keeps in redis.
+    namespace: String,
+    lockout: Option<Lockout>,
+    /// Failed login policy per client address, against one client trying many
+    /// usernames.
+    ip_lockout: Option<Lockout>,
+    /// Token classes by name, see `TOKEN_CLASSES`.
+    token_classes: HashMap<String, ClassPolicy>,
+    expose_session_id: bool,
+    failure_delay: Duration,
+    require_tls_for_login: bool,
+    validate_result_in_response: bool,
+    /// Keep an index of this instance's tokens and delete them on shutdown.
+    revoke_sessions_on_shutdown: bool,
+    emergency_admin: Option<EmergencyAdmin>,
+    /// Fails redis checkouts fast while redis is down, see `redis_conn`.
+    redis_breaker: Option<Breaker>,
+    /// Time a request may spend on redis, shortened by a closer client deadline.
+    redis_timeout: Duration,
+    /// Subtracted from the remaining TTL in validation cache hints.
+    cache_margin: Duration,
+    /// Encoding of new sessions; stored sessions are read in any format.
+    session_format: SessionFormat,
+    /// Store sessions under the hash of their token, see `token_key`.
+    hash_tokens: bool,
+    /// Unix time until which validation falls back to the plaintext key of a
+    /// token missing under its hashed key, see `migrate_token_key`.
+    legacy_token_keys_until: Option<u64>,
+    /// Redis operations taking longer are logged, see `timed_redis`.
+    slow_redis: Option<Duration>,
+    slow_redis_log_keys: bool,
+    trace_id_in_response: bool,
+    bind_sessions_to_ip: bool,
+    /// Proxies whose `x-forwarded-for` is trusted to name the client.
+    trusted_proxies: Vec<Cidr>,
+    /// Calls taking longer are logged, see `observed`.
+    slow_request: Option<Duration>,
+    /// How long a gone token is still reported as recently expired.
+    recently_expired_grace: Option<Duration>,
+    /// TTL of guest sessions; `LoginAnonymous` is refused when unset.
+    guest_ttl: Option<Duration>,
+    users: Users,
+    trace: TraceContext,
+}
+
+/// Break-glass login that bypasses the user directory, for recovery when it is
+/// broken. Only enabled for a bounded window, and every attempt is audit logged.
+struct EmergencyAdmin {
+    user: String,
+    password: String,
+    /// End of the window, unix seconds.
+    until: u64,
+    ttl: Duration,
+}
+
+impl EmergencyAdmin {
+    /// Longest window that may be opened at a time.
+    const MAX_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn active(&self, now: u64) -> bool {
+        now < self.until
+    }
+}
+
+/// Failed login policy. Counters live in redis, so a lockout survives restarts.
+struct Lockout {
+    max_failures: u64,
+    window: Duration,
+}
+
+/// The policy of `max_failures` within `window` seconds (900 by default),
+/// `None` when the limit is unset or 0.
+fn lockout(max_failures: Option<u64>, window: Option<u64>) -> Option<Lockout> {
+    match max_failures {
+        Some(0) | None => None,
+        Some(max_failures) => Some(Lockout {
+            max_failures,
+            window: Duration::from_secs(window.unwrap_or(900)),
+        }),
+    }
+}
+
+/// The parts of a request message that may be recorded on its span. Passwords
+/// and tokens never are.
+trait RequestSummary {
+    fn user(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl RequestSummary for LoginRequest {
+    fn user(&self) -> Option<&str> {
+        Some(&self.user)
+    }
+}
+
+impl RequestSummary for LoginAnonymousRequest {}
+
+impl RequestSummary for ValidateRequest {}
+
+impl RequestSummary for IntrospectRequest {}
+
+impl RequestSummary for ValidateBatchRequest {}
+
+impl RequestSummary for SessionCountRequest {}
+
+impl RequestSummary for TokenExchangeRequest {}
+
+impl RequestSummary for ListSessionsRequest {
+    fn user(&self) -> Option<&str> {
+        Some(&self.user)
+    }
+}
+
+impl RequestSummary for RevokeAllSessionsRequest {}
+
+impl RequestSummary for InspectTokenRequest {}
+
+/// One line description of a call for its span, naming the method, the user if
+/// the message has one and the peer address.
+fn request_summary<T: RequestSummary>(method: &str, request: &Request<T>) -> String {
+    let mut summary = format!("method={}", method);
+    if let Some(user) = request.get_ref().user() {
+        summary.push_str(&format!(" user={}", user));
+    }
+    match request.remote_addr() {
+        Some(peer) => summary.push_str(&format!(" peer={}", peer)),
+        None => summary.push_str(" peer=unknown"),
+    }
+    summary
+}
+
+/// Id the caller tagged the request with in `x-request-id` metadata, for logs.
+fn request_id(metadata: &tonic::metadata::MetadataMap) -> Option<String> {
+    metadata
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Extracts the token of an `authorization: Bearer <token>` metadata entry, as
+/// forwarded by gateways and proxies.
+fn bearer_token(metadata: &tonic::metadata::MetadataMap) -> Option<&str> {
+    metadata
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+thread_local! {
+    /// Backtrace of the latest panic on this thread, see `install_panic_hook`.
+    static PANIC_BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+/// Keeps the backtrace of every panic for `handler_panicked`, which runs on the
+/// panicking thread after unwinding, before handing the panic to the default hook.
+fn install_panic_hook() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        PANIC_BACKTRACE
+            .with(|backtrace| *backtrace.borrow_mut() = Some(Backtrace::force_capture()));
+        default(info);
+    }));
+}
+
+/// Logs and traces a handler panic, returning the status to answer with.
+fn handler_panicked(
+    method: &'static str,
+    request_id: Option<&str>,
+    payload: Box<dyn std::any::Any + Send>,
+) -> Status {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_owned());
+    let backtrace = PANIC_BACKTRACE
+        .with(|backtrace| backtrace.borrow_mut().take())
+        .map(|backtrace| backtrace.to_string())
+        .unwrap_or_default();
+    println!(
+        "handler {} panicked (request id {}): {}\n{}",
+        method,
+        request_id.unwrap_or("none"),
+        message,
+        backtrace
+    );
+    // the handler's own span was dropped while unwinding
+    let mut span = global::tracer(APPLICATION_ID).start(method);
+    span.set_attribute(KeyValue::new("error", true));
+    span.set_attribute(KeyValue::new("panic.message", message));
+    Status::internal("internal error")
+}
+
+/// Marks `span` as failed with `err`, and returns `err`.
+fn fail<E: std::error::Error>(span: &mut impl Span, err: E) -> E {
+    span.set_attribute(KeyValue::new("error", true));
+    span.record_error(&err);
+    err
+}
+
+/// Bounds the calls of a method handled at once, see `LOGIN_CONCURRENCY_LIMIT`.
+struct ConcurrencyLimit {
+    limit: usize,
+    slots: Semaphore,
+}
+
+/// Takes a slot from the method's concurrency limit, if one is configured.
+/// The returned permit must be held for the whole duration of the call.
+fn acquire_slot<'a>(
+    limit: &'a Option<ConcurrencyLimit>,
+    span: &mut impl Span,
+) -> Result<Option<SemaphorePermit<'a>>, Status> {
+    match limit {
+        Some(limit) => match limit.slots.try_acquire() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => Err(fail(
+                span,
+                Status::resource_exhausted("too many concurrent requests"),
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Bounds the password hashes verified at once, see `HASHING_CONCURRENCY`.
+/// Logins over the limit queue for a slot, unless `max_waiting` already do.
+struct HashingLimit {
+    concurrency: usize,
+    slots: Semaphore,
+    waiting: AtomicUsize,
+    max_waiting: usize,
+    max_wait: Duration,
+}
+
+impl HashingLimit {
+    fn from_config(config: &Config) -> Self {
+        let concurrency = config
+            .hashing_concurrency
+            .filter(|&concurrency| concurrency > 0)
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+        HashingLimit {
+            concurrency,
+            slots: Semaphore::new(concurrency),
+            waiting: AtomicUsize::new(0),
+            max_waiting: config.hashing_queue.unwrap_or(concurrency * 4),
+            max_wait: config
+                .hashing_queue_timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(Duration::from_secs(1)),
+        }
+    }
+
+    /// Waits for a slot, failing with `RESOURCE_EXHAUSTED` when the queue is
+    /// full or the wait exceeds `HASHING_QUEUE_TIMEOUT_MS`.
+    async fn acquire(&self, span: &mut impl Span) -> Result<SemaphorePermit<'_>, Status> {
+        if let Ok(permit) = self.slots.try_acquire() {
+            return Ok(permit);
+        }
+        let waiting = Waiting::enter(&self.waiting);
+        let err = if waiting.ahead >= self.max_waiting {
+            Status::resource_exhausted("too many logins waiting for password verification")
+        } else {
+            match tokio::time::timeout(self.max_wait, self.slots.acquire()).await {
+                Ok(Ok(permit)) => return Ok(permit),
+                _ => Status::resource_exhausted("timed out waiting for password verification"),
+            }
+        };
+        Err(fail(span, err))
+    }
+}
+
+/// Counts a login as queued until dropped, also when the call is cancelled.
+struct Waiting<'a> {
+    count: &'a AtomicUsize,
+    /// Logins that were queued already.
+    ahead: usize,
+}
+
+impl<'a> Waiting<'a> {
+    fn enter(count: &'a AtomicUsize) -> Self {
+        let ahead = count.fetch_add(1, Ordering::Relaxed);
+        Waiting { count, ahead }
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The service as mounted by `AuthService::into_service`: the generated server
+/// behind the `Interceptor` and `DISABLED_METHODS`.
+pub type AuthGrpcService =
+    toggles::Toggled<tonic::codegen::InterceptedService<AuthServer<AuthService>, Interceptor>>;
+
+/// The admin service as returned by `AuthService::into_services`, behind the
+/// same `Interceptor` as the `Auth` service.
+pub type AdminGrpcService =
+    tonic::codegen::InterceptedService<AuthAdminServer<AdminService>, Interceptor>;
+
+/// Configuration of an `AuthService`. Whatever isn't set here is taken from the
+/// `Config`, which is read from the environment unless one is given.
+pub struct AuthServiceBuilder {
+    config: Option<Config>,
+    pool: r2d2::Pool<RedisManager>,
+    aux_pool: Option<r2d2::Pool<RedisManager>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    events: Option<Arc<dyn EventSink>>,
+    ttl: Option<Duration>,
+    session_id: Option<String>,
+    namespace: Option<String>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl AuthServiceBuilder {
+    /// Settings of the service, in place of the environment's as loaded by
+    /// `Config::layered`.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Pool for auxiliary data such as failed login counters, the session pool
+    /// by default.
+    pub fn aux_pool(mut self, pool: r2d2::Pool<RedisManager>) -> Self {
+        self.aux_pool = Some(pool);
+        self
+    }
+
+    /// Sink for the service's metrics, `NoopMetrics` by default.
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Receiver of auth events such as logins, `NoopEvents` by default.
+    pub fn events(mut self, events: Arc<dyn EventSink>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Default session TTL, in place of `SESSION_TTL_SECONDS`.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Server session tokens are minted under, random by default. Tokens of
+    /// another session id fail validation, so a fixed one keeps tokens valid
+    /// across restarts.
+    pub fn session_id(mut self, session_id: impl Into<String>) -> Self {
+        self.session_id = Some(session_id.into());
+        self
+    }
+
+    /// Time source of the service's expiry checks, `SystemClock` by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Prefix of the service's redis keys, in place of `REDIS_NAMESPACE`.
+    pub fn namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Takes the remaining settings from the configuration, see `config`. Like
+    /// the binary, it panics on a malformed `TOKEN_CLASSES`, and on a malformed
+    /// environment when no configuration was given.
+    pub fn build(self) -> AuthService {
+        let AuthServiceBuilder {
+            config,
+            pool,
+            aux_pool,
+            metrics,
+            events,
+            ttl,
+            session_id,
+            namespace,
+            clock,
+        } = self;
+        let config = config.unwrap_or_else(|| {
+            Config::default()
+                .layered(std::env::vars(), None)
+                .unwrap_or_else(|err| panic!("invalid configuration: {}", err))
+        });
+        if config.audit_hash_chain {
+            audit::start_chain();
+        }
+        let seconds = |secs: Option<u64>| secs.map(Duration::from_secs);
+        let aux_pool = aux_pool.unwrap_or_else(|| pool.clone());
+        let metrics = metrics.unwrap_or_else(|| Arc::new(NoopMetrics));
+        let session_id = session_id.unwrap_or_else(|| Uuid::new_v4().hyphenated().to_string());
+        let redis_breaker = config
+            .redis_breaker_failures
+            .filter(|&failures| failures > 0)
+            .map(|failures| {
+                Breaker::new(
+                    failures,
+                    seconds(config.redis_breaker_cooldown_seconds)
+                        .unwrap_or(Duration::from_secs(30)),
+                    metrics.clone(),
+                )
+            });
+        let ttl = bounded_ttl(
+            "SESSION_TTL_SECONDS",
+            ttl.unwrap_or_else(|| Duration::from_secs(config.session_ttl_seconds.unwrap_or(600))),
+        );
+        let bounded = |setting, ttl: Option<Duration>| ttl.map(|ttl| bounded_ttl(setting, ttl));
+
+        AuthService {
+            session_id,
+            clock: clock.unwrap_or_else(|| Arc::new(SystemClock)),
+            pool,
+            aux_pool,
+            metrics,
+            events: events.unwrap_or_else(|| Arc::new(NoopEvents)),
+            ttl,
+            min_ttl: bounded(
+                "SESSION_TTL_MIN_SECONDS",
+                seconds(config.session_ttl_min_seconds),
+            )
+            .unwrap_or_else(|| ttl.min(Duration::from_secs(60))),
+            max_ttl: bounded(
+                "SESSION_TTL_MAX_SECONDS",
+                seconds(config.session_ttl_max_seconds),
+            )
+            .unwrap_or(ttl),
+            max_not_before: seconds(config.max_not_before_seconds),
+            extend_threshold_percent: extend_threshold_percent(&config),
+            opaque_errors: config.error_detail_level == Some(config::ErrorDetailLevel::Opaque),
+            exchange_ttl: bounded("EXCHANGE_TTL_SECONDS", seconds(config.exchange_ttl_seconds))
+                .unwrap_or(Duration::from_secs(300)),
+            capture: capture::Recorder::from_config(&config),
+            remember_me_ttl: bounded(
+                "REMEMBER_ME_TTL_SECONDS",
+                seconds(config.remember_me_ttl_seconds),
+            )
+            .unwrap_or(Duration::from_secs(30 * 24 * 3600)),
+            login_limit: concurrency_limit(config.login_concurrency_limit),
+            validate_limit: concurrency_limit(config.validate_concurrency_limit),
+            hashing_limit: HashingLimit::from_config(&config),
+            max_session_lifetime: seconds(config.session_max_lifetime_seconds),
+            namespace: namespace.unwrap_or_else(|| {
+                config
+                    .redis_namespace
+                    .clone()
+                    .unwrap_or_else(|| APPLICATION_ID.to_owned())
+            }),
+            lockout: lockout(config.login_max_failures, config.login_lockout_seconds),
+            ip_lockout: lockout(
+                config.login_max_failures_per_ip,
+                config.login_ip_lockout_seconds,
+            ),
+            token_classes: token_classes(
+                config.token_classes.as_deref().unwrap_or_default(),
+                ClassPolicy {
+                    ttl,
+                    single_use: false,
+                    max_sessions: config.max_sessions_per_user.unwrap_or_default(),
+                },
+            ),
+            expose_session_id: expose_session_id(&config),
+            failure_delay: Duration::from_millis(config.login_failure_delay_ms.unwrap_or_default()),
+            require_tls_for_login: config.require_tls_for_login,
+            validate_result_in_response: config.validate_result_in_response,
+            revoke_sessions_on_shutdown: config.revoke_sessions_on_shutdown,
+            emergency_admin: emergency_admin(&config),
+            redis_breaker,
+            redis_timeout: redis_timeout(&config),
+            hash_tokens: config.hash_tokens,
+            legacy_token_keys_until: legacy_token_keys_until(&config),
+            slow_redis: config.slow_redis_ms.map(Duration::from_millis),
+            slow_redis_log_keys: config.slow_redis_log_keys,
+            cache_margin: seconds(config.validate_cache_margin_seconds)
+                .unwrap_or(Duration::from_secs(30)),
+            session_format: config.session_format,
+            trace_id_in_response: config.trace_id_in_response,
+            bind_sessions_to_ip: config.bind_sessions_to_ip,
+            guest_ttl: bounded("GUEST_TTL_SECONDS", seconds(config.guest_ttl_seconds))
+                .filter(|ttl| !ttl.is_zero()),
+            recently_expired_grace: config
+                .recently_expired_grace_seconds
+                .filter(|grace| *grace > 0)
+                .map(Duration::from_secs),
+            slow_request: config.slow_request_ms.map(Duration::from_millis),
+            trusted_proxies: config
+                .trusted_proxies
+                .as_deref()
+                .map(|proxies| {
+                    proxies
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|proxy| !proxy.is_empty())
+                        .map(|proxy| {
+                            Cidr::parse(proxy).unwrap_or_else(|| {
+                                panic!("TRUSTED_PROXIES entry {} is not an address or CIDR", proxy)
+                            })
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            users: Users::from_config(&config),
+            trace: TraceContext::from_config(&config),
+            config,
+        }
+    }
+}
+
+impl AuthService {
+    /// Configures a service storing its sessions in `pool`.
+    pub fn builder(pool: r2d2::Pool<RedisManager>) -> AuthServiceBuilder {
+        AuthServiceBuilder {
+            config: None,
+            pool,
+            aux_pool: None,
+            metrics: None,
+            events: None,
+            ttl: None,
+            session_id: None,
+            namespace: None,
+            clock: None,
+        }
+    }
+
+    /// The service to add to a tonic `Server`. Fails if `DISABLED_METHODS` names
+    /// an unknown method or `GRPC_COMPRESSION` an unavailable algorithm.
+    pub fn into_service(self) -> Result<AuthGrpcService, String> {
+        Ok(self.into_services()?.0)
+    }
+
+    /// The `Auth` service as by `into_service`, and the `AuthAdmin` service on
+    /// the same state, which checks the same API key. Add the admin service to
+    /// a server of its own, on an address clients of `Auth` can't reach.
+    pub fn into_services(self) -> Result<(AuthGrpcService, AdminGrpcService), String> {
+        let disabled = disabled_methods(&self.config)?;
+        if !disabled.is_empty() {
+            println!("disabled methods: {:?}", disabled);
+        }
+        let encodings = compression_encodings(&self.config)?;
+        if !encodings.is_empty() {
+            println!("grpc compression: {:?}", encodings);
+        }
+        let interceptor = Interceptor::from_config(&self.config);
+        let auth = Arc::new(self);
+        let mut server = AuthServer::from_arc(auth.clone());
+        for encoding in encodings {
+            server = server.accept_compressed(encoding).send_compressed(encoding);
+        }
+        let service = toggles::Toggled::new(
+            tonic::codegen::InterceptedService::new(server, interceptor.clone()),
+            disabled,
+        );
+        let admin = tonic::codegen::InterceptedService::new(
+            AuthAdminServer::new(AdminService { auth }),
+            interceptor,
+        );
+        Ok((service, admin))
+    }
+
+    /// TTL of a new session: the requested one clamped to the configured bounds,
+    /// or the default of its class.
+    ///
+    /// A remember me login defaults to `REMEMBER_ME_TTL_SECONDS`, which also
+    /// raises the upper bound for a requested TTL. No TTL outlasts the maximum
+    /// session lifetime.
+    fn granted_ttl(
+        &self,
+        requested_secs: Option<u64>,
+        remember_me: bool,
+        class: &ClassPolicy,
+    ) -> Duration {
+        let (default, max) = if remember_me {
+            (self.remember_me_ttl, self.max_ttl.max(self.remember_me_ttl))
+        } else {
+            (class.ttl, self.max_ttl)
+        };
+        let ttl = match requested_secs {
+            Some(requested) => Duration::from_secs(requested).max(self.min_ttl).min(max),
+            None => default,
+        };
+        match self.max_session_lifetime {
+            Some(lifetime) => ttl.min(lifetime),
+            None => ttl,
+        }
+    }
+
+    /// Unix time before which a token issued at `now` for `ttl` is refused, or 0
+    /// if `requested` is unset or not ahead. A time further ahead than
+    /// `MAX_NOT_BEFORE_SECONDS`, or one the token would expire before, is refused.
+    fn not_before(
+        &self,
+        now: SystemTime,
+        requested: Option<&Timestamp>,
+        ttl: Duration,
+    ) -> Result<u64, Status> {
+        let now = unix_time(now);
+        let requested = match requested {
+            Some(requested) => u64::try_from(requested.seconds).unwrap_or_default(),
+            None => return Ok(0),
+        };
+        if requested <= now {
+            return Ok(0);
+        }
+        let delay = requested - now;
+        match self.max_not_before {
+            None => Err(Status::invalid_argument("not_before is disabled")),
+            Some(max) if delay > max.as_secs() => Err(Status::invalid_argument(format!(
+                "not_before is more than {} seconds ahead",
+                max.as_secs()
+            ))),
+            Some(_) if delay >= ttl.as_secs() => Err(Status::invalid_argument(
+                "not_before is past the token's expiry",
+            )),
+            Some(_) => Ok(requested),
+        }
+    }
+
+    /// Slows down a failed login by the configured delay plus up to as much random
+    /// jitter. Callers must not hold a pooled connection while waiting.
+    async fn failure_delay(&self) {
+        if self.failure_delay.is_zero() {
+            return;
+        }
+        let jitter = rand::thread_rng().gen_range(0..=self.failure_delay.as_millis() as u64);
+        tokio::time::sleep(self.failure_delay + Duration::from_millis(jitter)).await;
+    }
+
+    /// Deletes a session stored in a layout this release can't read, so it fails
+    /// like an unknown token from then on, and returns the error to answer with.
+    fn drop_unreadable(&self, conn: &mut RedisConnection, key: &str, err: &DecodeError) -> Status {
+        println!("deleting unreadable session: {}", err);
+        let _: () = conn.del(key).unwrap_or_default();
+        Status::unauthenticated(err.to_string())
+    }
+
+    /// Deletes a token key holding another redis type than a string, which
+    /// only an operator mistake or a bug writes, and returns the error to answer.
+    /// It is not a client error, so it is internal rather than unauthenticated.
+    fn drop_wrong_type(&self, conn: &mut RedisConnection, key: &str) -> Status {
+        let kind = redis::cmd("TYPE")
+            .arg(key)
+            .query::<String>(conn)
+            .unwrap_or_else(|_| "unknown".to_owned());
+        // the key names the token, so it stays out of the log
+        println!("deleting token key holding a {} instead of a string", kind);
+        let _: () = conn.del(key).unwrap_or_default();
+        Status::internal(format!("token key holds a {} instead of a session", kind))
+    }
+
+    /// Answers a failed validation with `err`, or in result mode with an `Ok`
+    /// response carrying `result`.
+    fn validation_failed(
+        &self,
+        in_response: bool,
+        result: ValidationResult,
+        err: Status,
+    ) -> Result<Response<ValidateResponse>, Status> {
+        if !in_response {
+            return Err(err);
+        }
+        Ok(Response::new(ValidateResponse {
+            session_id: String::new(),
+            result: result as i32,
+            cacheable_for_seconds: 0,
+            extended: false,
+        }))
+    }
+
+    /// Runs a redis operation, logging and counting it when it takes longer than
+    /// `SLOW_REDIS_MS`. `keys` names the kind of keys it touches, logged only
+    /// with `SLOW_REDIS_LOG_KEYS`.
+    fn timed_redis<T>(&self, operation: &'static str, keys: &str, op: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = op();
+        let elapsed = start.elapsed();
+        if self.slow_redis.is_some_and(|threshold| elapsed > threshold) {
+            if self.slow_redis_log_keys {
+                println!(
+                    "slow redis operation: {} on {} keys took {:?}",
+                    operation, keys, elapsed
+                );
+            } else {
+                println!("slow redis operation: {} took {:?}", operation, elapsed);
+            }
+            self.metrics.increment_counter(
+                metrics::SLOW_REDIS_OPERATIONS_TOTAL,
+                &[("operation", operation)],
+            );
+        }
+        result
+    }
+
+    /// Checks a stored session against the request, returning why it fails
+    /// validation if it does. Revoked and expired sessions are deleted, and a
+    /// valid one-time session is consumed.
+    fn session_failure(
+        &self,
+        conn: &mut RedisConnection,
+        key: &str,
+        session: &Session,
+        client_ip: Option<IpAddr>,
+        required_scope: &str,
+        revoked_before: u64,
+    ) -> Option<(ValidationResult, Status)> {
+        self.session_check(
+            conn,
+            key,
+            session,
+            client_ip,
+            required_scope,
+            revoked_before,
+        )
+        .or_else(|| {
+            // only the validation that deleted the token may use it
+            (session.one_time && !matches!(conn.del(key), Ok(1))).then(|| {
+                let err = Status::unauthenticated("one-time token already used");
+                (ValidationResult::Unknown, err)
+            })
+        })
+    }
+
+    /// The checks of `session_failure` short of consuming a one-time session,
+    /// for looking at a token without using it.
+    fn session_check(
+        &self,
+        conn: &mut RedisConnection,
+        key: &str,
+        session: &Session,
+        client_ip: Option<IpAddr>,
+        required_scope: &str,
+        revoked_before: u64,
+    ) -> Option<(ValidationResult, Status)> {
+        if session.session_id != self.session_id {
+            let err = Status::unauthenticated("wrong session ID");
+            Some((ValidationResult::WrongSession, err))
+        } else if !session.parent.is_empty() && matches!(conn.exists(&session.parent), Ok(false)) {
+            // see `handle_token_exchange`
+            let _: () = conn.del(key).unwrap_or_default();
+            let err = Status::unauthenticated("parent token revoked");
+            Some((ValidationResult::Revoked, err))
+        } else if session.login_time_micros() <= revoked_before {
+            // see `revoke_all_sessions`
+            let _: () = conn.del(key).unwrap_or_default();
+            let err = Status::unauthenticated("session revoked");
+            Some((ValidationResult::Revoked, err))
+        } else if session
+            .client_ip
+            .is_some_and(|bound| client_ip.map(ipv6) != Some(bound))
+        {
+            let err = status_with_reason(
+                tonic::Code::Unauthenticated,
+                "session is bound to another client address",
+                IP_MISMATCH,
+            );
+            Some((ValidationResult::IpMismatch, err))
+        } else if !self.token_classes.contains_key(&session.class) {
+            // removing a class from the config revokes its tokens
+            let _: () = conn.del(key).unwrap_or_default();
+            let err = Status::unauthenticated(format!(
+                "token class {} is no longer configured",
+                session.class
+            ));
+            Some((ValidationResult::Revoked, err))
+        } else if self.users.account_disabled(&session.user) {
+            let _: () = conn.del(key).unwrap_or_default();
+            let err = Status::unauthenticated("session revoked");
+            Some((ValidationResult::Revoked, err))
+        } else if session.not_before > self.unix_now() {
+            let err = status_with_reason(
+                tonic::Code::Unauthenticated,
+                "token not yet valid",
+                TOKEN_NOT_YET_VALID,
+            );
+            Some((ValidationResult::NotYetValid, err))
+        } else if self.session_expired(session) {
+            let _: () = conn.del(key).unwrap_or_default();
+            let err = status_with_reason(
+                tonic::Code::Unauthenticated,
+                "session exceeded maximum lifetime",
+                SESSION_EXPIRED,
+            );
+            Some((ValidationResult::Expired, err))
+        } else if !required_scope.is_empty() && !session.scopes.iter().any(|s| s == required_scope)
+        {
+            let err = Status::permission_denied(format!("token lacks scope {}", required_scope));
+            Some((ValidationResult::InsufficientScope, err))
+        } else {
+            None
+        }
+    }
+
+    /// Extends the token at `key`, which has `remaining` seconds left, back to
+    /// the TTL of `session` if less than `EXTEND_THRESHOLD_PERCENT` of it
+    /// remains, capped by the session's maximum lifetime. Single-use and guest
+    /// tokens aren't extended. Returns the new TTL if the token was extended;
+    /// failing to extend only logs, as the token is still valid.
+    fn extend_session(
+        &self,
+        conn: &mut RedisConnection,
+        key: &str,
+        session: &Session,
+        remaining: i64,
+    ) -> Option<i64> {
+        if session.one_time || session.ttl == 0 {
+            return None;
+        }
+        let now = self.unix_now();
+        let mut ttl = session.ttl;
+        if let Some(max) = self.max_session_lifetime {
+            ttl = ttl.min((session.login_at + max.as_secs()).saturating_sub(now));
+        }
+        let threshold = session.ttl * self.extend_threshold_percent / 100;
+        let remaining_secs = u64::try_from(remaining).ok()?;
+        if remaining_secs >= threshold || ttl <= remaining_secs {
+            return None;
+        }
+        let mut extend = EXTEND_SESSION.key(key);
+        extend.key(self.user_sessions_key(&session.user));
+        extend.key(self.class_sessions_key(&session.class, &session.user));
+        extend.key(self.instance_sessions_key());
+        extend.key(self.expired_marker_key(key));
+        let extended = self.timed_redis("extend_session", TOKEN_KEYS, || {
+            extend
+                .arg(ttl)
+                .arg(threshold)
+                .arg(now)
+                .arg(
+                    self.recently_expired_grace
+                        .map_or(0, |grace| grace.as_secs()),
+                )
+                .arg(self.revoke_sessions_on_shutdown as u8)
+                .invoke::<bool>(conn)
+        });
+        match extended {
+            Ok(true) => i64::try_from(ttl).ok(),
+            Ok(false) => None,
+            Err(err) => {
+                println!("failed to extend session: {}", err);
+                None
+            }
+        }
+    }
+
+    /// How long a client may reuse a successful validation of `session`, whose
+    /// token has `remaining_ttl` seconds left as reported by redis. Single-use
+    /// tokens and tokens about to expire are not cacheable.
+    fn cacheable_for(&self, session: &Session, remaining_ttl: i64) -> u64 {
+        if session.one_time {
+            return 0;
+        }
+        let mut remaining = u64::try_from(remaining_ttl).unwrap_or_default();
+        if let Some(max) = self.max_session_lifetime {
+            let lifetime_left = (session.login_at + max.as_secs()).saturating_sub(self.unix_now());
+            remaining = remaining.min(lifetime_left);
+        }
+        remaining.saturating_sub(self.cache_margin.as_secs())
+    }
+
+    /// The session id to put into responses; empty unless debug exposure is enabled
+    /// and the client is new enough to know the field.
+    fn debug_session_id(&self, client_version: u32) -> String {
+        if self.expose_session_id && client_version >= SESSION_ID_SINCE_VERSION {
+            self.session_id.clone()
+        } else {
+            String::new()
+        }
+    }
+
+    /// Effective configuration for the admin endpoint, with secrets redacted.
+    fn config_summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "session_ttl_seconds": self.ttl.as_secs(),
+            "session_format": self.session_format.name(),
+            "session_ttl_min_seconds": self.min_ttl.as_secs(),
+            "session_ttl_max_seconds": self.max_ttl.as_secs(),
+            "remember_me_ttl_seconds": self.remember_me_ttl.as_secs(),
+            "max_not_before_seconds": self.max_not_before.map(|max| max.as_secs()),
+            "extend_threshold_percent": self.extend_threshold_percent,
+            "exchange_ttl_seconds": self.exchange_ttl.as_secs(),
+            "error_detail_level": if self.opaque_errors { "opaque" } else { "verbose" },
+            "capture": self.capture.as_ref().map(|recorder| serde_json::json!({
+                "file": self.config.capture_file,
+                "every": recorder.every(),
+            })),
+            "session_max_lifetime_seconds": self.max_session_lifetime.map(|max| max.as_secs()),
+            "token_classes": self
+                .token_classes
+                .iter()
+                .map(|(name, class)| {
+                    (
+                        name.clone(),
+                        serde_json::json!({
+                            "ttl_seconds": class.ttl.as_secs(),
+                            "single_use": class.single_use,
+                            "max_sessions": class.max_sessions,
+                        }),
+                    )
+                })
+                .collect::<serde_json::Map<_, _>>(),
+            "login_concurrency_limit": self.login_limit.as_ref().map(|limit| limit.limit),
+            "validate_concurrency_limit": self.validate_limit.as_ref().map(|limit| limit.limit),
+            "hashing": {
+                "concurrency": self.hashing_limit.concurrency,
+                "queue": self.hashing_limit.max_waiting,
+                "queue_timeout_ms": self.hashing_limit.max_wait.as_millis() as u64,
+            },
+            "login_failure_delay_ms": self.failure_delay.as_millis() as u64,
+            "lockout": self.lockout.as_ref().map(|lockout| serde_json::json!({
+                "max_failures": lockout.max_failures,
+                "window_seconds": lockout.window.as_secs(),
+            })),
+            "ip_lockout": self.ip_lockout.as_ref().map(|lockout| serde_json::json!({
+                "max_failures": lockout.max_failures,
+                "window_seconds": lockout.window.as_secs(),
+            })),
+            "redis": {
+                "namespace": self.namespace,
+                "pool_size": self.pool.max_size(),
+                "session_db": self.config.redis_session_db.unwrap_or_default(),
+                "aux_db": self.config.redis_aux_db.unwrap_or_default(),
+                "username": self.config.redis_username.as_ref().map(|_| admin::REDACTED),
+                "password": self.config.redis_password.as_ref().map(|_| admin::REDACTED),
+            },
+            "tracing_exporter": "jaeger-agent",
+            "expose_session_id": self.expose_session_id,
+            "require_tls_for_login": self.require_tls_for_login,
+            "validate_result_in_response": self.validate_result_in_response,
+            "revoke_sessions_on_shutdown": self.revoke_sessions_on_shutdown,
+            "trace_id_in_response": self.trace_id_in_response,
+            "bind_sessions_to_ip": self.bind_sessions_to_ip,
+            "recently_expired_grace_seconds": self.recently_expired_grace.map(|grace| grace.as_secs()),
+            "guest_ttl_seconds": self.guest_ttl.map(|ttl| ttl.as_secs()),
+            "slow_request_ms": self.slow_request.map(|threshold| threshold.as_millis() as u64),
+            "trusted_proxies": self.trusted_proxies.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "redis_timeout_ms": self.redis_timeout.as_millis() as u64,
+            "validate_cache_margin_seconds": self.cache_margin.as_secs(),
+            "hash_tokens": self.hash_tokens,
+            "legacy_token_keys_until": self.legacy_token_keys_until,
+            "slow_redis_ms": self.slow_redis.map(|threshold| threshold.as_millis() as u64),
+            "slow_redis_log_keys": self.slow_redis_log_keys,
+            "redis_breaker": self.redis_breaker.as_ref().map(|breaker| serde_json::json!({
+                "failures": breaker.failures,
+                "cooldown_seconds": breaker.cooldown.as_secs(),
+            })),
+            "emergency_admin": self.emergency_admin.as_ref().map(|admin| serde_json::json!({
+                "user": admin.user,
+                "until": admin.until,
+                "session_ttl_seconds": admin.ttl.as_secs(),
+            })),
+            "api_key": self
+                .config
+                .api_key
+                .as_ref()
+                .filter(|key| !key.expose().is_empty())
+                .map(|_| admin::REDACTED),
+        })
+    }
+
+    /// Periodically publishes the number of live sessions to the `auth_active_sessions` gauge.
+    fn spawn_session_count_task(&self, interval: Duration) {
+        let pool = self.pool.clone();
+        let pattern = self.user_sessions_key("*");
+        let metrics = self.metrics.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let pool = pool.clone();
+                let pattern = pattern.clone();
+                // the count scans redis, keep it off the request handling workers
+                let count =
+                    tokio::task::spawn_blocking(move || count_sessions(&pool, &pattern)).await;
+                match count {
+                    Ok(Ok(count)) => metrics.set_gauge(metrics::ACTIVE_SESSIONS, &[], count),
+                    Ok(Err(err)) => println!("failed to count sessions: {}", err),
+                    Err(err) => println!("session count task failed: {}", err),
+                }
+            }
+        });
+    }
+
+    /// Periodically removes the entries of tokens that no longer exist from the
+    /// user, class and guest indexes, which redis doesn't expire member by
+    /// member. Indexes are walked `batch` entries per command, so large ones
+    /// don't block redis.
+    fn spawn_index_pruning(&self, interval: Duration, batch: usize) {
+        let pool = self.pool.clone();
+        let patterns = [
+            self.user_sessions_key("*"),
+            self.class_sessions_key("*", "*"),
+            self.guest_sessions_key(),
+        ];
+        let metrics = self.metrics.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // the first tick is immediate, leave the start to the requests
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let pool = pool.clone();
+                let patterns = patterns.clone();
+                let pruned =
+                    tokio::task::spawn_blocking(move || prune_indexes(&pool, &patterns, batch))
+                        .await;
+                match pruned {
+                    Ok(Ok(pruned)) => {
+                        if pruned > 0 {
+                            println!("pruned {} index entries of gone tokens", pruned);
+                        }
+                        metrics.add_to_counter(metrics::INDEX_ENTRIES_PRUNED_TOTAL, &[], pruned);
+                    }
+                    Ok(Err(err)) => println!("failed to prune session indexes: {}", err),
+                    Err(err) => println!("index pruning task failed: {}", err),
+                }
+            }
+        });
+    }
+
+    /// Periodically checks whether the session redis may evict keys, which would
+    /// end sessions before their TTL and log users out without a trace. Warns
+    /// when that becomes possible and publishes the `auth_redis_eviction_risk`
+    /// and `auth_redis_evicted_keys` gauges.
+    fn spawn_eviction_probe(&self, interval: Duration) {
+        let pool = self.pool.clone();
+        let metrics = self.metrics.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            let mut last_policy = None;
+            loop {
+                ticker.tick().await;
+                let pool = pool.clone();
+                let eviction = tokio::task::spawn_blocking(move || redis_eviction(&pool)).await;
+                let eviction = match eviction {
+                    Ok(Ok(eviction)) => eviction,
+                    Ok(Err(err)) => {
+                        println!("failed to check redis eviction policy: {}", err);
+                        continue;
+                    }
+                    Err(err) => {
+                        println!("redis eviction probe failed: {}", err);
+                        continue;
+                    }
+                };
+                let risk = eviction.at_risk();
+                if risk && last_policy.as_ref() != Some(&eviction.policy) {
+                    println!(
+                        "WARNING: redis has maxmemory {} with policy {}, sessions may be evicted before they expire; use noeviction",
+                        eviction.maxmemory, eviction.policy
+                    );
+                }
+                last_policy = risk.then_some(eviction.policy);
+                metrics.set_gauge(metrics::REDIS_EVICTION_RISK, &[], risk as i64);
+                metrics.set_gauge(metrics::REDIS_EVICTED_KEYS, &[], eviction.evicted_keys);
+            }
+        });
+    }
+
+    /// Address of the client. Behind a trusted proxy this is the last address in
+    /// `x-forwarded-for` that is not itself a trusted proxy.
+    fn client_ip<T>(&self, request: &Request<T>) -> Option<IpAddr> {
+        let peer = request.remote_addr()?.ip();
+        if !self.trusted_proxy(peer) {
+            return Some(peer);
+        }
+        let forwarded = request
+            .metadata()
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|hop| hop.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>();
+        Some(
+            forwarded
+                .into_iter()
+                .rev()
+                .find(|hop| !self.trusted_proxy(*hop))
+                .unwrap_or(peer),
+        )
+    }
+
+    /// Checks out a redis connection. Failures are reported as `UNAVAILABLE` and
+    /// fed to the circuit breaker, which refuses checkouts while it is open.
+    ///
+    /// The checkout and the commands sent on the connection are bounded by the
+    /// time left until `deadline`. A command running into it leaves its reply
+    /// unread, so `RedisConnection` has the pool drop the connection.
+    fn redis_conn(
+        &self,
+        pool: &r2d2::Pool<RedisManager>,
+        deadline: Deadline,
+        span: &mut impl Span,
+    ) -> Result<r2d2::PooledConnection<RedisManager>, Status> {
+        if let Some(breaker) = &self.redis_breaker {
+            if !breaker.allow() {
+                return Err(fail(span, Status::unavailable("redis is unavailable")));
+            }
+        }
+        let remaining = deadline.at.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(fail(
+                span,
+                deadline.status_or(Status::unavailable("redis timeout expired")),
+            ));
+        }
+        let conn = pool.get_timeout(remaining);
+        if let Some(breaker) = &self.redis_breaker {
+            breaker.record(conn.is_ok());
+        }
+        let conn = conn.map_err(|err| {
+            fail(span, &err);
+            deadline.status_or(Status::unavailable(format!(
+                "redis is unavailable: {}",
+                err
+            )))
+        })?;
+        // a timeout can only fail on zero, which is excluded above
+        let _ = conn.set_read_timeout(Some(remaining));
+        let _ = conn.set_write_timeout(Some(remaining));
+        Ok(conn)
+    }
+
+    /// Deadline for the redis work of a request: `REDIS_TIMEOUT_MS` from now, or
+    /// the client's `grpc-timeout` if that is closer.
+    fn redis_deadline(&self, metadata: &tonic::metadata::MetadataMap) -> Deadline {
+        let now = Instant::now();
+        let configured = Deadline {
+            at: now + self.redis_timeout,
+            client: false,
+        };
+        match grpc_timeout(metadata) {
+            Some(timeout) if timeout < self.redis_timeout => Deadline {
+                at: now + timeout,
+                client: true,
+            },
+            _ => configured,
+        }
+    }
+
+    fn trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|proxies| proxies.contains(ip))
+    }
+
+    /// Key of the sorted set indexing a user's tokens by login time.
+    fn user_sessions_key(&self, user: &str) -> String {
+        format!("{}:sessions:{}", self.namespace, user)
+    }
+
+    /// Key of the sorted set indexing a user's tokens of one class by login time,
+    /// used to enforce the class's session limit.
+    fn class_sessions_key(&self, class: &str, user: &str) -> String {
+        format!("{}:classes:{}:{}", self.namespace, class, user)
+    }
+
+    /// Key of the sorted set indexing guest tokens by login time, in place of the
+    /// per-user indexes other sessions have.
+    fn guest_sessions_key(&self) -> String {
+        format!("{}:guests", self.namespace)
+    }
+
+    /// Key of the unix time in microseconds up to which all sessions are
+    /// revoked, see `revoke_all_sessions`.
+    fn session_epoch_key(&self) -> String {
+        format!("{}:session-epoch", self.namespace)
+    }
+
+    /// Key of the sorted set indexing this instance's tokens by expiry time.
+    fn instance_sessions_key(&self) -> String {
+        format!("{}:instance:{}", self.namespace, self.session_id)
+    }
+
+    /// Key of the marker that outlives the session at `key` by the recently
+    /// expired grace period.
+    fn expired_marker_key(&self, key: &str) -> String {
+        format!("{}:expired:{}", self.namespace, key)
+    }
+
+    /// Prefix of the redis keys of sessions, see `token_key`.
+    fn token_key_prefix(&self) -> String {
+        format!("{}:token:", self.namespace)
+    }
+
+    /// Redis key of the session of `token`. With `HASH_TOKENS` only the SHA-256 of
+    /// the token is stored, so a dump of redis doesn't hold usable tokens.
+    fn token_key(&self, token: &str) -> String {
+        if self.hash_tokens {
+            format!(
+                "{}{:x}",
+                self.token_key_prefix(),
+                Sha256::digest(token.as_bytes())
+            )
+        } else {
+            format!("{}{}", self.token_key_prefix(), token)
+        }
+    }
+
+    /// Keys the session of `token` may be stored under by earlier releases or
+    /// settings, first to last looked up: with `HASH_TOKENS` its plaintext key,
+    /// then without the namespace its hashed key and the token itself.
+    fn legacy_token_keys(&self, token: &str) -> Vec<String> {
+        if self.hash_tokens {
+            vec![
+                format!("{}{}", self.token_key_prefix(), token),
+                format!("{:x}", Sha256::digest(token.as_bytes())),
+                token.to_owned(),
+            ]
+        } else {
+            vec![token.to_owned()]
+        }
+    }
+
+    /// Until `LEGACY_TOKEN_KEYS_UNTIL`, moves the session of `token` from a key
+    /// of `legacy_token_keys` to the `key` it was looked up under, so switching
+    /// on `HASH_TOKENS` or upgrading from a release without namespaced token
+    /// keys doesn't log anyone out. Returns the session data and remaining TTL
+    /// if there was a session to move.
+    fn migrate_token_key(
+        &self,
+        conn: &mut RedisConnection,
+        token: &str,
+        key: &str,
+    ) -> Option<(Vec<u8>, i64)> {
+        if self.legacy_token_keys_until? <= self.unix_now() {
+            return None;
+        }
+        let legacy_keys = self.legacy_token_keys(token);
+        let legacy = self.timed_redis("get_legacy_session", TOKEN_KEYS, || {
+            let mut lookup = redis::pipe();
+            for legacy_key in &legacy_keys {
+                lookup.get(legacy_key).ttl(legacy_key);
+            }
+            lookup.query::<Vec<(Option<Vec<u8>>, i64)>>(&mut *conn)
+        });
+        let found = legacy.map(|sessions| {
+            legacy_keys
+                .iter()
+                .zip(sessions)
+                .find_map(|(legacy_key, (value, ttl))| Some((legacy_key, value?, ttl)))
+        });
+        let (legacy_key, value, ttl) = match found {
+            Ok(Some(found)) => found,
+            Ok(None) => return None,
+            Err(err) => {
+                println!("failed to read legacy token key: {}", err);
+                return None;
+            }
+        };
+        // unreadable values are left for validation of the plaintext key to fail
+        let session = session_format::decode(&value).ok()?;
+        let mut migrate = MIGRATE_TOKEN_KEY.key(legacy_key);
+        migrate.key(key);
+        if session.user.is_empty() {
+            migrate.key(self.guest_sessions_key());
+            migrate.key(self.guest_sessions_key());
+        } else {
+            migrate.key(self.user_sessions_key(&session.user));
+            migrate.key(self.class_sessions_key(&session.class, &session.user));
+        }
+        migrate.key(self.instance_sessions_key());
+        migrate.key(self.expired_marker_key(legacy_key));
+        migrate.key(self.expired_marker_key(key));
+        match self.timed_redis("migrate_token_key", TOKEN_KEYS, || {
+            migrate.invoke::<bool>(conn)
+        }) {
+            Ok(true) => {
+                self.metrics
+                    .increment_counter(metrics::LEGACY_TOKENS_MIGRATED_TOTAL, &[]);
+                Some((value, ttl))
+            }
+            Ok(false) => None,
+            Err(err) => {
+                println!("failed to migrate legacy token key: {}", err);
+                None
+            }
+        }
+    }
+
+    fn lockout_key(&self, user: &str) -> String {
+        format!("{}:lockout:{}", self.namespace, user)
+    }
+
+    fn ip_lockout_key(&self, ip: IpAddr) -> String {
+        format!("{}:lockout-ip:{}", self.namespace, ipv6(ip))
+    }
+
+    /// The failure counters of a login by `user` from `ip`, each with its policy.
+    fn failure_counters(&self, user: &str, ip: Option<IpAddr>) -> Vec<(&Lockout, String)> {
+        let mut counters = Vec::new();
+        if let Some(lockout) = &self.lockout {
+            counters.push((lockout, self.lockout_key(user)));
+        }
+        if let (Some(lockout), Some(ip)) = (&self.ip_lockout, ip) {
+            counters.push((lockout, self.ip_lockout_key(ip)));
+        }
+        counters
+    }
+
+    /// If the user or the client address has reached its failed login limit,
+    /// returns how long until the counter expires and logins are accepted again.
+    /// When both have, the longer wait wins.
+    fn locked_out(
+        &self,
+        conn: &mut RedisConnection,
+        user: &str,
+        ip: Option<IpAddr>,
+    ) -> redis::RedisResult<Option<Duration>> {
+        let mut retry_after = None;
+        for (lockout, key) in self.failure_counters(user, ip) {
+            let (failures, ttl): (Option<u64>, i64) =
+                redis::pipe().get(&key).ttl(&key).query(conn)?;
+            if failures.unwrap_or_default() < lockout.max_failures {
+                continue;
+            }
+            // a counter without expiry can't be left by `record_failure`,
+            // fall back to the full window
+            let wait = match u64::try_from(ttl) {
+                Ok(secs) => Duration::from_secs(secs),
+                Err(_) => lockout.window,
+            };
+            retry_after = retry_after.max(Some(wait));
+        }
+        Ok(retry_after)
+    }
+
+    /// Counts a failed login against the user and the client address. Each
+    /// counter and its expiry are updated in one transaction so a crash can't
+    /// leave a counter that never expires.
+    fn record_failure(&self, conn: &mut RedisConnection, user: &str, ip: Option<IpAddr>) {
+        for (lockout, key) in self.failure_counters(user, ip) {
+            let result: redis::RedisResult<()> = redis::pipe()
+                .atomic()
+                .incr(&key, 1)
+                .ignore()
+                .expire(&key, lockout.window.as_secs() as usize)
+                .ignore()
+                .query(conn);
+            if let Err(err) = result {
+                println!("failed to record login failure for {}: {}", user, err);
+            }
+        }
+    }
+
+    /// Clears the user's failure counter. The client address keeps its own, or
+    /// logging into one account would let a client guess at others again.
+    fn reset_failures(&self, conn: &mut RedisConnection, user: &str) {
+        if self.lockout.is_some() {
+            let result: redis::RedisResult<()> = conn.del(self.lockout_key(user));
+            if let Err(err) = result {
+                println!("failed to reset login failures for {}: {}", user, err);
+            }
+        }
+    }
+
+    fn unix_now(&self) -> u64 {
+        unix_time(self.clock.now())
+    }
+
+    /// Reports whether the session has outlived the absolute lifetime cap,
+    /// no matter how recently its token was issued.
+    fn session_expired(&self, session: &Session) -> bool {
+        match self.max_session_lifetime {
+            Some(max) => self.unix_now().saturating_sub(session.login_at) > max.as_secs(),
+            None => false,
+        }
+    }
+}
+
+/// Point in time the redis work of a request has to be done by.
+#[derive(Clone, Copy)]
+struct Deadline {
+    at: Instant,
+    /// Set by the client's `grpc-timeout` rather than `REDIS_TIMEOUT_MS`.
+    client: bool,
+}
+
+impl Deadline {
+    /// `DEADLINE_EXCEEDED` once the client's deadline has passed, as the client
+    /// has given up on the call by then; `status` otherwise.
+    fn status_or(&self, status: Status) -> Status {
+        if self.client && Instant::now() >= self.at {
+            Status::deadline_exceeded("client deadline exceeded")
+        } else {
+            status
+        }
+    }
+}
+
+/// Time the client allows for the call, from the `grpc-timeout` metadata entry,
+/// written as up to eight digits and a unit.
+fn grpc_timeout(metadata: &tonic::metadata::MetadataMap) -> Option<Duration> {
+    let value = metadata.get("grpc-timeout")?.to_str().ok()?;
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+/// Longest a request may wait for redis, from `REDIS_TIMEOUT_MS`. The default is
+/// the pool's own checkout timeout.
+fn redis_timeout(config: &Config) -> Duration {
+    config
+        .redis_timeout_ms
+        .filter(|&ms| ms > 0)
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::from_secs(30))
+}
+
+/// `EXTEND_THRESHOLD_PERCENT`, 20 when unset. Above 100 panics, so a typo is
+/// noticed at startup.
+fn extend_threshold_percent(config: &Config) -> u64 {
+    let percent = config.extend_threshold_percent.unwrap_or(20);
+    if percent > 100 {
+        panic!("EXTEND_THRESHOLD_PERCENT {} is above 100", percent);
+    }
+    percent
+}
+
+/// `LEGACY_TOKEN_KEYS_UNTIL`, see `AuthService::migrate_token_key`.
+fn legacy_token_keys_until(config: &Config) -> Option<u64> {
+    let until = config.legacy_token_keys_until?;
+    if until <= unix_now() {
+        println!("LEGACY_TOKEN_KEYS_UNTIL has passed, legacy token keys are no longer read");
+    }
+    Some(until)
+}
+
+/// `scopes` without repeats, in the order first requested. More than
+/// `session_format::MAX_SCOPES` distinct scopes are refused, as a session can't
+/// store them.
+fn distinct_scopes(scopes: Vec<String>) -> Result<Vec<String>, Status> {
+    let mut seen = HashSet::new();
+    let scopes: Vec<String> = scopes
+        .into_iter()
+        .filter(|scope| seen.insert(scope.clone()))
+        .collect();
+    if scopes.len() > session_format::MAX_SCOPES {
+        return Err(Status::invalid_argument(format!(
+            "at most {} scopes may be requested",
+            session_format::MAX_SCOPES
+        )));
+    }
+    Ok(scopes)
+}
+
+/// Maps IPv4 addresses into IPv6, so both families compare in one form.
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// An address block such as `10.0.0.0/8`, kept in the IPv6 form of `ipv6`.
+struct Cidr {
+    network: u128,
+    prefix: u32,
+}
+
+impl Cidr {
+    /// Parses `<address>/<prefix>`, or a bare address as a block of one.
+    fn parse(cidr: &str) -> Option<Self> {
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (cidr.parse().ok()?, None),
+        };
+        // IPv4 blocks sit in the last 32 bits of the mapped form
+        let (bits, offset) = match addr {
+            IpAddr::V4(_) => (32, 96),
+            IpAddr::V6(_) => (128, 0),
+        };
+        let prefix = prefix.unwrap_or(bits);
+        if prefix > bits {
+            return None;
+        }
+        let prefix = prefix + offset;
+        Some(Cidr {
+            network: u128::from(ipv6(addr)) & Self::mask(prefix),
+            prefix,
+        })
+    }
+
+    fn mask(prefix: u32) -> u128 {
+        u128::MAX.checked_shl(128 - prefix).unwrap_or(0)
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        u128::from(ipv6(ip)) & Self::mask(self.prefix) == self.network
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let addr = Ipv6Addr::from(self.network);
+        match addr.to_ipv4_mapped() {
+            Some(v4) if self.prefix >= 96 => write!(f, "{}/{}", v4, self.prefix - 96),
+            _ => write!(f, "{}/{}", addr, self.prefix),
+        }
+    }
+}
+
+/// Whether the request arrived on a TLS connection of this server.
+#[cfg(feature = "tls")]
+fn over_tls<T>(request: &Request<T>) -> bool {
+    use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
+    request
+        .extensions()
+        .get::<TlsConnectInfo<TcpConnectInfo>>()
+        .is_some()
+}
+
+#[cfg(not(feature = "tls"))]
+fn over_tls<T>(_request: &Request<T>) -> bool {
+    false
+}
+
+/// The break-glass login needs a password secret and an end time no more than
+/// `EmergencyAdmin::MAX_WINDOW` ahead; it is refused otherwise and announced
+/// loudly when enabled.
+fn emergency_admin(config: &Config) -> Option<EmergencyAdmin> {
+    let password = config
+        .emergency_admin_password
+        .as_ref()
+        .map(|password| password.expose().to_owned())
+        .filter(|password| !password.is_empty())?;
+    let now = unix_now();
+    let until = match config.emergency_admin_until {
+        Some(until) if until > now && until - now <= EmergencyAdmin::MAX_WINDOW.as_secs() => until,
+        _ => {
+            println!(
+                "emergency admin login is disabled: EMERGENCY_ADMIN_UNTIL must be a unix time within the next {} seconds",
+                EmergencyAdmin::MAX_WINDOW.as_secs()
+            );
+            return None;
+        }
+    };
+    let admin = EmergencyAdmin {
+        user: config
+            .emergency_admin_user
+            .clone()
+            .unwrap_or_else(|| "emergency-admin".to_owned()),
+        password,
+        until,
+        ttl: bounded_ttl(
+            "EMERGENCY_ADMIN_SESSION_TTL_SECONDS",
+            Duration::from_secs(config.emergency_admin_session_ttl_seconds.unwrap_or(300)),
+        ),
+    };
+    audit::record(&format!(
+        "WARNING: emergency admin login enabled for {} until {} (unix time)",
+        admin.user, admin.until
+    ));
+    Some(admin)
+}
+
+/// Pool of connections to database `db`, over TLS if REDIS_TLS is set, and
+/// authenticating with REDIS_USERNAME and REDIS_PASSWORD if set. Rejected credentials fail right away rather than on
+/// the first request.
+fn redis_pool(config: &Config, db: u32) -> r2d2::Pool<RedisManager> {
+    let host = config.redis_host.as_deref().unwrap_or("127.0.0.1");
+    let port = config.redis_port.unwrap_or(6379);
+    let url = if config.redis_tls {
+        if !cfg!(feature = "redis-tls") {
+            panic!("REDIS_TLS is set but redis TLS support is not compiled in");
+        }
+        if config.redis_tls_insecure {
+            println!("WARNING: redis server certificate is not verified (REDIS_TLS_INSECURE), use for testing only");
+            format!("rediss://{}:{}/{}#insecure", host, port, db)
+        } else {
+            format!("rediss://{}:{}/{}", host, port, db)
+        }
+    } else {
+        format!("redis://{}:{}/{}", host, port, db)
+    };
+    let mut info = url.into_connection_info().unwrap();
+    info.username = config
+        .redis_username
+        .as_ref()
+        .map(|username| username.expose().to_owned());
+    info.passwd = config
+        .redis_password
+        .as_ref()
+        .map(|password| password.expose().to_owned());
+    let authenticated = info.passwd.is_some();
+    let manager = RedisManager::new(info).unwrap();
+    if authenticated {
+        if let Err(err) = r2d2::ManageConnection::connect(&manager) {
+            panic!("failed to authenticate to redis: {}", err);
+        }
+    }
+    // building blocks until `min_idle` connections are open, which warms the
+    // pool before the server reports that it is ready
+    r2d2::Pool::builder()
+        .min_idle(config.redis_pool_min_idle)
+        .idle_timeout(pool_timeout(
+            config.redis_idle_timeout_seconds,
+            Duration::from_secs(600),
+        ))
+        .max_lifetime(pool_timeout(
+            config.redis_max_lifetime_seconds,
+            Duration::from_secs(1800),
+        ))
+        .connection_timeout(redis_timeout(config))
+        // PING every connection before handing it out so stale ones are replaced
+        .test_on_check_out(true)
+        .build(manager)
+        .unwrap_or_else(|err| panic!("failed to connect to redis at {}:{}: {}", host, port, err))
+}
+
+/// Exposing the session id lets clients correlate tokens with server instances,
+/// so it is never allowed in production.
+fn expose_session_id(config: &Config) -> bool {
+    if !config.debug_expose_session_id {
+        return false;
+    }
+    if config.app_env.as_deref() == Some("production") {
+        println!("DEBUG_EXPOSE_SESSION_ID is ignored because APP_ENV is production");
+        return false;
+    }
+    println!("WARNING: session id is exposed in responses (DEBUG_EXPOSE_SESSION_ID)");
+    true
+}
+
+/// A per-method concurrency limit of at most `limit` calls.
+/// An unset or zero value means the method is not limited.
+fn concurrency_limit(limit: Option<usize>) -> Option<ConcurrencyLimit> {
+    match limit {
+        Some(0) | None => None,
+        Some(limit) => Some(ConcurrencyLimit {
+            limit,
+            slots: Semaphore::new(limit),
+        }),
+    }
+}
+
+/// Checks out a connection for work outside of requests, clearing the command
+/// timeouts a request may have left on it.
+fn background_conn(
+    pool: &r2d2::Pool<RedisManager>,
+) -> Result<r2d2::PooledConnection<RedisManager>, Box<dyn std::error::Error + Send + Sync>> {
+    let conn = pool.get()?;
+    conn.set_read_timeout(None)?;
+    conn.set_write_timeout(None)?;
+    Ok(conn)
+}
+
+/// Deletes every token in the index `key` and the index itself, returning the
+/// number of tokens that still existed.
+fn revoke_sessions(
+    pool: &r2d2::Pool<RedisManager>,
+    key: &str,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn = background_conn(pool)?;
+    let tokens: Vec<String> = conn.zrange(key, 0, -1)?;
+    let mut revoked = 0;
+    for chunk in tokens.chunks(100) {
+        revoked += conn.del::<_, usize>(chunk)?;
+    }
+    let _: () = conn.del(key)?;
+    Ok(revoked)
+}
+
+/// Revokes every session issued up to `now`, by any instance, without touching
+/// the sessions themselves: validation refuses sessions that started no later
+/// than the epoch stored under `key`, a unix time in microseconds. Returns the
+/// new epoch.
+fn revoke_all_sessions(
+    pool: &r2d2::Pool<RedisManager>,
+    key: &str,
+    now: SystemTime,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn = background_conn(pool)?;
+    let epoch = RAISE_SESSION_EPOCH
+        .key(key)
+        .arg(unix_micros(now))
+        .invoke(&mut *conn)?;
+    audit::record(&format!("revoked all sessions issued up to {}", epoch));
+    Ok(epoch)
+}
+
+/// Counts live sessions across all per-user indexes matching `pattern`, with
+/// `COUNT_SESSIONS`. A session is live while its token key exists, which redis
+/// expires by the TTL the token was granted, whatever its class.
+fn count_sessions(
+    pool: &r2d2::Pool<RedisManager>,
+    pattern: &str,
+) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn = background_conn(pool)?;
+
+    let mut cursor = 0u64;
+    let mut count = 0i64;
+    loop {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .cursor_arg(cursor)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(100)
+            .query(&mut *conn)?;
+
+        for key in &keys {
+            count += COUNT_SESSIONS.key(key).invoke::<i64>(&mut *conn)?;
+        }
+
+        if next == 0 {
+            return Ok(count);
+        }
+        cursor = next;
+    }
+}
+
+/// Removes the entries of tokens that no longer exist from every index matching
+/// one of `patterns`, `batch` keys or entries per command, and returns how many
+/// it removed.
+fn prune_indexes(
+    pool: &r2d2::Pool<RedisManager>,
+    patterns: &[String],
+    batch: usize,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn = background_conn(pool)?;
+    let mut pruned = 0;
+    for pattern in patterns {
+        let mut cursor = 0u64;
+        loop {
+            let (next, indexes): (u64, Vec<String>) = redis::cmd("SCAN")
+                .cursor_arg(cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(batch)
+                .query(&mut *conn)?;
+            for index in &indexes {
+                pruned += prune_index(&mut conn, index, batch)?;
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+    }
+    Ok(pruned)
+}
+
+/// Removes the entries of gone tokens from the sorted set `index`.
+fn prune_index(
+    conn: &mut RedisConnection,
+    index: &str,
+    batch: usize,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let mut pruned = 0;
+    let mut cursor = 0u64;
+    loop {
+        // entries come as token and score pairs
+        let (next, entries): (u64, Vec<String>) = redis::cmd("ZSCAN")
+            .arg(index)
+            .cursor_arg(cursor)
+            .arg("COUNT")
+            .arg(batch)
+            .query(&mut *conn)?;
+        let tokens: Vec<&str> = entries.iter().step_by(2).map(String::as_str).collect();
+        if !tokens.is_empty() {
+            let mut exists = redis::pipe();
+            for token in &tokens {
+                exists.exists(*token);
+            }
+            let found: Vec<bool> = exists.query(&mut *conn)?;
+            let gone: Vec<&str> = tokens
+                .into_iter()
+                .zip(found)
+                .filter(|(_, found)| !found)
+                .map(|(token, _)| token)
+                .collect();
+            if !gone.is_empty() {
+                pruned += conn.zrem::<_, _, u64>(index, gone)?;
+            }
+        }
+        if next == 0 {
+            return Ok(pruned);
+        }
+        cursor = next;
+    }
+}
+
+/// Memory limit and eviction settings of a redis server, from `INFO`.
+struct Eviction {
+    /// Bytes, 0 if unlimited.
+    maxmemory: u64,
+    policy: String,
+    evicted_keys: i64,
+}
+
+impl Eviction {
+    /// Whether keys may be dropped before their TTL. Every session key has a
+    /// TTL, so the `volatile-*` policies are as dangerous as `allkeys-*`.
+    fn at_risk(&self) -> bool {
+        self.maxmemory > 0 && self.policy != "noeviction"
+    }
+}
+
+/// Reads the eviction settings from `INFO`, which unlike `CONFIG GET` is also
+/// allowed on managed redis services.
+fn redis_eviction(
+    pool: &r2d2::Pool<RedisManager>,
+) -> Result<Eviction, Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn = background_conn(pool)?;
+    let (memory, stats): (String, String) = redis::pipe()
+        .cmd("INFO")
+        .arg("memory")
+        .cmd("INFO")
+        .arg("stats")
+        .query(&mut *conn)?;
+    let field = |info: &str, name: &str| {
+        info.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .map(|value| value.trim().to_owned())
+            .ok_or_else(|| format!("redis INFO lacks {}", name))
+    };
+    Ok(Eviction {
+        maxmemory: field(&memory, "maxmemory")?.parse()?,
+        policy: field(&memory, "maxmemory_policy")?,
+        evicted_keys: field(&stats, "evicted_keys")?.parse()?,
+    })
+}
+
+/// A pool timeout of `secs` seconds. Unset keeps `default`, zero disables the timeout.
+fn pool_timeout(secs: Option<u64>, default: Duration) -> Option<Duration> {
+    match secs {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => Some(default),
+    }
+}
+
+/// Trace context formats read from requests: jaeger's `uber-trace-id` and
+/// W3C baggage.
+fn propagator() -> TextMapCompositePropagator {
+    TextMapCompositePropagator::new(vec![
+        Box::new(opentelemetry_jaeger::Propagator::new()),
+        Box::new(BaggagePropagator::new()),
+    ])
+}
+
+/// Metadata key clients present the shared API key in.
+const API_KEY_METADATA: &str = "x-api-key";
+
+/// Compares secrets in time that depends only on their length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Formats metadata for logs. Binary values are hex encoded and unprintable ascii
+/// values are replaced by a marker, so logging never fails or spews raw bytes.
+/// Credentials such as the API key or a bearer token are redacted.
+fn describe_metadata(metadata: &tonic::metadata::MetadataMap) -> String {
+    metadata
+        .iter()
+        .map(|entry| match entry {
+            tonic::metadata::KeyAndValueRef::Ascii(key, _)
+                if capture::SECRET_METADATA.contains(&key.as_str()) =>
+            {
+                format!("{}: {}", key, admin::REDACTED)
+            }
+            tonic::metadata::KeyAndValueRef::Ascii(key, value) => match value.to_str() {
+                Ok(value) => format!("{}: {:?}", key, value),
+                Err(_) => format!("{}: <non-printable>", key),
+            },
+            tonic::metadata::KeyAndValueRef::Binary(key, value) => match value.to_bytes() {
+                Ok(bytes) => format!(
+                    "{}: 0x{}",
+                    key,
+                    bytes
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<String>()
+                ),
+                Err(_) => format!("{}: <invalid base64>", key),
+            },
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Checks the API key of every call and logs a sample of them.
+#[derive(Clone)]
+pub struct Interceptor {
+    /// Shared service-to-service secret. Requests are not gated when it is unset.
+    api_key: Option<Secret>,
+    /// Every how many requests one is logged; 0 disables the log. Defaults to
+    /// every request, or none in production.
+    log_every: u64,
+    intercepted: Arc<AtomicU64>,
+}
+
+impl Interceptor {
+    fn from_config(config: &Config) -> Self {
+        Interceptor {
+            api_key: config
+                .api_key
+                .clone()
+                .filter(|key| !key.expose().is_empty()),
+            log_every: config.request_log_every.unwrap_or(
+                if config.app_env.as_deref() == Some("production") {
+                    0
+                } else {
+                    1
+                },
+            ),
+            intercepted: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl tonic::service::Interceptor for Interceptor {
+    fn call(&mut self, req: Request<()>) -> Result<Request<()>, Status> {
+        if self.log_every > 0
+            && self
+                .intercepted
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.log_every)
+        {
+            println!(
+                "Intercepting request: {{{}}}",
+                describe_metadata(req.metadata())
+            );
+        }
+
+        if let Some(api_key) = &self.api_key {
+            let presented = req
+                .metadata()
+                .get(API_KEY_METADATA)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            if !constant_time_eq(presented.as_bytes(), api_key.expose().as_bytes()) {
+                return Err(Status::unauthenticated("invalid API key"));
+            }
+        }
+
+        Ok(req)
+    }
+}
+/// Methods of the service, as named in `DISABLED_METHODS`.
+const METHODS: &[&str] = &[
+    "login",
+    "loginanonymous",
+    "validate",
+    "introspect",
+    "validatebatch",
+    "sessioncount",
+    "tokenexchange",
+];
+
+/// Methods listed in `DISABLED_METHODS`. An unknown name fails the start rather
+/// than leaving a method on that was meant to be off.
+fn disabled_methods(config: &Config) -> Result<HashSet<String>, String> {
+    config
+        .disabled_methods
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(|method| method.trim().to_lowercase())
+        .filter(|method| !method.is_empty())
+        .map(|method| {
+            if METHODS.contains(&method.as_str()) {
+                Ok(method)
+            } else {
+                Err(format!("DISABLED_METHODS names unknown method {}", method))
+            }
+        })
+        .collect()
+}
+
+/// Encodings named by `GRPC_COMPRESSION`, accepted for requests and used for
+/// responses to clients accepting them. Unset leaves compression off.
+fn compression_encodings(config: &Config) -> Result<Vec<CompressionEncoding>, String> {
+    config
+        .grpc_compression
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .map(|name| compression_encoding(&name))
+        .collect()
+}
+
+#[cfg(feature = "compression")]
+fn compression_encoding(name: &str) -> Result<CompressionEncoding, String> {
+    match name {
+        "gzip" => Ok(CompressionEncoding::Gzip),
+        _ => Err(format!("GRPC_COMPRESSION names unknown algorithm {}", name)),
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn compression_encoding(name: &str) -> Result<CompressionEncoding, String> {
+    Err(format!(
+        "GRPC_COMPRESSION names {} but compression support is not compiled in",
+        name
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auth::auth_server::Auth;
+    use opentelemetry::trace::TraceId;
+
+    /// A pool that never connects, for services built but not called.
+    fn unconnected_pool() -> r2d2::Pool<RedisManager> {
+        r2d2::Pool::builder().build_unchecked(RedisManager::new("redis://127.0.0.1:1/").unwrap())
+    }
+
+    #[test]
+    fn request_log_redacts_credentials() {
+        let mut metadata = tonic::metadata::MetadataMap::new();
+        metadata.insert("x-api-key", "shared-secret".parse().unwrap());
+        metadata.insert("authorization", "Bearer 3f2a9c41".parse().unwrap());
+        metadata.insert("cookie", "session=3f2a9c41".parse().unwrap());
+        metadata.insert("user-agent", "grpc-go".parse().unwrap());
+
+        let logged = describe_metadata(&metadata);
+
+        assert!(!logged.contains("shared-secret"));
+        assert!(!logged.contains("3f2a9c41"));
+        assert!(logged.contains(r#"user-agent: "grpc-go""#));
+        assert_eq!(logged.matches(admin::REDACTED).count(), 3);
+    }
+
+    #[test]
+    fn trace_context_round_trips_through_metadata() {
+        use opentelemetry::propagation::TextMapPropagator;
+        use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceState};
+
+        global::set_text_map_propagator(propagator());
+        let sent = SpanContext::new(
+            TraceId::from_bytes(0x3f2a9c41_0000_0000_0000_000000000001u128.to_be_bytes()),
+            SpanId::from_bytes(0x3f2a9c41u64.to_be_bytes()),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let cx = Context::new()
+            .with_remote_span_context(sent.clone())
+            .with_baggage(vec![KeyValue::new("tenant", "acme")]);
+        let mut request = Request::new(());
+        inject_context(&cx, &mut request);
+        let received = |keys: Option<&str>| {
+            let mut config = Config::default();
+            if let Some(keys) = keys {
+                config.set("TRACE_CONTEXT_KEYS", keys).unwrap();
+            }
+            let trace = TraceContext::from_config(&config);
+            propagator().extract(&MetadataMap(request.metadata(), &trace))
+        };
+
+        let cx = received(None);
+        let span = cx.span().span_context().clone();
+        assert_eq!(span.trace_id(), sent.trace_id());
+        assert_eq!(span.span_id(), sent.span_id());
+        assert_eq!(cx.baggage().get("tenant"), Some(&"acme".into()));
+        let cx = received(Some("uber-trace-id"));
+        assert_eq!(cx.span().span_context(), &sent);
+        assert_eq!(cx.baggage().len(), 0);
+        assert!(!received(Some("uberctx-*")).span().span_context().is_valid());
+    }
+
+    #[test]
+    fn config_summary_redacts_every_secret() {
+        let secrets = [
+            ("API_KEY", "3f2a9c41-api"),
+            ("AUDIT_CHAIN_KEY", "3f2a9c41-audit"),
+            ("EMERGENCY_ADMIN_PASSWORD", "3f2a9c41-admin"),
+            ("REDIS_PASSWORD", "3f2a9c41-password"),
+            ("REDIS_USERNAME", "3f2a9c41-username"),
+        ];
+        let mut config = Config::default();
+        for (key, value) in secrets {
+            config.set(key, value).unwrap();
+        }
+        config.set("EMERGENCY_ADMIN_USER", "ops").unwrap();
+        config.set("EMERGENCY_ADMIN_UNTIL", "4102444800").unwrap();
+
+        let summary = AuthService::builder(unconnected_pool())
+            .config(config)
+            .build()
+            .config_summary()
+            .to_string();
+
+        assert!(!summary.contains("3f2a9c41"), "{}", summary);
+        assert_eq!(summary.matches(admin::REDACTED).count(), 3);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis at REDIS_URL"]
+    async fn session_count_follows_the_ttl_of_each_token() {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_owned());
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .build(RedisManager::new(url).unwrap())
+            .unwrap();
+        let mut config = Config::default();
+        config.set("SESSION_TTL_SECONDS", "1").unwrap();
+        config.set("REMEMBER_ME_TTL_SECONDS", "60").unwrap();
+        let service = AuthService::builder(pool.clone())
+            .config(config)
+            .namespace(format!("test-{}", Uuid::new_v4()))
+            .build();
+        for remember_me in [false, true] {
+            let request = Request::new(LoginRequest {
+                user: "user".to_owned(),
+                password: "user".to_owned(),
+                remember_me,
+                ..Default::default()
+            });
+            service.login(request).await.unwrap();
+        }
+
+        // past the TTL of the session without remember me
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let pattern = service.user_sessions_key("*");
+        let count = tokio::task::spawn_blocking(move || count_sessions(&pool, &pattern))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn enormous_ttls_are_clamped_when_built() {
+        let mut config = Config::default();
+        config
+            .set("SESSION_TTL_SECONDS", u64::MAX.to_string())
+            .unwrap();
+        config
+            .set("SESSION_TTL_MAX_SECONDS", u64::MAX.to_string())
+            .unwrap();
+        config
+            .set("TOKEN_CLASSES", format!("long=ttl:{}", u64::MAX))
+            .unwrap();
+
+        let service = AuthService::builder(unconnected_pool())
+            .config(config)
+            .build();
+
+        assert_eq!((service.ttl, service.max_ttl), (MAX_TTL, MAX_TTL));
+        let long = &service.token_classes["long"];
+        assert_eq!(long.ttl, MAX_TTL);
+        let now = SystemTime::now();
+        let granted = service.granted_ttl(Some(u64::MAX), false, long);
+        assert_eq!(expiry(now, granted), Timestamp::from(now + MAX_TTL));
+    }
+
+    #[test]
+    fn requested_scopes_are_deduplicated_and_bounded() {
+        let scopes = ["read", "write", "read"].map(str::to_owned).to_vec();
+        assert_eq!(distinct_scopes(scopes).unwrap(), ["read", "write"]);
+
+        let most = (0..2 * session_format::MAX_SCOPES)
+            .map(|scope| (scope % session_format::MAX_SCOPES).to_string())
+            .collect();
+        assert!(distinct_scopes(most).is_ok());
+        let too_many = (0..=session_format::MAX_SCOPES)
+            .map(|scope| scope.to_string())
+            .collect();
+        let err = distinct_scopes(too_many).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+}
@@ -0,0 +1,379 @@
+//! Metrics abstraction, its Prometheus backend and the HTTP endpoint metrics
+//! are scraped from.
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use opentelemetry::metrics::{Counter, Histogram, Meter, MetricsError, UpDownCounter};
+use opentelemetry::sdk::export::metrics::{aggregation, AggregatorSelector};
+use opentelemetry::sdk::metrics::aggregators::{self, Aggregator};
+use opentelemetry::sdk::metrics::sdk_api::{Descriptor, InstrumentKind};
+use opentelemetry::sdk::metrics::{controllers, processors};
+use opentelemetry::sdk::Resource;
+use opentelemetry::{global, Context, KeyValue};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, TextEncoder,
+};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tonic::Code;
+
+/// Handled requests, labelled by `method` and `result`.
+pub const REQUESTS_TOTAL: &str = "auth_requests_total";
+/// Failed requests, labelled by `method` and the gRPC status `code`.
+pub const ERRORS_TOTAL: &str = "auth_errors_total";
+/// Request latency in seconds, labelled by `method`.
+pub const REQUEST_DURATION_SECONDS: &str = "auth_request_duration_seconds";
+/// Requests slower than `SLOW_REQUEST_MS`, labelled by `method`.
+pub const SLOW_REQUESTS_TOTAL: &str = "auth_slow_requests_total";
+/// Redis operations slower than `SLOW_REDIS_MS`, labelled by `operation`.
+pub const SLOW_REDIS_OPERATIONS_TOTAL: &str = "auth_slow_redis_operations_total";
+/// Successful logins, labelled by the `backend` that verified the credentials.
+pub const LOGINS_TOTAL: &str = "auth_logins_total";
+/// Connections closed on accept because `MAX_CONNECTIONS` were open.
+pub const CONNECTIONS_REJECTED_TOTAL: &str = "auth_connections_rejected_total";
+/// TLS handshakes that failed or timed out, e.g. of clients offering only
+/// versions, cipher suites or curves outside the TLS policy.
+#[cfg(feature = "tls")]
+pub const TLS_HANDSHAKE_FAILURES_TOTAL: &str = "auth_tls_handshake_failures_total";
+/// State changes of the redis circuit breaker, labelled by the new `state`
+/// (`open`, `half_open` or `closed`).
+pub const REDIS_BREAKER_TRANSITIONS_TOTAL: &str = "auth_redis_breaker_transitions_total";
+/// Time from process start until the server accepted connections.
+pub const STARTUP_DURATION_MILLISECONDS: &str = "auth_startup_duration_milliseconds";
+/// Number of live sessions.
+pub const ACTIVE_SESSIONS: &str = "auth_active_sessions";
+/// TTL in seconds granted by `Login`, labelled by token `class`.
+pub const GRANTED_TTL_SECONDS: &str = "auth_granted_ttl_seconds";
+/// Entries of expired or deleted tokens removed from the session indexes.
+pub const INDEX_ENTRIES_PRUNED_TOTAL: &str = "auth_index_entries_pruned_total";
+/// 1 while the session redis may evict keys before their TTL runs out, i.e. has
+/// a `maxmemory` with a policy other than `noeviction`, else 0.
+pub const REDIS_EVICTION_RISK: &str = "auth_redis_eviction_risk";
+/// Keys the session redis evicted since it started, as it reports them.
+pub const REDIS_EVICTED_KEYS: &str = "auth_redis_evicted_keys";
+/// Plaintext token keys moved to their hashed key, see `LEGACY_TOKEN_KEYS_UNTIL`.
+pub const LEGACY_TOKENS_MIGRATED_TOTAL: &str = "auth_legacy_tokens_migrated_total";
+
+/// Label names whose values come from a small fixed set. Labelling by username,
+/// token or any other client supplied value would create a time series per value,
+/// so samples with other labels are refused. Token classes are bounded by
+/// `TOKEN_CLASSES`, as logins naming another class are rejected.
+pub const BOUNDED_LABELS: &[&str] = &[
+    "method",
+    "result",
+    "backend",
+    "code",
+    "state",
+    "operation",
+    "class",
+];
+
+/// Label name and value pairs of a metric sample.
+pub type Labels<'a> = &'a [(&'static str, &'a str)];
+
+/// Sink for the service's metrics, so handlers don't depend on a particular
+/// metrics library. A metric must always be recorded with the same label names,
+/// all of them from `BOUNDED_LABELS`.
+pub trait Metrics: Send + Sync {
+    fn increment_counter(&self, name: &'static str, labels: Labels);
+    /// Adds `value` to a counter at once. The default increments it `value`
+    /// times.
+    fn add_to_counter(&self, name: &'static str, labels: Labels, value: u64) {
+        for _ in 0..value {
+            self.increment_counter(name, labels);
+        }
+    }
+    fn observe_histogram(&self, name: &'static str, labels: Labels, value: f64);
+    fn set_gauge(&self, name: &'static str, labels: Labels, value: i64);
+}
+
+/// Discards everything; used when no metrics backend is configured.
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {
+    fn increment_counter(&self, _name: &'static str, _labels: Labels) {}
+    fn add_to_counter(&self, _name: &'static str, _labels: Labels, _value: u64) {}
+    fn observe_histogram(&self, _name: &'static str, _labels: Labels, _value: f64) {}
+    fn set_gauge(&self, _name: &'static str, _labels: Labels, _value: i64) {}
+}
+
+/// Records metrics into the default Prometheus registry, creating each metric on first use.
+#[derive(Default)]
+pub struct PrometheusMetrics {
+    counters: Mutex<HashMap<&'static str, IntCounterVec>>,
+    histograms: Mutex<HashMap<&'static str, HistogramVec>>,
+    gauges: Mutex<HashMap<&'static str, IntGaugeVec>>,
+}
+
+/// Whether all labels are in `BOUNDED_LABELS`. An unbounded label is a bug, so
+/// debug builds panic on it; release builds drop the sample.
+fn bounded(name: &str, labels: Labels) -> bool {
+    match labels
+        .iter()
+        .find(|(label, _)| !BOUNDED_LABELS.contains(label))
+    {
+        None => true,
+        Some((label, _)) => {
+            debug_assert!(false, "metric {} uses unbounded label {}", name, label);
+            println!(
+                "dropping sample of metric {}: label {} is unbounded",
+                name, label
+            );
+            false
+        }
+    }
+}
+
+/// The snake case name of a gRPC status code, as used for the `code` label.
+pub fn code_label(code: Code) -> &'static str {
+    match code {
+        Code::Ok => "ok",
+        Code::Cancelled => "cancelled",
+        Code::Unknown => "unknown",
+        Code::InvalidArgument => "invalid_argument",
+        Code::DeadlineExceeded => "deadline_exceeded",
+        Code::NotFound => "not_found",
+        Code::AlreadyExists => "already_exists",
+        Code::PermissionDenied => "permission_denied",
+        Code::ResourceExhausted => "resource_exhausted",
+        Code::FailedPrecondition => "failed_precondition",
+        Code::Aborted => "aborted",
+        Code::OutOfRange => "out_of_range",
+        Code::Unimplemented => "unimplemented",
+        Code::Internal => "internal",
+        Code::Unavailable => "unavailable",
+        Code::DataLoss => "data_loss",
+        Code::Unauthenticated => "unauthenticated",
+    }
+}
+
+/// Records metrics through the OpenTelemetry metrics API, creating each
+/// instrument on first use. They are exported by the pipeline `otel_pipeline`
+/// sets up, with the same resource attributes as the traces.
+pub struct OtelMetrics {
+    meter: Meter,
+    counters: Mutex<HashMap<&'static str, Counter<u64>>>,
+    histograms: Mutex<HashMap<&'static str, Histogram<f64>>>,
+    /// OpenTelemetry has no synchronous gauge, so gauges are up-down counters
+    /// moved by the difference to the last value set for the labels.
+    gauges: Mutex<HashMap<&'static str, UpDownCounter<i64>>>,
+    gauge_values: Mutex<HashMap<(&'static str, Vec<String>), i64>>,
+}
+
+/// Installs a meter provider exporting into the default Prometheus registry, so
+/// the metrics endpoint serves the OpenTelemetry metrics, and returns a sink
+/// recording through it.
+pub fn otel_pipeline(resource: Resource) -> Result<OtelMetrics, MetricsError> {
+    let controller = controllers::basic(processors::factory(
+        BucketSelector,
+        aggregation::cumulative_temporality_selector(),
+    ))
+    .with_resource(resource)
+    .build();
+    global::set_meter_provider(controller.clone());
+    opentelemetry_prometheus::exporter(controller)
+        .with_registry(prometheus::default_registry().clone())
+        .try_init()?;
+    Ok(OtelMetrics {
+        meter: global::meter(crate::APPLICATION_ID),
+        counters: Mutex::default(),
+        histograms: Mutex::default(),
+        gauges: Mutex::default(),
+        gauge_values: Mutex::default(),
+    })
+}
+
+/// Histogram buckets in seconds, the Prometheus client defaults.
+const HISTOGRAM_BOUNDARIES: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Buckets of `GRANTED_TTL_SECONDS`: a minute, 5 and 15 minutes, an hour, 4 and
+/// 12 hours, a day, a week and 30 days.
+const TTL_BOUNDARIES: [f64; 9] = [
+    60.0, 300.0, 900.0, 3600.0, 14400.0, 43200.0, 86400.0, 604800.0, 2592000.0,
+];
+
+/// Bucket boundaries of the histogram `name`. Latencies fit the default
+/// buckets, session lifetimes need far larger ones.
+fn boundaries(name: &str) -> &'static [f64] {
+    if name == GRANTED_TTL_SECONDS {
+        &TTL_BOUNDARIES
+    } else {
+        &HISTOGRAM_BOUNDARIES
+    }
+}
+
+/// The simple histogram selector, but with the buckets of `boundaries`.
+struct BucketSelector;
+
+impl AggregatorSelector for BucketSelector {
+    fn aggregator_for(&self, descriptor: &Descriptor) -> Option<Arc<dyn Aggregator + Send + Sync>> {
+        match descriptor.instrument_kind() {
+            InstrumentKind::GaugeObserver => Some(Arc::new(aggregators::last_value())),
+            InstrumentKind::Histogram => Some(Arc::new(aggregators::histogram(boundaries(
+                descriptor.name(),
+            )))),
+            _ => Some(Arc::new(aggregators::sum())),
+        }
+    }
+}
+
+fn attributes(labels: Labels) -> Vec<KeyValue> {
+    labels
+        .iter()
+        .map(|(name, value)| KeyValue::new(*name, value.to_string()))
+        .collect()
+}
+
+impl Metrics for OtelMetrics {
+    fn increment_counter(&self, name: &'static str, labels: Labels) {
+        self.add_to_counter(name, labels, 1);
+    }
+
+    fn add_to_counter(&self, name: &'static str, labels: Labels, value: u64) {
+        if !bounded(name, labels) {
+            return;
+        }
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters
+            .entry(name)
+            .or_insert_with(|| self.meter.u64_counter(name).init());
+        counter.add(&Context::current(), value, &attributes(labels));
+    }
+
+    fn observe_histogram(&self, name: &'static str, labels: Labels, value: f64) {
+        if !bounded(name, labels) {
+            return;
+        }
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms
+            .entry(name)
+            .or_insert_with(|| self.meter.f64_histogram(name).init());
+        histogram.record(&Context::current(), value, &attributes(labels));
+    }
+
+    fn set_gauge(&self, name: &'static str, labels: Labels, value: i64) {
+        if !bounded(name, labels) {
+            return;
+        }
+        let last = self
+            .gauge_values
+            .lock()
+            .unwrap()
+            .insert(
+                (
+                    name,
+                    label_values(labels).iter().map(|v| v.to_string()).collect(),
+                ),
+                value,
+            )
+            .unwrap_or_default();
+        let mut gauges = self.gauges.lock().unwrap();
+        let gauge = gauges
+            .entry(name)
+            .or_insert_with(|| self.meter.i64_up_down_counter(name).init());
+        gauge.add(&Context::current(), value - last, &attributes(labels));
+    }
+}
+
+fn label_names(labels: Labels) -> Vec<&'static str> {
+    labels.iter().map(|(name, _)| *name).collect()
+}
+
+fn label_values<'a>(labels: Labels<'a>) -> Vec<&'a str> {
+    labels.iter().map(|(_, value)| *value).collect()
+}
+
+impl Metrics for PrometheusMetrics {
+    fn increment_counter(&self, name: &'static str, labels: Labels) {
+        self.add_to_counter(name, labels, 1);
+    }
+
+    fn add_to_counter(&self, name: &'static str, labels: Labels, value: u64) {
+        if !bounded(name, labels) {
+            return;
+        }
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(name).or_insert_with(|| {
+            let counter = IntCounterVec::new(Opts::new(name, name), &label_names(labels)).unwrap();
+            prometheus::register(Box::new(counter.clone())).unwrap();
+            counter
+        });
+        counter
+            .with_label_values(&label_values(labels))
+            .inc_by(value);
+    }
+
+    fn observe_histogram(&self, name: &'static str, labels: Labels, value: f64) {
+        if !bounded(name, labels) {
+            return;
+        }
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry(name).or_insert_with(|| {
+            let opts = HistogramOpts::new(name, name).buckets(boundaries(name).to_vec());
+            let histogram = HistogramVec::new(opts, &label_names(labels)).unwrap();
+            prometheus::register(Box::new(histogram.clone())).unwrap();
+            histogram
+        });
+        histogram
+            .with_label_values(&label_values(labels))
+            .observe(value);
+    }
+
+    fn set_gauge(&self, name: &'static str, labels: Labels, value: i64) {
+        if !bounded(name, labels) {
+            return;
+        }
+        let mut gauges = self.gauges.lock().unwrap();
+        let gauge = gauges.entry(name).or_insert_with(|| {
+            let gauge = IntGaugeVec::new(Opts::new(name, name), &label_names(labels)).unwrap();
+            prometheus::register(Box::new(gauge.clone())).unwrap();
+            gauge
+        });
+        gauge.with_label_values(&label_values(labels)).set(value);
+    }
+}
+
+/// Serves the metrics of the default registry in the Prometheus text format.
+pub async fn serve(addr: SocketAddr) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+
+    Server::bind(&addr).serve(make_service).await
+}
+
+async fn handle(_request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        println!("failed to encode metrics: {}", err);
+    }
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_labels_are_recorded() {
+        assert!(bounded(
+            "auth_logins_total",
+            &[("backend", "htpasswd"), ("result", "ok")]
+        ));
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "unbounded label user"))]
+    fn unbounded_label_is_dropped() {
+        assert!(!bounded("auth_logins_total", &[("user", "alice")]));
+    }
+}
@@ -0,0 +1,171 @@
+//! Pooled redis connections that are dropped once a command fails on the socket.
+//!
+//! A request sets its deadline as the read timeout of the connection it checks
+//! out. When a command times out its reply is still on the way, and a
+//! connection returned to the pool like that would hand it to the next caller
+//! as the reply to a different command. `r2d2_redis` only drops connections the
+//! server has closed, so every command goes through `RedisConnection`, which
+//! marks the connection broken on any I/O error, timeouts included, and the
+//! pool closes it instead of reusing it.
+
+use r2d2_redis::{r2d2, redis, RedisConnectionManager};
+use redis::{ConnectionLike, RedisResult, Value};
+use std::time::Duration;
+
+/// `RedisConnectionManager` handing out `RedisConnection`s.
+#[derive(Debug)]
+pub struct RedisManager(RedisConnectionManager);
+
+impl RedisManager {
+    /// See `RedisConnectionManager::new`.
+    pub fn new<T: redis::IntoConnectionInfo>(params: T) -> RedisResult<Self> {
+        RedisConnectionManager::new(params).map(RedisManager)
+    }
+}
+
+impl r2d2::ManageConnection for RedisManager {
+    type Connection = RedisConnection;
+    type Error = r2d2_redis::Error;
+
+    fn connect(&self) -> Result<RedisConnection, Self::Error> {
+        Ok(RedisConnection {
+            conn: self.0.connect()?,
+            broken: false,
+        })
+    }
+
+    fn is_valid(&self, conn: &mut RedisConnection) -> Result<(), Self::Error> {
+        redis::cmd("PING")
+            .query(conn)
+            .map_err(r2d2_redis::Error::Other)
+    }
+
+    fn has_broken(&self, conn: &mut RedisConnection) -> bool {
+        conn.broken || self.0.has_broken(&mut conn.conn)
+    }
+}
+
+/// A redis connection that remembers whether a command failed on the socket,
+/// after which replies may no longer match the commands they answer.
+pub struct RedisConnection {
+    conn: redis::Connection,
+    broken: bool,
+}
+
+impl RedisConnection {
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> RedisResult<()> {
+        self.conn.set_read_timeout(dur)
+    }
+
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> RedisResult<()> {
+        self.conn.set_write_timeout(dur)
+    }
+
+    fn checked<T>(&mut self, result: RedisResult<T>) -> RedisResult<T> {
+        if let Err(err) = &result {
+            if err.is_io_error() {
+                self.broken = true;
+            }
+        }
+        result
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        let result = self.conn.req_packed_command(cmd);
+        self.checked(result)
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        let result = self.conn.req_packed_commands(cmd, offset, count);
+        self.checked(result)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.get_db()
+    }
+
+    fn check_connection(&mut self) -> bool {
+        redis::cmd("PING").query::<String>(self).is_ok()
+    }
+
+    fn is_open(&self) -> bool {
+        !self.broken && self.conn.is_open()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Serves `GET <key>` with the key as the value, and with `delay` before
+    /// answering `GET slow`.
+    fn fake_redis(delay: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                std::thread::spawn(move || {
+                    let mut writer = stream.try_clone().unwrap();
+                    let mut lines = BufReader::new(stream).lines();
+                    // commands come as arrays of bulk strings, keep the strings
+                    while let Some(Ok(header)) = lines.next() {
+                        let count: usize = header[1..].parse().unwrap();
+                        let args: Vec<String> = (0..count)
+                            .map(|_| {
+                                lines.next();
+                                lines.next().unwrap().unwrap()
+                            })
+                            .collect();
+                        let reply = match args[0].to_uppercase().as_str() {
+                            "PING" => "+PONG\r\n".to_owned(),
+                            "GET" => {
+                                if args[1] == "slow" {
+                                    std::thread::sleep(delay);
+                                }
+                                format!("${}\r\n{}\r\n", args[1].len(), args[1])
+                            }
+                            _ => "-ERR unknown command\r\n".to_owned(),
+                        };
+                        if writer.write_all(reply.as_bytes()).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        format!("redis://{}/", addr)
+    }
+
+    #[test]
+    fn timed_out_connection_is_not_reused() {
+        let manager = RedisManager::new(fake_redis(Duration::from_millis(200))).unwrap();
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .test_on_check_out(true)
+            .build(manager)
+            .unwrap();
+
+        let mut conn = pool.get().unwrap();
+        conn.set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let slow = redis::cmd("GET").arg("slow").query::<String>(&mut *conn);
+        assert!(slow.unwrap_err().is_timeout());
+        drop(conn);
+        // let the late reply arrive, where a reused connection would read it
+        std::thread::sleep(Duration::from_millis(300));
+
+        let mut conn = pool.get().unwrap();
+        let value: String = redis::cmd("GET").arg("mine").query(&mut *conn).unwrap();
+        assert_eq!(value, "mine");
+    }
+}
@@ -0,0 +1,31 @@
+//! Issues the calls of a `CAPTURE_FILE` again: `auth-replay <file> <url>`.
+
+use auth::auth::auth_client::AuthClient;
+use auth::capture::{self, Captured};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let (path, url) = match (args.next(), args.next(), args.next()) {
+        (Some(path), Some(url), None) => (path, url),
+        _ => return Err("usage: auth-replay <capture file> <url>".into()),
+    };
+    let captures = std::fs::read_to_string(&path)?;
+    let mut client = AuthClient::connect(url).await?;
+    for (index, line) in captures.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = match Captured::parse(line) {
+            Ok(captured) => capture::replay(&mut client, &captured)
+                .await
+                .map(|code| format!("{} {:?}", captured.method, code)),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(outcome) => println!("{}: {}", index + 1, outcome),
+            Err(err) => println!("{}: {}", index + 1, err),
+        }
+    }
+    Ok(())
+}
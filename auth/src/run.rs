@@ -0,0 +1,408 @@
+//! The `auth` binary: the tokio runtime, tracing and metrics, and the servers
+//! `run` starts from the loaded `Config` and stops on SIGINT or SIGTERM.
+
+use crate::config::Config;
+use crate::events::{Event, EventSink, NoopEvents};
+use crate::metrics::{Metrics, NoopMetrics, PrometheusMetrics};
+use crate::{
+    access_log, admin, audit, config, connections, install_panic_hook, metrics, propagator,
+    redis_pool, revoke_all_sessions, revoke_sessions, AuthService, APPLICATION_ID,
+};
+use opentelemetry::sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::{TraceError, Tracer, TracerProvider};
+use opentelemetry::{global, KeyValue};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tonic::transport::Server;
+
+/// Resolves on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    println!("shutting down");
+}
+
+/// Resource attributes of the exported traces and metrics.
+fn otel_resource() -> Resource {
+    Resource::new([
+        KeyValue::new("service.name", APPLICATION_ID),
+        KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+    ])
+}
+
+/// Installs the jaeger pipeline, exporting only failed requests with
+/// `errors_only` as `TRACE_ERRORS_ONLY` asks.
+fn tracing_init(errors_only: bool) -> Result<impl Tracer, TraceError> {
+    global::set_text_map_propagator(propagator());
+    let pipeline = opentelemetry_jaeger::new_agent_pipeline()
+        .with_service_name(APPLICATION_ID)
+        .with_trace_config(opentelemetry::sdk::trace::config().with_resource(otel_resource()));
+    if !errors_only {
+        return pipeline.install_simple();
+    }
+    println!("exporting spans of failed requests only");
+    let provider = opentelemetry::sdk::trace::TracerProvider::builder()
+        .with_simple_exporter(ErrorsOnly(pipeline.build_sync_agent_exporter()?))
+        .build();
+    let tracer = provider.versioned_tracer(APPLICATION_ID, Some(env!("CARGO_PKG_VERSION")), None);
+    global::set_tracer_provider(provider);
+    Ok(tracer)
+}
+
+/// Exporter that drops every span not marked as failed by the `error` attribute
+/// or an error status, so failures are traced without exporting all requests.
+#[derive(Debug)]
+struct ErrorsOnly<E>(E);
+
+impl<E: SpanExporter> SpanExporter for ErrorsOnly<E> {
+    fn export(
+        &mut self,
+        mut batch: Vec<SpanData>,
+    ) -> std::pin::Pin<Box<dyn Future<Output = ExportResult> + Send>> {
+        let error = opentelemetry::Key::new("error");
+        batch.retain(|span| {
+            matches!(span.status, opentelemetry::trace::Status::Error { .. })
+                || span.attributes.get(&error) == Some(&opentelemetry::Value::Bool(true))
+        });
+        self.0.export(batch)
+    }
+
+    fn shutdown(&mut self) {
+        self.0.shutdown()
+    }
+}
+
+/// Runs the server until SIGINT or SIGTERM, configured by the settings file,
+/// environment and flags as described in `config`. Its runtime has
+/// `RUNTIME_WORKER_THREADS` worker threads, one per CPU by default, and at most
+/// `RUNTIME_MAX_BLOCKING_THREADS` threads for blocking work such as password
+/// hashing and scans, tokio's 512 by default.
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    install_panic_hook();
+    println!("start");
+    let config = Config::load()?;
+    let workers = config
+        .runtime_worker_threads
+        .filter(|&workers| workers > 0)
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let max_blocking = config
+        .runtime_max_blocking_threads
+        .filter(|&max| max > 0)
+        .unwrap_or(512);
+    println!(
+        "runtime: {} worker threads, at most {} blocking threads",
+        workers, max_blocking
+    );
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(workers)
+        .max_blocking_threads(max_blocking)
+        .enable_all()
+        .build()?
+        .block_on(serve(config, started))
+}
+
+async fn serve(config: Config, started: Instant) -> Result<(), Box<dyn std::error::Error>> {
+    // without a tracer spans are not exported, which must not keep auth down
+    let _tracer = match tracing_init(config.trace_errors_only) {
+        Ok(tracer) => {
+            startup_step("tracing", started);
+            Some(tracer)
+        }
+        Err(err) if !config.require_tracing => {
+            println!(
+                "WARNING: tracing disabled, failed to initialize tracer: {}",
+                err
+            );
+            None
+        }
+        Err(err) => return Err(format!("failed to initialize tracer: {}", err).into()),
+    };
+    let addr = "127.0.0.1:50051".parse()?;
+    let session_db = config.redis_session_db.unwrap_or_default();
+    let aux_db = config.redis_aux_db.unwrap_or_default();
+    let pool = redis_pool(&config, session_db);
+    let aux_pool = if aux_db == session_db {
+        pool.clone()
+    } else {
+        redis_pool(&config, aux_db)
+    };
+    println!(
+        "redis pool warmed with {} idle connections",
+        pool.state().idle_connections
+    );
+    startup_step("redis", started);
+    let metrics: Arc<dyn Metrics> = match config.metrics_addr {
+        Some(_) => match config.metrics_backend {
+            None | Some(config::MetricsBackend::Prometheus) => {
+                Arc::new(PrometheusMetrics::default())
+            }
+            Some(config::MetricsBackend::Otel) => Arc::new(
+                metrics::otel_pipeline(otel_resource())
+                    .map_err(|err| format!("failed to initialize metrics: {}", err))?,
+            ),
+        },
+        None => Arc::new(NoopMetrics),
+    };
+    let events: Arc<dyn EventSink> = match &config.events_nats_addr {
+        #[cfg(feature = "nats")]
+        Some(addr) => Arc::new(crate::events::NatsEvents::spawn(
+            addr.clone(),
+            config
+                .events_subject_prefix
+                .clone()
+                .unwrap_or_else(|| "auth".to_owned()),
+        )),
+        #[cfg(not(feature = "nats"))]
+        Some(_) => return Err("EVENTS_NATS_ADDR requires the nats cargo feature".into()),
+        None => Arc::new(NoopEvents),
+    };
+    let auth = AuthService::builder(pool)
+        .config(config.clone())
+        .aux_pool(aux_pool)
+        .metrics(metrics.clone())
+        .events(events.clone())
+        .build();
+    if let Some(metrics_addr) = config.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_addr).await {
+                println!("metrics server failed: {}", err);
+            }
+        });
+        println!("serving metrics on address {}", metrics_addr);
+        auth.spawn_session_count_task(Duration::from_secs(
+            config.session_count_interval_seconds.unwrap_or(60),
+        ));
+    }
+    if let Some(key) = &config.audit_chain_key {
+        audit::spawn_checkpoints(
+            key.expose().as_bytes().to_vec(),
+            Duration::from_secs(
+                config
+                    .audit_checkpoint_interval_seconds
+                    .filter(|&secs| secs > 0)
+                    .unwrap_or(3600),
+            ),
+        );
+    }
+    match config.index_prune_interval_seconds.unwrap_or(3600) {
+        0 => {}
+        interval => auth.spawn_index_pruning(
+            Duration::from_secs(interval),
+            config
+                .index_prune_batch_size
+                .filter(|&batch| batch > 0)
+                .unwrap_or(100),
+        ),
+    }
+    match config.redis_eviction_check_interval_seconds.unwrap_or(300) {
+        0 => {}
+        interval => auth.spawn_eviction_probe(Duration::from_secs(interval)),
+    }
+    let admin_grpc_addr = admin_addr(config.admin_grpc_addr, config.admin_allow_remote)?;
+    if let Some(admin_addr) = admin_addr(config.admin_addr, config.admin_allow_remote)? {
+        let config_summary = auth.config_summary();
+        let (pool, epoch_key, clock) = (
+            auth.pool.clone(),
+            auth.session_epoch_key(),
+            auth.clock.clone(),
+        );
+        let api_key = config
+            .api_key
+            .clone()
+            .filter(|key| !key.expose().is_empty());
+        let revoke_all: admin::RevokeAll = Arc::new(move || {
+            let epoch = revoke_all_sessions(&pool, &epoch_key, clock.now())
+                .map_err(|err| err.to_string())?;
+            events.emit(Event::SessionsRevoked {
+                user: None,
+                reason: "revoke_all",
+            });
+            Ok(epoch)
+        });
+        tokio::spawn(async move {
+            if let Err(err) = admin::serve(admin_addr, config_summary, revoke_all, api_key).await {
+                println!("admin server failed: {}", err);
+            }
+        });
+        println!("serving admin endpoint on address {}", admin_addr);
+    }
+    if auth.require_tls_for_login && !cfg!(feature = "tls") {
+        println!("WARNING: REQUIRE_TLS_FOR_LOGIN is set but TLS is not compiled in, every login is refused");
+    }
+    let disabled_users = auth.users.disabled_users();
+    if !disabled_users.is_empty() {
+        let pool = auth.pool.clone();
+        let keys: Vec<String> = disabled_users
+            .iter()
+            .map(|user| auth.user_sessions_key(user))
+            .collect();
+        let revoked = tokio::task::spawn_blocking(move || {
+            keys.iter()
+                .map(|key| revoke_sessions(&pool, key))
+                .sum::<Result<usize, _>>()
+        })
+        .await?;
+        match revoked {
+            Ok(revoked) => {
+                println!(
+                    "revoked {} sessions of {} disabled accounts",
+                    revoked,
+                    disabled_users.len()
+                );
+                for user in &disabled_users {
+                    auth.events.emit(Event::SessionsRevoked {
+                        user: Some(user.clone()),
+                        reason: "account_disabled",
+                    });
+                }
+            }
+            Err(err) => println!("failed to revoke sessions of disabled accounts: {}", err),
+        }
+        startup_step("revoke_disabled", started);
+    }
+    let revoke_on_shutdown = auth
+        .revoke_sessions_on_shutdown
+        .then(|| (auth.pool.clone(), auth.instance_sessions_key()));
+    let (auth_service, admin_service) = auth.into_services()?;
+
+    #[cfg(feature = "tls")]
+    let tls = crate::tls::server_config(&config)?;
+    #[cfg(not(feature = "tls"))]
+    let tls: Option<std::convert::Infallible> = None;
+    let access_log = access_log::AccessLogLayer::from_config(&config);
+    // the admin server is layered and secured like the main one
+    if let Some(admin_addr) = admin_grpc_addr {
+        let admin = Server::builder()
+            .layer(access_log.clone())
+            .add_service(admin_service);
+        let incoming = tonic::transport::server::TcpIncoming::new(admin_addr, true, None)
+            .map_err(|err| format!("failed to listen on {}: {}", admin_addr, err))?;
+        match &tls {
+            #[cfg(feature = "tls")]
+            Some(tls) => {
+                let incoming = crate::tls::accept(incoming, tls.clone(), metrics.clone());
+                tokio::spawn(async move {
+                    if let Err(err) = admin.serve_with_incoming(incoming).await {
+                        println!("admin gRPC server failed: {}", err);
+                    }
+                });
+                println!(
+                    "serving admin gRPC service over TLS on address {}",
+                    admin_addr
+                );
+            }
+            #[cfg(not(feature = "tls"))]
+            Some(never) => match *never {},
+            None => {
+                tokio::spawn(async move {
+                    if let Err(err) = admin.serve_with_incoming(incoming).await {
+                        println!("admin gRPC server failed: {}", err);
+                    }
+                });
+                println!("serving admin gRPC service on address {}", admin_addr);
+            }
+        }
+    }
+
+    let mut builder = Server::builder().layer(access_log);
+    let router = builder.add_service(auth_service);
+    // bind before reporting readiness, so a taken address fails the start
+    let incoming = tonic::transport::server::TcpIncoming::new(addr, true, None)
+        .map_err(|err| format!("failed to listen on {}: {}", addr, err))?;
+    startup_step("listen", started);
+    metrics.set_gauge(
+        metrics::STARTUP_DURATION_MILLISECONDS,
+        &[],
+        started.elapsed().as_millis() as i64,
+    );
+    println!("server started on address {}", addr);
+    let max_connections = config.max_connections.filter(|&max| max > 0);
+    if let Some(max) = max_connections {
+        println!("accepting at most {} connections", max);
+    }
+    match (tls, max_connections) {
+        #[cfg(feature = "tls")]
+        (Some(tls), Some(max)) => {
+            println!("serving over TLS");
+            let limited = connections::limit(incoming, max, metrics.clone());
+            router
+                .serve_with_incoming_shutdown(
+                    crate::tls::accept(limited, tls, metrics),
+                    shutdown_signal(),
+                )
+                .await?
+        }
+        #[cfg(feature = "tls")]
+        (Some(tls), None) => {
+            println!("serving over TLS");
+            router
+                .serve_with_incoming_shutdown(
+                    crate::tls::accept(incoming, tls, metrics),
+                    shutdown_signal(),
+                )
+                .await?
+        }
+        #[cfg(not(feature = "tls"))]
+        (Some(never), _) => match never {},
+        (None, Some(max)) => {
+            router
+                .serve_with_incoming_shutdown(
+                    connections::limit(incoming, max, metrics),
+                    shutdown_signal(),
+                )
+                .await?
+        }
+        (None, None) => {
+            router
+                .serve_with_incoming_shutdown(incoming, shutdown_signal())
+                .await?
+        }
+    }
+
+    if let Some((pool, key)) = revoke_on_shutdown {
+        match tokio::task::spawn_blocking(move || revoke_sessions(&pool, &key)).await? {
+            Ok(revoked) => println!("revoked {} sessions of this instance", revoked),
+            Err(err) => println!("failed to revoke sessions: {}", err),
+        }
+    }
+
+    println!("server stopped");
+
+    Ok(())
+}
+
+/// The admin address `addr`. It must be a loopback address unless
+/// `allow_remote` as by `ADMIN_ALLOW_REMOTE`, so admin operations aren't
+/// exposed by accident.
+fn admin_addr(
+    addr: Option<std::net::SocketAddr>,
+    allow_remote: bool,
+) -> Result<Option<std::net::SocketAddr>, String> {
+    let addr = match addr {
+        Some(addr) => addr,
+        None => return Ok(None),
+    };
+    if !addr.ip().is_loopback() && !allow_remote {
+        return Err(format!(
+            "admin address {} is not a loopback address, set ADMIN_ALLOW_REMOTE to allow it",
+            addr
+        ));
+    }
+    Ok(Some(addr))
+}
+
+/// Logs that a startup step has completed, with the time since `started`.
+fn startup_step(step: &str, started: Instant) {
+    println!(
+        "startup step={} elapsed_ms={}",
+        step,
+        started.elapsed().as_millis()
+    );
+}
@@ -0,0 +1,149 @@
+//! Lua scripts for the redis updates that must read and write several keys
+//! atomically. Each documents the KEYS and ARGV it takes and what it returns.
+
+use once_cell::sync::Lazy;
+use r2d2_redis::redis;
+
+/// Raises the session epoch to ARGV[1] unless it is already later, so clocks
+/// differing between instances can't bring back revoked sessions. Returns the
+/// resulting epoch.
+///
+/// KEYS: session epoch.
+pub static RAISE_SESSION_EPOCH: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        local epoch = tonumber(redis.call('GET', KEYS[1]) or '0')
+        if tonumber(ARGV[1]) > epoch then
+            redis.call('SET', KEYS[1], ARGV[1])
+            return tonumber(ARGV[1])
+        end
+        return epoch
+        ",
+    )
+});
+
+/// Drops the tokens that no longer exist from a per-user session index and
+/// returns how many remain.
+///
+/// KEYS: per-user session index.
+pub static COUNT_SESSIONS: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        for _, token in ipairs(redis.call('ZRANGE', KEYS[1], 0, -1)) do
+            if redis.call('EXISTS', token) == 0 then
+                redis.call('ZREM', KEYS[1], token)
+            end
+        end
+        return redis.call('ZCARD', KEYS[1])
+        ",
+    )
+});
+
+/// Stores a new session and indexes it under its user in one atomic step,
+/// evicting the user's oldest sessions of the same class beyond the class's
+/// maximum. Returns 1, or 0 without touching anything if the token is already
+/// taken.
+///
+/// KEYS: token, per-user session index, per-user index of the class, optionally
+/// the per-instance token index.
+/// ARGV: session data, TTL, login time, max sessions of the class (0 is unlimited).
+pub static CREATE_SESSION: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        if not redis.call('SET', KEYS[1], ARGV[1], 'NX', 'EX', ARGV[2]) then
+            return 0
+        end
+        -- classes differ in TTL, so an index only ever has its expiry extended
+        for i = 2, 3 do
+            redis.call('ZADD', KEYS[i], ARGV[3], KEYS[1])
+            if redis.call('TTL', KEYS[i]) < tonumber(ARGV[2]) then
+                redis.call('EXPIRE', KEYS[i], ARGV[2])
+            end
+        end
+        if KEYS[4] then
+            redis.call('ZADD', KEYS[4], ARGV[3] + ARGV[2], KEYS[1])
+            redis.call('ZREMRANGEBYSCORE', KEYS[4], '-inf', ARGV[3])
+            redis.call('EXPIRE', KEYS[4], ARGV[2])
+        end
+        local max = tonumber(ARGV[4])
+        if max > 0 then
+            local excess = redis.call('ZCARD', KEYS[3]) - max
+            if excess > 0 then
+                for _, token in ipairs(redis.call('ZRANGE', KEYS[3], 0, excess - 1)) do
+                    redis.call('DEL', token)
+                    redis.call('ZREM', KEYS[2], token)
+                end
+                redis.call('ZREMRANGEBYRANK', KEYS[3], 0, excess - 1)
+            end
+        end
+        return 1
+        ",
+    )
+});
+
+/// Extends a token to ARGV[1] seconds if fewer than ARGV[2] remain, along with
+/// its indexes and expiry marker. Returns 1, or 0 without touching anything if
+/// the token is gone or has enough time left.
+///
+/// KEYS: token, per-user session index, per-user index of the class, the
+/// per-instance token index, the expiry marker.
+/// ARGV: TTL, threshold, current time, expiry marker grace (0 without marker),
+/// 1 if the per-instance index is kept.
+pub static EXTEND_SESSION: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        local remaining = redis.call('TTL', KEYS[1])
+        if remaining < 0 or remaining >= tonumber(ARGV[2]) then
+            return 0
+        end
+        redis.call('EXPIRE', KEYS[1], ARGV[1])
+        for i = 2, 3 do
+            if redis.call('TTL', KEYS[i]) < tonumber(ARGV[1]) then
+                redis.call('EXPIRE', KEYS[i], ARGV[1])
+            end
+        end
+        if ARGV[5] == '1' then
+            redis.call('ZADD', KEYS[4], ARGV[3] + ARGV[1], KEYS[1])
+            if redis.call('TTL', KEYS[4]) < tonumber(ARGV[1]) then
+                redis.call('EXPIRE', KEYS[4], ARGV[1])
+            end
+        end
+        if tonumber(ARGV[4]) > 0 then
+            redis.call('SET', KEYS[5], 1, 'EX', ARGV[1] + ARGV[4])
+        end
+        return 1
+        ",
+    )
+});
+
+/// Moves a session stored under a legacy token key to its current key, along
+/// with its index entries and expiry marker; the TTL moves with the key.
+/// Returns 1 if the session is now under the current key, 0 if it's gone.
+///
+/// KEYS: legacy key, current key, per-user session index, per-user index of
+/// the class, the per-instance token index, the expiry markers of both keys.
+pub static MIGRATE_TOKEN_KEY: Lazy<redis::Script> = Lazy::new(|| {
+    redis::Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 0 then
+            return redis.call('EXISTS', KEYS[2])
+        end
+        if redis.call('RENAMENX', KEYS[1], KEYS[2]) == 0 then
+            -- already migrated by a concurrent validation
+            redis.call('DEL', KEYS[1])
+            return 1
+        end
+        for i = 3, 5 do
+            local score = redis.call('ZSCORE', KEYS[i], KEYS[1])
+            if score then
+                redis.call('ZREM', KEYS[i], KEYS[1])
+                redis.call('ZADD', KEYS[i], score, KEYS[2])
+            end
+        end
+        if redis.call('EXISTS', KEYS[6]) == 1 then
+            redis.call('RENAME', KEYS[6], KEYS[7])
+        end
+        return 1
+        ",
+    )
+});
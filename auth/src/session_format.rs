@@ -0,0 +1,411 @@
+//! Encodings of the session data stored as the value of a token.
+//!
+//! Values start with the `VERSION` of their layout, followed by a byte naming
+//! the encoding. `SESSION_FORMAT` picks the encoding of new sessions; reading
+//! doesn't depend on it, so sessions written before a format change stay
+//! readable until they expire. Values without a version byte are the colon
+//! separated format of earlier releases, which starts with a hex digit of the
+//! session id. Any other first byte is a version this release doesn't know.
+
+use crate::Session;
+use std::fmt;
+use std::net::Ipv6Addr;
+
+/// Layout version of newly written values. Bump it when the fields change in a
+/// way older releases would misread.
+pub const VERSION: u8 = 1;
+
+/// Most scopes a session holds; the binary layout counts them in one byte.
+pub const MAX_SCOPES: usize = u8::MAX as usize;
+
+const JSON_TAG: u8 = b'j';
+const BINARY_TAG: u8 = b'b';
+
+/// Why a stored value can't be read.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Written by a release with another layout version; it can't be trusted to
+    /// mean the same and should be dropped.
+    UnknownVersion(u8),
+    /// In the colon separated format of earlier releases but not parseable as
+    /// one, such as a bare session id; like an unknown version it is dropped.
+    Legacy,
+    /// The version is known but the value doesn't parse, which is a bug.
+    Malformed,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownVersion(version) => {
+                write!(f, "session stored with unknown version {}", version)
+            }
+            DecodeError::Legacy => f.write_str("unreadable session of an earlier release"),
+            DecodeError::Malformed => f.write_str("malformed session data"),
+        }
+    }
+}
+
+/// How new sessions are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionFormat {
+    /// A JSON object, readable with `redis-cli GET`.
+    Json,
+    /// Length prefixed fields, about half the size of JSON.
+    Binary,
+}
+
+impl SessionFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            SessionFormat::Json => "json",
+            SessionFormat::Binary => "binary",
+        }
+    }
+
+    pub(crate) fn encode(self, session: &Session) -> Vec<u8> {
+        let mut out = vec![VERSION];
+        match self {
+            SessionFormat::Json => {
+                out.push(JSON_TAG);
+                out.extend(encode_json(session));
+            }
+            SessionFormat::Binary => {
+                out.push(BINARY_TAG);
+                encode_binary(session, &mut out);
+            }
+        }
+        out
+    }
+}
+
+/// Decodes a session written in any of the formats and versions this release
+/// knows.
+pub fn decode(value: &[u8]) -> Result<Session, DecodeError> {
+    let session = match value {
+        [VERSION, JSON_TAG, payload @ ..] => decode_json(payload),
+        [VERSION, BINARY_TAG, payload @ ..] => decode_binary(payload),
+        [VERSION, ..] | [] => None,
+        [first, ..] if first.is_ascii_hexdigit() => {
+            return std::str::from_utf8(value)
+                .ok()
+                .and_then(decode_legacy)
+                .ok_or(DecodeError::Legacy)
+        }
+        [version, ..] => return Err(DecodeError::UnknownVersion(*version)),
+    };
+    session.ok_or(DecodeError::Malformed)
+}
+
+fn encode_json(session: &Session) -> Vec<u8> {
+    let value = serde_json::json!({
+        "session_id": session.session_id,
+        "login_at": session.login_at,
+        "login_micros": session.login_micros,
+        "not_before": session.not_before,
+        "ttl": session.ttl,
+        "client_ip": session.client_ip.map(|ip| ip.to_string()),
+        "one_time": session.one_time,
+        "scopes": session.scopes,
+        "class": session.class,
+        "user": session.user,
+        "parent": session.parent,
+    });
+    serde_json::to_vec(&value).expect("a JSON value always serializes")
+}
+
+fn decode_json(value: &[u8]) -> Option<Session> {
+    let value: serde_json::Value = serde_json::from_slice(value).ok()?;
+    let string = |field: &str| value.get(field)?.as_str().map(str::to_owned);
+    Some(Session {
+        session_id: string("session_id")?,
+        login_at: value.get("login_at")?.as_u64()?,
+        // absent in values written before revocation compared them
+        login_micros: match value.get("login_micros") {
+            None => 0,
+            Some(micros) => u32::try_from(micros.as_u64()?).ok()?,
+        },
+        // absent in values written before not-before times existed
+        not_before: match value.get("not_before") {
+            None => 0,
+            Some(not_before) => not_before.as_u64()?,
+        },
+        // absent in values written before sessions could be extended
+        ttl: match value.get("ttl") {
+            None => 0,
+            Some(ttl) => ttl.as_u64()?,
+        },
+        client_ip: match value.get("client_ip") {
+            None => None,
+            Some(ip) if ip.is_null() => None,
+            Some(ip) => Some(ip.as_str()?.parse().ok()?),
+        },
+        one_time: value.get("one_time")?.as_bool()?,
+        scopes: value
+            .get("scopes")?
+            .as_array()?
+            .iter()
+            .map(|scope| scope.as_str().map(str::to_owned))
+            .collect::<Option<_>>()?,
+        class: string("class")?,
+        user: string("user")?,
+        // absent in values written before tokens could be exchanged
+        parent: match value.get("parent") {
+            None => String::new(),
+            Some(parent) => parent.as_str()?.to_owned(),
+        },
+    })
+}
+
+/// The login time as 8 bytes big endian, a flags byte (bit 0 one-time,
+/// bit 1 bound to an address, bit 2 not valid before a later time, bit 3 with
+/// a granted TTL, bit 4 exchanged from a parent token, bit 5 with the login's
+/// microseconds), the 16 address bytes if bound, the not-before time and the
+/// TTL as 8 bytes big endian each if set, the microseconds as 4 bytes big
+/// endian if set, then the session id, class, user and scopes as strings with a 2 byte length,
+/// the scopes preceded by their count as one byte, and the parent token key as
+/// such a string if exchanged.
+fn encode_binary(session: &Session, out: &mut Vec<u8>) {
+    out.extend_from_slice(&session.login_at.to_be_bytes());
+    out.push(
+        session.one_time as u8
+            | (session.client_ip.is_some() as u8) << 1
+            | ((session.not_before != 0) as u8) << 2
+            | ((session.ttl != 0) as u8) << 3
+            | (!session.parent.is_empty() as u8) << 4
+            | ((session.login_micros != 0) as u8) << 5,
+    );
+    if let Some(ip) = session.client_ip {
+        out.extend_from_slice(&ip.octets());
+    }
+    if session.not_before != 0 {
+        out.extend_from_slice(&session.not_before.to_be_bytes());
+    }
+    if session.ttl != 0 {
+        out.extend_from_slice(&session.ttl.to_be_bytes());
+    }
+    if session.login_micros != 0 {
+        out.extend_from_slice(&session.login_micros.to_be_bytes());
+    }
+    let string = |out: &mut Vec<u8>, value: &str| {
+        // fields are short, a longer value would be a bug elsewhere
+        let len = u16::try_from(value.len()).expect("session field longer than 64 KiB");
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(value.as_bytes());
+    };
+    string(out, &session.session_id);
+    string(out, &session.class);
+    string(out, &session.user);
+    // requests with more than `MAX_SCOPES` are refused
+    out.push(u8::try_from(session.scopes.len()).expect("more than MAX_SCOPES scopes"));
+    for scope in &session.scopes {
+        string(out, scope);
+    }
+    if !session.parent.is_empty() {
+        string(out, &session.parent);
+    }
+}
+
+fn decode_binary(mut value: &[u8]) -> Option<Session> {
+    fn take<'a>(value: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        if value.len() < len {
+            return None;
+        }
+        let (taken, rest) = value.split_at(len);
+        *value = rest;
+        Some(taken)
+    }
+    fn string(value: &mut &[u8]) -> Option<String> {
+        let len = u16::from_be_bytes(take(value, 2)?.try_into().ok()?);
+        String::from_utf8(take(value, len.into())?.to_vec()).ok()
+    }
+
+    let login_at = u64::from_be_bytes(take(&mut value, 8)?.try_into().ok()?);
+    let flags = take(&mut value, 1)?[0];
+    let client_ip = if flags & 2 != 0 {
+        let octets: [u8; 16] = take(&mut value, 16)?.try_into().ok()?;
+        Some(Ipv6Addr::from(octets))
+    } else {
+        None
+    };
+    let not_before = if flags & 4 != 0 {
+        u64::from_be_bytes(take(&mut value, 8)?.try_into().ok()?)
+    } else {
+        0
+    };
+    let ttl = if flags & 8 != 0 {
+        u64::from_be_bytes(take(&mut value, 8)?.try_into().ok()?)
+    } else {
+        0
+    };
+    let login_micros = if flags & 32 != 0 {
+        u32::from_be_bytes(take(&mut value, 4)?.try_into().ok()?)
+    } else {
+        0
+    };
+    let session_id = string(&mut value)?;
+    let class = string(&mut value)?;
+    let user = string(&mut value)?;
+    let scopes = (0..take(&mut value, 1)?[0])
+        .map(|_| string(&mut value))
+        .collect::<Option<_>>()?;
+    let parent = if flags & 16 != 0 {
+        string(&mut value)?
+    } else {
+        String::new()
+    };
+    Some(Session {
+        session_id,
+        login_at,
+        login_micros,
+        not_before,
+        ttl,
+        client_ip,
+        one_time: flags & 1 != 0,
+        scopes,
+        class,
+        user,
+        parent,
+    })
+}
+
+/// `{sid}:{login_at}:{ip}:{one_time}:{scopes}:{class}:{user}`, with the address
+/// as a decimal number or empty.
+fn decode_legacy(value: &str) -> Option<Session> {
+    // user goes last as the only part that may contain the separator
+    let mut parts = value.splitn(7, ':');
+
+    Some(Session {
+        session_id: parts.next()?.to_owned(),
+        login_at: parts.next()?.parse().ok()?,
+        login_micros: 0,
+        not_before: 0,
+        ttl: 0,
+        client_ip: match parts.next()? {
+            "" => None,
+            ip => Some(Ipv6Addr::from(ip.parse::<u128>().ok()?)),
+        },
+        one_time: parts.next()? == "1",
+        scopes: parts
+            .next()?
+            .split(',')
+            .filter(|scope| !scope.is_empty())
+            .map(str::to_owned)
+            .collect(),
+        class: parts.next()?.to_owned(),
+        user: parts.next()?.to_owned(),
+        parent: String::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(scopes: usize) -> Session {
+        Session {
+            session_id: "3f2a9c41".to_owned(),
+            login_at: 1_700_000_000,
+            login_micros: 250_000,
+            not_before: 1_700_000_060,
+            ttl: 3600,
+            client_ip: Some("::ffff:192.0.2.1".parse().unwrap()),
+            one_time: true,
+            scopes: (0..scopes)
+                .map(|scope| format!("scope-{}", scope))
+                .collect(),
+            class: "web".to_owned(),
+            user: "user:with:colons".to_owned(),
+            parent: "parent-key".to_owned(),
+        }
+    }
+
+    #[test]
+    fn most_scopes_round_trip_in_both_formats() {
+        for format in [SessionFormat::Json, SessionFormat::Binary] {
+            let session = session(MAX_SCOPES);
+            let decoded = decode(&format.encode(&session)).unwrap();
+            assert_eq!(decoded, session, "{}", format.name());
+        }
+    }
+
+    #[test]
+    fn legacy_value_decodes() {
+        let value = b"3f2a9c41:1700000000:281473902969345:1:read,write:web:user:with:colons";
+
+        assert_eq!(
+            decode(value).unwrap(),
+            Session {
+                login_micros: 0,
+                not_before: 0,
+                ttl: 0,
+                scopes: vec!["read".to_owned(), "write".to_owned()],
+                parent: String::new(),
+                ..session(0)
+            }
+        );
+    }
+
+    #[test]
+    fn json_without_later_fields_decodes() {
+        let value = br#"{"session_id":"3f2a9c41","login_at":1700000000,"client_ip":null,"one_time":false,"scopes":[],"class":"web","user":"alice"}"#;
+
+        let session = decode(&[&[VERSION, JSON_TAG][..], value].concat()).unwrap();
+
+        assert_eq!(
+            (session.login_micros, session.not_before, session.ttl),
+            (0, 0, 0)
+        );
+        assert_eq!((session.client_ip, session.parent.as_str()), (None, ""));
+        assert_eq!(session.user, "alice");
+    }
+
+    #[test]
+    fn binary_value_without_optional_fields_decodes() {
+        let mut value = vec![VERSION, BINARY_TAG];
+        value.extend_from_slice(&1_700_000_000u64.to_be_bytes());
+        value.push(0);
+        for field in ["3f2a9c41", "web", "alice"] {
+            value.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            value.extend_from_slice(field.as_bytes());
+        }
+        value.push(0);
+
+        let session = decode(&value).unwrap();
+
+        assert_eq!(session.login_at, 1_700_000_000);
+        assert_eq!((session.client_ip, session.one_time), (None, false));
+        assert_eq!(
+            (session.class.as_str(), session.user.as_str()),
+            ("web", "alice")
+        );
+        assert!(session.scopes.is_empty());
+    }
+
+    #[test]
+    fn unknown_version_and_malformed_values_are_refused() {
+        assert!(matches!(
+            decode(b"\x02j{}"),
+            Err(DecodeError::UnknownVersion(2))
+        ));
+        assert!(matches!(
+            decode(&[VERSION, JSON_TAG, b'{']),
+            Err(DecodeError::Malformed)
+        ));
+        // cut off within the login time
+        assert!(matches!(
+            decode(&[VERSION, BINARY_TAG, 0, 0]),
+            Err(DecodeError::Malformed)
+        ));
+        assert!(matches!(
+            decode(&[VERSION, b'x']),
+            Err(DecodeError::Malformed)
+        ));
+        assert!(matches!(decode(b""), Err(DecodeError::Malformed)));
+    }
+
+    #[test]
+    fn unparseable_legacy_value_is_unreadable() {
+        assert!(matches!(decode(b"3f2a9c41"), Err(DecodeError::Legacy)));
+    }
+}
@@ -0,0 +1,280 @@
+//! Server TLS restricted to the configured versions, cipher suites and curves.
+//!
+//! tonic's `ServerTlsConfig` builds a rustls configuration with the rustls
+//! defaults and doesn't take one of our own, so the server builds it from
+//! `TLS_CERT_FILE` and `TLS_KEY_FILE` with `TLS_MIN_VERSION`,
+//! `TLS_CIPHER_SUITES` and `TLS_CURVES`, and does the handshakes on the stream
+//! of accepted connections. A client offering only weaker versions, suites or
+//! curves fails the handshake and never reaches the service.
+
+use crate::config::Config;
+use crate::metrics::{self, Metrics};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::{
+    self, ServerConfig, SupportedCipherSuite, SupportedKxGroup, SupportedProtocolVersion,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::server::{Connected, TcpConnectInfo};
+
+/// Longest a client may take to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections past their handshake that the server hasn't taken yet.
+const QUEUE: usize = 64;
+
+/// Versions as named in `TLS_MIN_VERSION`, oldest first.
+static VERSIONS: [(&str, &SupportedProtocolVersion); 2] = [
+    ("1.2", &rustls::version::TLS12),
+    ("1.3", &rustls::version::TLS13),
+];
+
+/// The rustls configuration of the server, `None` unless `TLS_CERT_FILE` and
+/// `TLS_KEY_FILE` are both set. An unknown version, suite or curve name, or a
+/// policy leaving no suite for the allowed versions, fails the start.
+pub(crate) fn server_config(
+    config: &Config,
+) -> Result<Option<Arc<ServerConfig>>, Box<dyn std::error::Error>> {
+    let (cert, key) = match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(cert), Some(key)) => (std::fs::read(cert)?, std::fs::read(key)?),
+        _ => return Ok(None),
+    };
+    let min_version = config.tls_min_version.as_deref().unwrap_or("1.2");
+    let oldest = VERSIONS
+        .iter()
+        .position(|(name, _)| *name == min_version)
+        .ok_or_else(|| {
+            format!(
+                "TLS_MIN_VERSION names unknown version {}, expected one of {}",
+                min_version,
+                VERSIONS
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })?;
+    let versions = VERSIONS[oldest..]
+        .iter()
+        .map(|(_, version)| *version)
+        .collect::<Vec<_>>();
+    let suites = allowed(
+        "TLS_CIPHER_SUITES",
+        config.tls_cipher_suites.as_deref(),
+        rustls::ALL_CIPHER_SUITES,
+        |suite: &SupportedCipherSuite| format!("{:?}", suite.suite()),
+    )?
+    .into_iter()
+    .filter(|suite| {
+        versions
+            .iter()
+            .any(|version| version.version == suite.version().version)
+    })
+    .collect::<Vec<_>>();
+    if suites.is_empty() {
+        return Err(format!(
+            "TLS_CIPHER_SUITES allows no cipher suite of TLS {} or later",
+            min_version
+        )
+        .into());
+    }
+    let curves = allowed(
+        "TLS_CURVES",
+        config.tls_curves.as_deref(),
+        &rustls::ALL_KX_GROUPS,
+        |group: &&SupportedKxGroup| format!("{:?}", group.name),
+    )?;
+
+    let certs = rustls_pemfile::certs(&mut &cert[..])?;
+    if certs.is_empty() {
+        return Err("TLS_CERT_FILE holds no PEM certificate".into());
+    }
+    let mut config = ServerConfig::builder()
+        .with_cipher_suites(&suites)
+        .with_kx_groups(&curves)
+        .with_protocol_versions(&versions)?
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(rustls::Certificate).collect(),
+            private_key(&key)?,
+        )?;
+    config.alpn_protocols = vec![b"h2".to_vec()];
+
+    println!(
+        "TLS policy: TLS {} or later, cipher suites {}, curves {}",
+        min_version,
+        suites
+            .iter()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .collect::<Vec<_>>()
+            .join(", "),
+        curves
+            .iter()
+            .map(|group| format!("{:?}", group.name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Ok(Some(Arc::new(config)))
+}
+
+/// The `items` named in the comma separated `names` of `setting`, in its order,
+/// or all of them when it is unset. Names are matched regardless of case.
+fn allowed<T: Clone>(
+    setting: &str,
+    names: Option<&str>,
+    items: &[T],
+    name: impl Fn(&T) -> String,
+) -> Result<Vec<T>, String> {
+    let names = match names {
+        Some(names) => names,
+        None => return Ok(items.to_vec()),
+    };
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|wanted| !wanted.is_empty())
+        .map(|wanted| {
+            items
+                .iter()
+                .find(|item| name(item).eq_ignore_ascii_case(wanted))
+                .cloned()
+                .ok_or_else(|| {
+                    format!(
+                        "{} names unknown {}, expected one of {}",
+                        setting,
+                        wanted,
+                        items.iter().map(&name).collect::<Vec<_>>().join(", ")
+                    )
+                })
+        })
+        .collect()
+}
+
+/// The PKCS #8 or RSA private key in `pem`, as tonic reads `Identity::from_pem`.
+fn private_key(pem: &[u8]) -> Result<rustls::PrivateKey, Box<dyn std::error::Error>> {
+    let key = match rustls_pemfile::pkcs8_private_keys(&mut &pem[..])?.pop() {
+        Some(key) => key,
+        None => rustls_pemfile::rsa_private_keys(&mut &pem[..])?
+            .pop()
+            .ok_or("TLS_KEY_FILE holds no PKCS #8 or RSA private key")?,
+    };
+    Ok(rustls::PrivateKey(key))
+}
+
+/// Does the TLS handshake of each connection of `incoming` with `config`, in a
+/// task per connection so a slow client doesn't hold up the others. Failed
+/// handshakes are logged with the peer and counted in
+/// `TLS_HANDSHAKE_FAILURES_TOTAL`.
+pub(crate) fn accept<IO>(
+    incoming: impl Stream<Item = io::Result<IO>> + Send + 'static,
+    config: Arc<ServerConfig>,
+    sink: Arc<dyn Metrics>,
+) -> impl Stream<Item = io::Result<TlsStream<IO>>>
+where
+    IO: AsyncRead + AsyncWrite + Connected<ConnectInfo = TcpConnectInfo> + Unpin + Send + 'static,
+{
+    let (sender, handshaken) = mpsc::channel(QUEUE);
+    let acceptor = TlsAcceptor::from(config);
+    tokio::spawn(async move {
+        tokio::pin!(incoming);
+        loop {
+            let conn = tokio::select! {
+                conn = incoming.next() => conn,
+                // the server stopped taking connections
+                _ = sender.closed() => return,
+            };
+            match conn {
+                Some(Ok(conn)) => {
+                    let acceptor = acceptor.clone();
+                    let sender = sender.clone();
+                    let sink = sink.clone();
+                    tokio::spawn(async move {
+                        let peer = conn
+                            .connect_info()
+                            .remote_addr()
+                            .map_or_else(|| "unknown".to_owned(), |addr| addr.to_string());
+                        let failure =
+                            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(conn))
+                                .await
+                            {
+                                Ok(Ok(stream)) => {
+                                    let _ = sender.send(Ok(stream)).await;
+                                    return;
+                                }
+                                Ok(Err(err)) => err.to_string(),
+                                Err(_) => "timed out".to_owned(),
+                            };
+                        println!("TLS handshake with {} failed: {}", peer, failure);
+                        sink.increment_counter(metrics::TLS_HANDSHAKE_FAILURES_TOTAL, &[]);
+                    });
+                }
+                Some(Err(err)) => {
+                    if sender.send(Err(err)).await.is_err() {
+                        return;
+                    }
+                }
+                None => return,
+            }
+        }
+    });
+    ReceiverStream::new(handshaken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerName};
+    use tokio_rustls::TlsConnector;
+
+    const DATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/tls");
+
+    fn config(min_version: &str) -> Config {
+        let mut config = Config::default();
+        config
+            .set("TLS_CERT_FILE", format!("{}/cert.pem", DATA))
+            .unwrap();
+        config
+            .set("TLS_KEY_FILE", format!("{}/key.pem", DATA))
+            .unwrap();
+        config.set("TLS_MIN_VERSION", min_version).unwrap();
+        config
+    }
+
+    #[test]
+    fn unknown_min_version_fails() {
+        let err = server_config(&config("1.1")).err().unwrap().to_string();
+        assert_eq!(
+            err,
+            "TLS_MIN_VERSION names unknown version 1.1, expected one of 1.2, 1.3"
+        );
+    }
+
+    #[tokio::test]
+    async fn tls12_client_is_refused_when_13_is_required() {
+        let server = server_config(&config("1.3")).unwrap().unwrap();
+        let mut roots = RootCertStore::empty();
+        let ca = std::fs::read(format!("{}/ca.pem", DATA)).unwrap();
+        roots.add_parsable_certificates(&rustls_pemfile::certs(&mut &ca[..]).unwrap());
+        let client = ClientConfig::builder()
+            .with_cipher_suites(rustls::ALL_CIPHER_SUITES)
+            .with_kx_groups(&rustls::ALL_KX_GROUPS)
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let name = ServerName::try_from("localhost").unwrap();
+        let (accepted, connected) = tokio::join!(
+            TlsAcceptor::from(server).accept(server_io),
+            TlsConnector::from(Arc::new(client)).connect(name, client_io),
+        );
+        assert!(accepted.is_err());
+        assert!(connected.is_err());
+    }
+}
@@ -0,0 +1,57 @@
+//! Methods switched off by configuration.
+//!
+//! Interceptors don't see which method is called, so the check wraps the
+//! generated service and looks at the request path instead. Disabled methods
+//! answer `UNIMPLEMENTED`, as methods the server doesn't know do.
+
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture, Context, Poll, Service};
+use tonic::transport::NamedService;
+use tonic::Status;
+
+/// Wraps `inner`, refusing the methods named in `disabled`. Names are matched
+/// case insensitively against the last segment of the request path.
+#[derive(Clone)]
+pub struct Toggled<S> {
+    inner: S,
+    disabled: Arc<HashSet<String>>,
+}
+
+impl<S> Toggled<S> {
+    pub fn new(inner: S, disabled: HashSet<String>) -> Self {
+        Toggled {
+            inner,
+            disabled: Arc::new(disabled),
+        }
+    }
+}
+
+impl<S: NamedService> NamedService for Toggled<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for Toggled<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let method = request.uri().path().rsplit('/').next().unwrap_or_default();
+        if self.disabled.contains(&method.to_lowercase()) {
+            let status = Status::unimplemented(format!("method {} is disabled", method));
+            return Box::pin(async move { Ok(status.to_http()) });
+        }
+        Box::pin(self.inner.call(request))
+    }
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use auth::auth::auth_admin_client::AuthAdminClient;
+use auth::auth::ListSessionsRequest;
+use auth::config::Config;
+use tonic::transport::Server;
+use tonic::Code;
+
+fn config() -> Config {
+    let mut config = Config::default();
+    config.set("API_KEY", "shared-secret").unwrap();
+    config
+}
+
+#[tokio::test]
+async fn auth_port_does_not_serve_admin_methods() {
+    let (auth, _) = common::builder(common::unconnected_pool())
+        .config(config())
+        .build()
+        .into_services()
+        .unwrap();
+    let channel = common::connect(Server::builder().add_service(auth)).await;
+
+    let mut request = tonic::Request::new(ListSessionsRequest {
+        user: "user".to_owned(),
+    });
+    request
+        .metadata_mut()
+        .insert("x-api-key", "shared-secret".parse().unwrap());
+    let err = AuthAdminClient::new(channel)
+        .list_sessions(request)
+        .await
+        .unwrap_err();
+
+    assert_eq!(err.code(), Code::Unimplemented);
+}
+
+#[tokio::test]
+async fn admin_service_checks_the_api_key() {
+    let (_, admin) = common::builder(common::unconnected_pool())
+        .config(config())
+        .build()
+        .into_services()
+        .unwrap();
+    let channel = common::connect(Server::builder().add_service(admin)).await;
+
+    let err = AuthAdminClient::new(channel)
+        .list_sessions(ListSessionsRequest {
+            user: "user".to_owned(),
+        })
+        .await
+        .unwrap_err();
+
+    assert_eq!(err.code(), Code::Unauthenticated);
+}
@@ -0,0 +1,141 @@
+mod common;
+
+use auth::auth::auth_client::AuthClient;
+use auth::auth::{IntrospectRequest, LoginRequest, ValidateRequest, ValidationResult};
+use auth::config::Config;
+use auth::{Clock, ManualClock};
+use r2d2_redis::redis::Commands;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tonic::transport::Channel;
+
+/// A client of a service on `config` whose clock starts at the current time
+/// and only moves when advanced.
+async fn serve(config: Config) -> (AuthClient<Channel>, Arc<ManualClock>) {
+    serve_in(config, &common::namespace()).await
+}
+
+/// The same under `namespace`.
+async fn serve_in(config: Config, namespace: &str) -> (AuthClient<Channel>, Arc<ManualClock>) {
+    let clock = Arc::new(ManualClock::new(SystemTime::now()));
+    let service = common::builder_in(common::pool(), namespace)
+        .config(config)
+        .clock(clock.clone())
+        .build()
+        .into_service()
+        .unwrap();
+    (common::serve(service).await, clock)
+}
+
+fn login(not_before: Option<SystemTime>) -> LoginRequest {
+    LoginRequest {
+        user: "user".to_owned(),
+        password: "user".to_owned(),
+        not_before: not_before.map(Into::into),
+        ..Default::default()
+    }
+}
+
+async fn validate(client: &mut AuthClient<Channel>, token: &str) -> ValidationResult {
+    let response = client
+        .validate(ValidateRequest {
+            token: token.to_owned(),
+            result_in_response: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    ValidationResult::from_i32(response.result).unwrap()
+}
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn session_expires_when_the_clock_passes_its_lifetime() {
+    let mut config = Config::default();
+    config.set("SESSION_MAX_LIFETIME_SECONDS", "60").unwrap();
+    let (mut client, clock) = serve(config).await;
+    let token = client.login(login(None)).await.unwrap().into_inner().token;
+
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(validate(&mut client, &token).await, ValidationResult::Valid);
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(
+        validate(&mut client, &token).await,
+        ValidationResult::Expired
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn token_becomes_valid_when_the_clock_reaches_not_before() {
+    let mut config = Config::default();
+    config.set("MAX_NOT_BEFORE_SECONDS", "300").unwrap();
+    let (mut client, clock) = serve(config).await;
+    let not_before = clock.now() + Duration::from_secs(120);
+    let token = client
+        .login(login(Some(not_before)))
+        .await
+        .unwrap()
+        .into_inner()
+        .token;
+
+    assert_eq!(
+        validate(&mut client, &token).await,
+        ValidationResult::NotYetValid
+    );
+    let introspection = |token: String| IntrospectRequest { token };
+    let response = client.introspect(introspection(token.clone())).await;
+    assert!(!response.unwrap().into_inner().active);
+
+    clock.set(not_before);
+    assert_eq!(validate(&mut client, &token).await, ValidationResult::Valid);
+    let response = client.introspect(introspection(token.clone())).await;
+    assert!(response.unwrap().into_inner().active);
+}
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn expiry_times_count_from_the_clock() {
+    let mut config = Config::default();
+    config.set("SESSION_TTL_SECONDS", "600").unwrap();
+    let (mut client, clock) = serve(config).await;
+    // an hour ago by the clock; redis still counts the TTL from now
+    clock.set(SystemTime::now() - Duration::from_secs(3600));
+    let login_at = clock.now();
+
+    let response = client.login(login(None)).await.unwrap().into_inner();
+
+    let issued_at = SystemTime::try_from(response.issued_at.unwrap()).unwrap();
+    let expire_at = SystemTime::try_from(response.expire_at.unwrap()).unwrap();
+    assert_eq!(issued_at, login_at);
+    assert_eq!(expire_at, login_at + Duration::from_secs(600));
+    assert_eq!(
+        validate(&mut client, &response.token).await,
+        ValidationResult::Valid
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn grace_period_runs_on_the_redis_clock() {
+    let pool = common::pool();
+    let mut config = Config::default();
+    config.set("RECENTLY_EXPIRED_GRACE_SECONDS", "60").unwrap();
+    let namespace = common::namespace();
+    let (mut client, clock) = serve_in(config, &namespace).await;
+    let token = client.login(login(None)).await.unwrap().into_inner().token;
+    // as if redis expired the token
+    let _: () = pool
+        .get()
+        .unwrap()
+        .del(common::token_key(&namespace, &token))
+        .unwrap();
+
+    // the expiry marker outlives the token in redis, not by the clock
+    clock.advance(Duration::from_secs(3600));
+    assert_eq!(
+        validate(&mut client, &token).await,
+        ValidationResult::RecentlyExpired
+    );
+}
@@ -0,0 +1,65 @@
+//! Runs services against the redis at `REDIS_URL`, `redis://127.0.0.1/` by
+//! default, such as the one of the repository's docker-compose.yml. The tests
+//! using it are ignored unless run with `cargo test -- --ignored`.
+
+// each test binary uses only some of the helpers
+#![allow(dead_code)]
+
+use auth::auth::auth_client::AuthClient;
+use auth::{AuthGrpcService, AuthServiceBuilder, RedisManager};
+use r2d2_redis::r2d2;
+use tonic::transport::server::Router;
+use tonic::transport::{Channel, Server};
+
+pub fn pool() -> r2d2::Pool<RedisManager> {
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_owned());
+    r2d2::Pool::builder()
+        .max_size(4)
+        .build(RedisManager::new(url).expect("REDIS_URL is not a redis URL"))
+        .expect("no redis at REDIS_URL")
+}
+
+/// A pool that never connects, for calls refused before they reach redis.
+pub fn unconnected_pool() -> r2d2::Pool<RedisManager> {
+    r2d2::Pool::builder().build_unchecked(RedisManager::new("redis://127.0.0.1:1/").unwrap())
+}
+
+/// A builder on `pool` under a namespace of its own, so tests don't see each
+/// other's keys.
+pub fn builder(pool: r2d2::Pool<RedisManager>) -> AuthServiceBuilder {
+    builder_in(pool, &namespace())
+}
+
+/// A builder on `pool` under `namespace`, for tests looking at its keys.
+pub fn builder_in(pool: r2d2::Pool<RedisManager>, namespace: &str) -> AuthServiceBuilder {
+    auth::AuthService::builder(pool).namespace(namespace)
+}
+
+/// A namespace no other test uses.
+pub fn namespace() -> String {
+    format!("test-{}", uuid::Uuid::new_v4())
+}
+
+/// Redis key of the session of `token` under `namespace`, without
+/// `HASH_TOKENS`.
+pub fn token_key(namespace: &str, token: &str) -> String {
+    format!("{}:token:{}", namespace, token)
+}
+
+/// Serves `service` on a free local port and connects a client to it.
+pub async fn serve(service: AuthGrpcService) -> AuthClient<Channel> {
+    AuthClient::new(connect(Server::builder().add_service(service)).await)
+}
+
+/// Serves `router` on a free local port and connects a channel to it.
+pub async fn connect(router: Router) -> Channel {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
+    tokio::spawn(router.serve_with_incoming(incoming));
+    Channel::from_shared(format!("http://{}", addr))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap()
+}
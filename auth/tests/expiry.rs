@@ -0,0 +1,85 @@
+mod common;
+
+use auth::auth::{LoginRequest, ValidateRequest, ValidationResult};
+use auth::config::Config;
+use r2d2_redis::redis::Commands;
+use std::time::Duration;
+
+/// Logs in with a 2 second TTL and validates the token before and after it ran
+/// out, returning both results. Asserts the session's key is gone from redis
+/// by then.
+async fn results_around_expiry(mut config: Config) -> (ValidationResult, ValidationResult) {
+    config.set("SESSION_TTL_SECONDS", "2").unwrap();
+    let pool = common::pool();
+    let namespace = common::namespace();
+    let mut client = common::serve(
+        common::builder_in(pool.clone(), &namespace)
+            .config(config)
+            .build()
+            .into_service()
+            .unwrap(),
+    )
+    .await;
+    let token = client
+        .login(LoginRequest {
+            user: "user".to_owned(),
+            password: "user".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .token;
+    let request = || ValidateRequest {
+        token: token.clone(),
+        result_in_response: true,
+        ..Default::default()
+    };
+
+    let before = client
+        .validate(request())
+        .await
+        .unwrap()
+        .into_inner()
+        .result;
+    // past the TTL, with a second to spare
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let after = client
+        .validate(request())
+        .await
+        .unwrap()
+        .into_inner()
+        .result;
+    let exists: bool = pool
+        .get()
+        .unwrap()
+        .exists(common::token_key(&namespace, &token))
+        .unwrap();
+    assert!(!exists, "the session outlived its TTL in redis");
+    (
+        ValidationResult::from_i32(before).unwrap(),
+        ValidationResult::from_i32(after).unwrap(),
+    )
+}
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn token_is_unknown_once_its_ttl_ran_out() {
+    let results = results_around_expiry(Config::default()).await;
+    assert_eq!(
+        results,
+        (ValidationResult::Valid, ValidationResult::Unknown)
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn token_is_recently_expired_within_the_grace_period() {
+    let mut config = Config::default();
+    config.set("RECENTLY_EXPIRED_GRACE_SECONDS", "60").unwrap();
+    let results = results_around_expiry(config).await;
+    assert_eq!(
+        results,
+        (ValidationResult::Valid, ValidationResult::RecentlyExpired)
+    );
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use auth::auth::{IntrospectRequest, LoginRequest, ValidateRequest};
+use auth::config::Config;
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn introspecting_a_one_time_token_leaves_it_valid() {
+    let mut client = common::serve(
+        common::builder(common::pool())
+            .build()
+            .into_service()
+            .unwrap(),
+    )
+    .await;
+    let token = client
+        .login(LoginRequest {
+            user: "user".to_owned(),
+            password: "user".to_owned(),
+            one_time: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .token;
+
+    for _ in 0..2 {
+        let response = client
+            .introspect(IntrospectRequest {
+                token: token.clone(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.active);
+    }
+
+    let request = || ValidateRequest {
+        token: token.clone(),
+        ..Default::default()
+    };
+    client.validate(request()).await.unwrap();
+    client.validate(request()).await.unwrap_err();
+}
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn session_of_a_disabled_user_is_inactive() {
+    let pool = common::pool();
+    let namespace = format!("test-{}", uuid::Uuid::new_v4());
+    let session_id = uuid::Uuid::new_v4().to_string();
+    let builder = |config: Config| {
+        auth::AuthService::builder(pool.clone())
+            .config(config)
+            .namespace(&namespace)
+            .session_id(&session_id)
+            .build()
+            .into_service()
+            .unwrap()
+    };
+    let token = common::serve(builder(Config::default()))
+        .await
+        .login(LoginRequest {
+            user: "user".to_owned(),
+            password: "user".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .token;
+    let mut disabled = Config::default();
+    disabled.set("DISABLED_USERS", "user").unwrap();
+
+    let response = common::serve(builder(disabled))
+        .await
+        .introspect(IntrospectRequest { token })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert!(!response.active);
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use auth::auth::auth_admin_client::AuthAdminClient;
+use auth::auth::auth_client::AuthClient;
+use auth::auth::{LoginRequest, RevokeAllSessionsRequest, ValidateRequest, ValidationResult};
+use auth::ManualClock;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::transport::Server;
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn revoking_all_sessions_spares_logins_right_after_it() {
+    let second = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let clock = Arc::new(ManualClock::new(
+        UNIX_EPOCH + Duration::from_secs(second) + Duration::from_millis(100),
+    ));
+    let (auth, admin) = common::builder(common::pool())
+        .clock(clock.clone())
+        .build()
+        .into_services()
+        .unwrap();
+    let channel = common::connect(Server::builder().add_service(auth).add_service(admin)).await;
+    let client = AuthClient::new(channel.clone());
+    let login = || {
+        let mut client = client.clone();
+        async move {
+            client
+                .login(LoginRequest {
+                    user: "user".to_owned(),
+                    password: "user".to_owned(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap()
+                .into_inner()
+                .token
+        }
+    };
+
+    // all within one second
+    let before = login().await;
+    clock.advance(Duration::from_millis(200));
+    AuthAdminClient::new(channel)
+        .revoke_all_sessions(RevokeAllSessionsRequest {})
+        .await
+        .unwrap();
+    clock.advance(Duration::from_millis(200));
+    let after = login().await;
+
+    let result = |token: String| {
+        let mut client = client.clone();
+        async move {
+            let result = client
+                .validate(ValidateRequest {
+                    token,
+                    result_in_response: true,
+                    ..Default::default()
+                })
+                .await
+                .unwrap()
+                .into_inner()
+                .result;
+            ValidationResult::from_i32(result).unwrap()
+        }
+    };
+    assert_eq!(result(before).await, ValidationResult::Revoked);
+    assert_eq!(result(after).await, ValidationResult::Valid);
+}
@@ -0,0 +1,138 @@
+mod common;
+
+use auth::auth::{IntrospectRequest, LoginAnonymousRequest};
+use auth::config::Config;
+use opentelemetry::global;
+use opentelemetry::sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry::sdk::propagation::{BaggagePropagator, TextMapCompositePropagator};
+use opentelemetry::sdk::trace::TracerProvider;
+use opentelemetry::trace::{SpanId, TraceId};
+use opentelemetry::{Key, Value};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tonic::Code;
+
+/// Keeps exported spans in memory for the test to look at.
+#[derive(Clone, Debug, Default)]
+struct Spans(Arc<Mutex<Vec<SpanData>>>);
+
+impl SpanExporter for Spans {
+    fn export(
+        &mut self,
+        batch: Vec<SpanData>,
+    ) -> Pin<Box<dyn Future<Output = ExportResult> + Send>> {
+        self.0.lock().unwrap().extend(batch);
+        Box::pin(std::future::ready(Ok(())))
+    }
+}
+
+/// Installs the propagators of the server.
+fn install_propagator() {
+    global::set_text_map_propagator(TextMapCompositePropagator::new(vec![
+        Box::new(opentelemetry_jaeger::Propagator::new()),
+        Box::new(BaggagePropagator::new()),
+    ]));
+}
+
+impl Spans {
+    /// Installs the propagators of the server and exports every span to the
+    /// returned `Spans`.
+    fn install() -> Self {
+        install_propagator();
+        let spans = Spans::default();
+        global::set_tracer_provider(
+            TracerProvider::builder()
+                .with_simple_exporter(spans.clone())
+                .build(),
+        );
+        spans
+    }
+
+    /// Waits for the span of `trace_id`, which is exported once the handler is done.
+    async fn of_trace(&self, trace_id: TraceId) -> SpanData {
+        for _ in 0..100 {
+            let exported =
+                self.0.lock().unwrap().iter().find_map(|span| {
+                    (span.span_context.trace_id() == trace_id).then(|| span.clone())
+                });
+            if let Some(span) = exported {
+                return span;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("no span of trace {} exported", trace_id);
+    }
+}
+
+#[tokio::test]
+async fn handler_span_continues_the_callers_trace() {
+    let spans = Spans::install();
+    let mut config = Config::default();
+    config.set("BAGGAGE_SPAN_ATTRIBUTES", "tenant").unwrap();
+    config.set("TRACE_ID_IN_RESPONSE", "true").unwrap();
+    // refused before redis, as guest sessions are off
+    let mut client = common::serve(
+        common::builder(common::unconnected_pool())
+            .config(config)
+            .build()
+            .into_service()
+            .unwrap(),
+    )
+    .await;
+    let trace_id = TraceId::from_hex("3f2a9c41000000000000000000000001").unwrap();
+    let parent_id = SpanId::from_hex("000000003f2a9c41").unwrap();
+
+    let mut request = tonic::Request::new(LoginAnonymousRequest::default());
+    let metadata = request.metadata_mut();
+    let parent = format!("{}:{}:0:1", trace_id, parent_id);
+    metadata.insert("uber-trace-id", parent.parse().unwrap());
+    metadata.insert("baggage", "tenant=acme".parse().unwrap());
+    let err = client.login_anonymous(request).await.unwrap_err();
+
+    assert_eq!(err.code(), Code::Unimplemented);
+    assert_eq!(
+        err.metadata().get("x-trace-id").unwrap(),
+        trace_id.to_string().as_str()
+    );
+    let span = spans.of_trace(trace_id).await;
+    assert_eq!(span.name, "login_anonymous");
+    assert_eq!(span.parent_span_id, parent_id);
+    assert_eq!(
+        span.attributes.get(&Key::new("baggage.tenant")),
+        Some(&Value::from("acme"))
+    );
+}
+
+#[tokio::test]
+async fn introspect_reports_the_callers_trace_id() {
+    install_propagator();
+    let mut config = Config::default();
+    config.set("TRACE_ID_IN_RESPONSE", "true").unwrap();
+    config.set("REDIS_TIMEOUT_MS", "100").unwrap();
+    let mut client = common::serve(
+        common::builder(common::unconnected_pool())
+            .config(config)
+            .build()
+            .into_service()
+            .unwrap(),
+    )
+    .await;
+    let trace_id = TraceId::from_hex("3f2a9c41000000000000000000000002").unwrap();
+
+    let mut request = tonic::Request::new(IntrospectRequest {
+        token: "3f2a9c41".to_owned(),
+    });
+    let parent = format!("{}:000000003f2a9c41:0:1", trace_id);
+    request
+        .metadata_mut()
+        .insert("uber-trace-id", parent.parse().unwrap());
+    let err = client.introspect(request).await.unwrap_err();
+
+    assert_eq!(err.code(), Code::Unavailable);
+    assert_eq!(
+        err.metadata().get("x-trace-id").unwrap(),
+        trace_id.to_string().as_str()
+    );
+}
@@ -0,0 +1,212 @@
+mod common;
+
+use auth::auth::auth_client::AuthClient;
+use auth::auth::{LoginRequest, ValidateBatchRequest, ValidateRequest, ValidationResult};
+use auth::config::Config;
+use r2d2_redis::redis::{self, Commands};
+use sha2::{Digest, Sha256};
+use tonic::transport::Channel;
+
+async fn login(client: &mut AuthClient<Channel>) -> String {
+    client
+        .login(LoginRequest {
+            user: "user".to_owned(),
+            password: "user".to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .token
+}
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn unreadable_legacy_session_is_unknown_and_dropped() {
+    let pool = common::pool();
+    let namespace = common::namespace();
+    let token = uuid::Uuid::new_v4().to_string();
+    let key = common::token_key(&namespace, &token);
+    // a bare session id, as no release parses
+    let _: () = pool.get().unwrap().set_ex(&key, "3f2a9c41", 60).unwrap();
+    let mut client = common::serve(
+        common::builder_in(pool.clone(), &namespace)
+            .build()
+            .into_service()
+            .unwrap(),
+    )
+    .await;
+
+    let response = client
+        .validate(ValidateRequest {
+            token: token.clone(),
+            result_in_response: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(response.result, ValidationResult::Unknown as i32);
+    let exists: bool = redis::cmd("EXISTS")
+        .arg(&key)
+        .query(&mut *pool.get().unwrap())
+        .unwrap();
+    assert!(!exists);
+}
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn batch_reports_wrong_type_tokens_per_item() {
+    let pool = common::pool();
+    let namespace = common::namespace();
+    let mut client = common::serve(
+        common::builder_in(pool.clone(), &namespace)
+            .build()
+            .into_service()
+            .unwrap(),
+    )
+    .await;
+    let valid = login(&mut client).await;
+    let wrong_type = uuid::Uuid::new_v4().to_string();
+    let wrong_type_key = common::token_key(&namespace, &wrong_type);
+    let _: i64 = pool
+        .get()
+        .unwrap()
+        .zadd(&wrong_type_key, "member", 1)
+        .unwrap();
+    let unknown = uuid::Uuid::new_v4().to_string();
+
+    let response = client
+        .validate_batch(ValidateBatchRequest {
+            tokens: vec![valid, wrong_type.clone(), unknown],
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    let results = [
+        ValidationResult::Valid,
+        ValidationResult::Unknown,
+        ValidationResult::Unknown,
+    ];
+    assert_eq!(response.results, results.map(|result| result as i32));
+    assert_eq!(
+        response.items[1].reason,
+        "token key holds a zset instead of a session"
+    );
+    assert_eq!(response.items[2].reason, "unknown token");
+    let exists: bool = pool.get().unwrap().exists(&wrong_type_key).unwrap();
+    assert!(!exists);
+}
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn batch_migrates_plaintext_token_keys() {
+    let pool = common::pool();
+    let namespace = format!("test-{}", uuid::Uuid::new_v4());
+    let session_id = uuid::Uuid::new_v4().to_string();
+    let builder = |config: Config| {
+        auth::AuthService::builder(pool.clone())
+            .config(config)
+            .namespace(&namespace)
+            .session_id(&session_id)
+            .build()
+            .into_service()
+            .unwrap()
+    };
+    let token = login(&mut common::serve(builder(Config::default())).await).await;
+    let mut hashed = legacy_token_keys(Config::default());
+    hashed.set("HASH_TOKENS", "true").unwrap();
+
+    let response = common::serve(builder(hashed))
+        .await
+        .validate_batch(ValidateBatchRequest {
+            tokens: vec![token.clone()],
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(response.results, [ValidationResult::Valid as i32]);
+    let exists: bool = pool
+        .get()
+        .unwrap()
+        .exists(common::token_key(&namespace, &token))
+        .unwrap();
+    assert!(!exists);
+}
+
+/// `LEGACY_TOKEN_KEYS_UNTIL` an hour from now.
+fn legacy_token_keys(mut config: Config) -> Config {
+    let until = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 3600;
+    config
+        .set("LEGACY_TOKEN_KEYS_UNTIL", until.to_string())
+        .unwrap();
+    config
+}
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn tokens_are_stored_under_the_namespace() {
+    let pool = common::pool();
+    let namespace = common::namespace();
+    let token = |config: Config| {
+        let service = common::builder_in(pool.clone(), &namespace)
+            .config(config)
+            .build()
+            .into_service()
+            .unwrap();
+        async { login(&mut common::serve(service).await).await }
+    };
+    let plain = token(Config::default()).await;
+    let mut hashed = Config::default();
+    hashed.set("HASH_TOKENS", "true").unwrap();
+    let hashed = token(hashed).await;
+
+    let mut conn = pool.get().unwrap();
+    let mut exists = |key: String| conn.exists::<_, bool>(key).unwrap();
+    assert!(exists(format!("{}:token:{}", namespace, plain)));
+    assert!(!exists(plain));
+    let digest = format!("{:x}", Sha256::digest(hashed.as_bytes()));
+    assert!(exists(format!("{}:token:{}", namespace, digest)));
+    assert!(!exists(digest));
+}
+
+#[tokio::test]
+#[ignore = "needs redis at REDIS_URL"]
+async fn validate_migrates_token_keys_without_the_namespace() {
+    let pool = common::pool();
+    let namespace = common::namespace();
+    let mut client = common::serve(
+        common::builder_in(pool.clone(), &namespace)
+            .config(legacy_token_keys(Config::default()))
+            .build()
+            .into_service()
+            .unwrap(),
+    )
+    .await;
+    let token = login(&mut client).await;
+    let key = common::token_key(&namespace, &token);
+    // where a release before namespaced token keys kept it
+    let _: () = pool.get().unwrap().rename(&key, &token).unwrap();
+
+    let response = client
+        .validate(ValidateRequest {
+            token: token.clone(),
+            result_in_response: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(response.result, ValidationResult::Valid as i32);
+    let mut conn = pool.get().unwrap();
+    assert!(conn.exists::<_, bool>(&key).unwrap());
+    assert!(!conn.exists::<_, bool>(&token).unwrap());
+}